@@ -0,0 +1,106 @@
+//! benchmarks of concurrent cache lookups, with and without sharding, compared
+//! with a single cache behind a global lock taken for writing on every lookup
+
+#[macro_use]
+extern crate criterion;
+extern crate hermes;
+
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, BenchmarkId, Criterion};
+
+use hermes::dns::cache::{Cache, SynchronizedCache};
+use hermes::dns::protocol::{DnsRecord, QueryClass, QueryType, TransientTtl};
+
+const DOMAINS: usize = 1000;
+
+fn records() -> Vec<DnsRecord> {
+    (0..DOMAINS).map(|i| DnsRecord::A {
+        domain: format!("host{}.example.com", i),
+        class: QueryClass::IN,
+        addr: "10.0.0.1".parse().unwrap(),
+        ttl: TransientTtl(86400)
+    }).collect()
+}
+
+fn build_cache(shards: usize) -> Arc<SynchronizedCache> {
+    let cache = SynchronizedCache::with_shards(shards);
+    cache.store(&records()).unwrap();
+
+    Arc::new(cache)
+}
+
+/// The cache as it was before sharding, where counting hits meant that every
+/// lookup took the lock for writing
+fn build_locked_cache() -> Arc<RwLock<Cache>> {
+    let mut cache = Cache::new();
+    cache.store(&records());
+
+    Arc::new(RwLock::new(cache))
+}
+
+/// Run `iters` lookups spread over `threads` threads, as the UDP server
+/// workers would, and return the time it took for all of them to finish
+fn run_lookups<F>(lookup: &Arc<F>, threads: usize, iters: u64) -> Duration
+    where F: Fn(&str) + Send + Sync + 'static {
+
+    let names = (0..DOMAINS).map(|i| format!("host{}.example.com", i)).collect::<Vec<String>>();
+    let names = Arc::new(names);
+
+    let start = Instant::now();
+
+    let handles = (0..threads).map(|thread_id| {
+        let lookup = lookup.clone();
+        let names = names.clone();
+        let count = iters / threads as u64 + 1;
+
+        thread::spawn(move || {
+            for i in 0..count {
+                lookup(&names[(i as usize * 7 + thread_id * 131) % DOMAINS]);
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+
+    let cache = build_locked_cache();
+    let lookup = Arc::new(move |name: &str| {
+        black_box(cache.write().unwrap().lookup(name, QueryType::A));
+    });
+
+    for &threads in &[1, 4, 32] {
+        let id = BenchmarkId::new("global_write_lock", format!("{}_threads", threads));
+        group.bench_with_input(id, &threads, |b, &threads| {
+            b.iter_custom(|iters| run_lookups(&lookup, threads, iters))
+        });
+    }
+
+    for &shards in &[1, 16] {
+        let cache = build_cache(shards);
+        let lookup = Arc::new(move |name: &str| {
+            black_box(cache.lookup(name, QueryType::A));
+        });
+
+        for &threads in &[1, 4, 32] {
+            let id = BenchmarkId::new(format!("{}_shards", shards), format!("{}_threads", threads));
+            group.bench_with_input(id, &threads, |b, &threads| {
+                b.iter_custom(|iters| run_lookups(&lookup, threads, iters))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookup);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+//! benchmarks comparing full packet parsing with the borrowed packet view
+
+#[macro_use]
+extern crate criterion;
+extern crate hermes;
+
+use criterion::{black_box, Criterion};
+
+use hermes::dns::buffer::{SlicePacketBuffer, VectorPacketBuffer};
+use hermes::dns::protocol::{DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType, TransientTtl};
+use hermes::dns::view::DnsPacketRef;
+
+/// A query as sent by a typical stub resolver
+fn build_query() -> Vec<u8> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 1337;
+    packet.header.recursion_desired = true;
+    packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+    packet.resources.push(DnsRecord::OPT {
+        packet_len: 4096,
+        flags: 0,
+        data: Vec::new()
+    });
+
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer, 512).unwrap();
+
+    buffer.buffer
+}
+
+/// A response with a CNAME chain and a delegation
+fn build_response() -> Vec<u8> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 1337;
+    packet.header.response = true;
+    packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+    packet.answers.push(DnsRecord::CNAME {
+        domain: "www.example.com".to_string(),
+        class: QueryClass::IN,
+        host: "cdn.example.net".to_string(),
+        ttl: TransientTtl(3600)
+    });
+    for i in 0..4 {
+        packet.answers.push(DnsRecord::A {
+            domain: "cdn.example.net".to_string(),
+            class: QueryClass::IN,
+            addr: format!("10.0.0.{}", i).parse().unwrap(),
+            ttl: TransientTtl(300)
+        });
+    }
+    for i in 0..4 {
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.net".to_string(),
+            class: QueryClass::IN,
+            host: format!("ns{}.example.net", i),
+            ttl: TransientTtl(86400)
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: format!("ns{}.example.net", i),
+            class: QueryClass::IN,
+            addr: format!("10.0.1.{}", i).parse().unwrap(),
+            ttl: TransientTtl(86400)
+        });
+    }
+
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer, 512).unwrap();
+
+    buffer.buffer
+}
+
+fn bench_query(c: &mut Criterion) {
+    let data = build_query();
+
+    let mut group = c.benchmark_group("query");
+    group.bench_function("from_buffer", |b| b.iter(|| {
+        let packet = DnsPacket::from_buffer(&mut SlicePacketBuffer::new(black_box(&data))).unwrap();
+        black_box(packet.questions[0].name == "www.example.com")
+    }));
+    group.bench_function("packet_ref", |b| b.iter(|| {
+        let packet = DnsPacketRef::parse(black_box(&data)).unwrap();
+        black_box(packet.questions().next().unwrap().name.eq_ignore_case("www.example.com"))
+    }));
+    group.finish();
+}
+
+fn bench_response(c: &mut Criterion) {
+    let data = build_response();
+
+    let mut group = c.benchmark_group("response");
+    group.bench_function("from_buffer", |b| b.iter(|| {
+        let packet = DnsPacket::from_buffer(&mut SlicePacketBuffer::new(black_box(&data))).unwrap();
+        black_box(packet.answers.iter().map(|rec| rec.get_ttl()).min())
+    }));
+    group.bench_function("packet_ref", |b| b.iter(|| {
+        let packet = DnsPacketRef::parse(black_box(&data)).unwrap();
+        black_box(packet.answers().map(|rec| rec.ttl).min())
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_query, bench_response);
+criterion_main!(benches);
@@ -7,7 +7,7 @@ use std::fs::File;
 use std::path::Path;
 
 use dns::buffer::{VectorPacketBuffer, PacketBuffer, StreamPacketBuffer};
-use dns::protocol::{DnsPacket,DnsRecord,QueryType,ResultCode,TransientTtl};
+use dns::protocol::{DnsPacket,DnsRecord,QueryClass,QueryType,ResultCode,TransientTtl};
 
 #[derive(Clone,Debug,Default)]
 pub struct Zone {
@@ -99,7 +99,7 @@ impl<'a> Zones {
 
             println!("Loaded zone {} with {} records", zone.domain, record_count);
 
-            self.zones.insert(zone.domain.clone(), zone);
+            self.zones.insert(zone.domain.to_lowercase(), zone);
         }
 
         Ok(())
@@ -145,17 +145,17 @@ impl<'a> Zones {
 
     pub fn add_zone(&mut self, zone: Zone)
     {
-        self.zones.insert(zone.domain.clone(), zone);
+        self.zones.insert(zone.domain.to_lowercase(), zone);
     }
 
     pub fn get_zone(&'a self, domain: &str) -> Option<&'a Zone>
     {
-        self.zones.get(domain)
+        self.zones.get(&domain.to_lowercase())
     }
 
     pub fn get_zone_mut(&'a mut self, domain: &str) -> Option<&'a mut Zone>
     {
-        self.zones.get_mut(domain)
+        self.zones.get_mut(&domain.to_lowercase())
     }
 }
 
@@ -190,9 +190,11 @@ impl Authority {
             None => return None
         };
 
+        let qname = &qname.to_lowercase();
+
         let mut best_match = None;
         for zone in zones.zones() {
-            if !qname.ends_with(&zone.domain) {
+            if !qname.ends_with(&zone.domain.to_lowercase()) {
                 continue;
             }
 
@@ -220,7 +222,7 @@ impl Authority {
                 None => continue
             };
 
-            if &domain != qname {
+            if !domain.eq_ignore_ascii_case(qname) {
                 continue;
             }
 
@@ -238,6 +240,7 @@ impl Authority {
 
             packet.authorities.push(DnsRecord::SOA {
                 domain: zone.domain.clone(),
+                class: QueryClass::IN,
                 m_name: zone.m_name.clone(),
                 r_name: zone.r_name.clone(),
                 serial: zone.serial,
@@ -3,6 +3,76 @@
 use std::io::{Result, Read};
 use std::io::{Error, ErrorKind};
 use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+/// Maximum length of a single label
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+/// Maximum length of a name in wire format, length bytes included
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Maximum number of compression pointers followed while reading a name
+pub const MAX_JUMPS: usize = 32;
+
+/// Compression pointers have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// The ways in which wire format data can be malformed
+///
+/// Parsing functions keep returning `std::io::Result`, with these wrapped in
+/// the `io::Error`. `WireError::from_io` can be used to get them back out.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum WireError {
+    EndOfBuffer,
+    EmptyLabel,
+    LabelTooLong(usize),
+    NameTooLong,
+    BadLabelType(u8),
+    ForwardPointer(usize),
+    TooManyJumps,
+    RdataLength {
+        qtype: u16,
+        expected: usize,
+        consumed: usize
+    },
+    EmptyRdata(u16)
+}
+
+impl WireError {
+    pub fn from_io(err: &Error) -> Option<&WireError> {
+        err.get_ref().and_then(|x| x.downcast_ref::<WireError>())
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WireError::EndOfBuffer => write!(f, "End of buffer"),
+            WireError::EmptyLabel => write!(f, "Empty label in name"),
+            WireError::LabelTooLong(len) => write!(f, "Label of {} bytes exceeds {} bytes", len, MAX_LABEL_LENGTH),
+            WireError::NameTooLong => write!(f, "Name exceeds {} bytes", MAX_NAME_LENGTH),
+            WireError::BadLabelType(len) => write!(f, "Unsupported label type {:#04x}", len),
+            WireError::ForwardPointer(offset) => write!(f, "Compression pointer to {} doesn't point backwards", offset),
+            WireError::TooManyJumps => write!(f, "More than {} compression pointers in name", MAX_JUMPS),
+            WireError::RdataLength { qtype, expected, consumed } => {
+                write!(f, "RDATA of type {} is {} bytes, but {} were used", qtype, expected, consumed)
+            },
+            WireError::EmptyRdata(qtype) => write!(f, "Empty RDATA for record of type {}", qtype)
+        }
+    }
+}
+
+impl error::Error for WireError {}
+
+impl From<WireError> for Error {
+    fn from(err: WireError) -> Error {
+        match err {
+            WireError::EndOfBuffer => Error::new(ErrorKind::UnexpectedEof, err),
+            _ => Error::new(ErrorKind::InvalidData, err)
+        }
+    }
+}
 
 pub trait PacketBuffer {
     fn read(&mut self) -> Result<u8>;
@@ -13,8 +83,16 @@ pub trait PacketBuffer {
     fn pos(&self) -> usize;
     fn seek(&mut self, pos: usize) -> Result<()>;
     fn step(&mut self, steps: usize) -> Result<()>;
-    fn find_label(&self, label: &str) -> Option<usize>;
-    fn save_label(&mut self, label: &str, pos: usize);
+
+    /// Look up the offset of a previously written name, for use in a
+    /// compression pointer. Only `PacketWriter` keeps track of names, so plain
+    /// buffers write every name in full.
+    fn find_label(&self, _label: &str) -> Option<usize> {
+        None
+    }
+
+    fn save_label(&mut self, _label: &str, _pos: usize) {
+    }
 
     fn write_u8(&mut self, val: u8) -> Result<()> {
         self.write(val)?;
@@ -46,7 +124,26 @@ pub trait PacketBuffer {
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        let split_str = qname.split('.').collect::<Vec<&str>>();
+        // The root name, and the trailing dot of a fully qualified name, are
+        // both represented by the terminating empty label alone.
+        let name = qname.trim_end_matches('.');
+        if name.is_empty() {
+            return self.write_u8(0);
+        }
+
+        if name.len() + 2 > MAX_NAME_LENGTH {
+            return Err(WireError::NameTooLong.into());
+        }
+
+        let split_str = name.split('.').collect::<Vec<&str>>();
+        for label in &split_str {
+            if label.is_empty() {
+                return Err(WireError::EmptyLabel.into());
+            }
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(WireError::LabelTooLong(label.len()).into());
+            }
+        }
 
         let mut jump_performed = false;
         for (i, label) in split_str.iter().enumerate() {
@@ -95,6 +192,14 @@ pub trait PacketBuffer {
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
         let mut pos = self.pos();
         let mut jumped = false;
+        let mut jumps = 0;
+
+        // Every pointer has to point before the previous one, which
+        // guarantees that we can't be led into a loop.
+        let mut pointer_limit = pos;
+
+        // Length of the name in wire format, which is limited to 255 bytes
+        let mut name_len = 0;
 
         let mut delim = "";
         loop {
@@ -104,23 +209,43 @@ pub trait PacketBuffer {
             // set, represents a offset relative to the start of the buffer. We
             // handle this by jumping to the offset, setting a flag to indicate
             // that we shouldn't update the shared buffer position once done.
-            if (len & 0xC0) > 0 {
-
-                // When a jump is performed, we only modify the shared buffer
-                // position once, and avoid making the change later on.
-                if !jumped {
-                    self.seek(pos + 2)?;
-                }
-
-                let b2 = self.get(pos + 1)? as u16;
-                let offset = (((len as u16) ^ 0xC0) << 8) | b2;
-                pos = offset as usize;
-                jumped = true;
-                continue;
+            match len & 0xC0 {
+                0xC0 => {
+                    let b2 = self.get(pos + 1)? as u16;
+                    let offset = ((((len as u16) ^ 0xC0) << 8) | b2) as usize;
+
+                    if offset >= pointer_limit {
+                        return Err(WireError::ForwardPointer(offset).into());
+                    }
+
+                    jumps += 1;
+                    if jumps > MAX_JUMPS {
+                        return Err(WireError::TooManyJumps.into());
+                    }
+
+                    // When a jump is performed, we only modify the shared buffer
+                    // position once, and avoid making the change later on.
+                    if !jumped {
+                        self.seek(pos + 2)?;
+                    }
+
+                    pos = offset;
+                    pointer_limit = offset;
+                    jumped = true;
+                    continue;
+                },
+                0x00 => {},
+                // The 0x40 and 0x80 label types were never standardized for use
+                _ => return Err(WireError::BadLabelType(len).into())
             }
 
             pos += 1;
 
+            name_len += len as usize + 1;
+            if name_len > MAX_NAME_LENGTH {
+                return Err(WireError::NameTooLong.into());
+            }
+
             // Names are terminated by an empty label of length 0
             if len == 0 {
                 break;
@@ -128,8 +253,10 @@ pub trait PacketBuffer {
 
             outstr.push_str(delim);
 
+            // Labels are kept in their original case, since the case of a
+            // question has to be echoed back as it was received.
             let str_buffer = self.get_range(pos, len as usize)?;
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            outstr.push_str(&String::from_utf8_lossy(str_buffer));
 
             delim = ".";
 
@@ -148,22 +275,23 @@ pub trait PacketBuffer {
 #[derive(Default)]
 pub struct VectorPacketBuffer {
     pub buffer: Vec<u8>,
-    pub pos: usize,
-    pub label_lookup: BTreeMap<String, usize>
+    pub pos: usize
 }
 
 impl VectorPacketBuffer {
     pub fn new() -> VectorPacketBuffer {
         VectorPacketBuffer {
             buffer: Vec::new(),
-            pos: 0,
-            label_lookup: BTreeMap::new()
+            pos: 0
         }
     }
 }
 
 impl PacketBuffer for VectorPacketBuffer {
     fn read(&mut self) -> Result<u8> {
+        if self.pos >= self.buffer.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
         let res = self.buffer[self.pos];
         self.pos += 1;
 
@@ -171,21 +299,35 @@ impl PacketBuffer for VectorPacketBuffer {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
+        if pos >= self.buffer.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
         Ok(self.buffer[pos])
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > self.buffer.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
         Ok(&self.buffer[start..start+len as usize])
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
-        self.buffer.push(val);
+        // Writes go to the current position, so that data can be rewritten
+        // after seeking back
+        if self.pos >= self.buffer.len() {
+            self.buffer.resize(self.pos + 1, 0);
+        }
+        self.buffer[self.pos] = val;
         self.pos += 1;
 
         Ok(())
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.buffer.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
         self.buffer[pos] = val;
 
         Ok(())
@@ -206,14 +348,6 @@ impl PacketBuffer for VectorPacketBuffer {
 
         Ok(())
     }
-
-    fn find_label(&self, label: &str) -> Option<usize> {
-        self.label_lookup.get(label).cloned()
-    }
-
-    fn save_label(&mut self, label: &str, pos: usize) {
-        self.label_lookup.insert(label.to_string(), pos);
-    }
 }
 
 pub struct StreamPacketBuffer<'a, T> where T: Read + 'a {
@@ -230,15 +364,34 @@ impl<'a, T> StreamPacketBuffer<'a, T> where T: Read + 'a {
             pos: 0
         }
     }
+
+    /// Read from the stream until at least `len` bytes have been buffered
+    ///
+    /// DNS messages are limited to 65535 bytes, so nothing beyond that is
+    /// ever read.
+    fn fill(&mut self, len: usize) -> Result<()> {
+        if len <= self.buffer.len() {
+            return Ok(());
+        }
+        if len > 0xFFFF {
+            return Err(WireError::EndOfBuffer.into());
+        }
+
+        let start = self.buffer.len();
+        self.buffer.resize(len, 0);
+        if let Err(e) = self.stream.read_exact(&mut self.buffer[start..]) {
+            self.buffer.truncate(start);
+            return Err(e);
+        }
+
+        Ok(())
+    }
 }
 
 impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T> where T: Read + 'a {
     fn read(&mut self) -> Result<u8> {
-        while self.pos >= self.buffer.len() {
-            let mut local_buffer = [0; 1];
-            self.stream.read(&mut local_buffer)?;
-            self.buffer.push(local_buffer[0]);
-        }
+        let pos = self.pos;
+        self.fill(pos + 1)?;
 
         let res = self.buffer[self.pos];
         self.pos += 1;
@@ -247,21 +400,13 @@ impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T> where T: Read + 'a {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        while pos >= self.buffer.len() {
-            let mut local_buffer = [0; 1];
-            self.stream.read(&mut local_buffer)?;
-            self.buffer.push(local_buffer[0]);
-        }
+        self.fill(pos + 1)?;
 
         Ok(self.buffer[pos])
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        while start+len > self.buffer.len() {
-            let mut local_buffer = [0; 1];
-            self.stream.read(&mut local_buffer)?;
-            self.buffer.push(local_buffer[0]);
-        }
+        self.fill(start + len)?;
 
         Ok(&self.buffer[start..start+len as usize])
     }
@@ -287,14 +432,6 @@ impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T> where T: Read + 'a {
         self.pos += steps;
         Ok(())
     }
-
-    fn find_label(&self, _: &str) -> Option<usize> {
-        None
-    }
-
-    fn save_label(&mut self, _: &str, _: usize) {
-        unimplemented!();
-    }
 }
 
 pub struct BytePacketBuffer {
@@ -320,7 +457,7 @@ impl Default for BytePacketBuffer {
 impl PacketBuffer for BytePacketBuffer {
     fn read(&mut self) -> Result<u8> {
         if self.pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(WireError::EndOfBuffer.into());
         }
         let res = self.buf[self.pos];
         self.pos += 1;
@@ -330,21 +467,21 @@ impl PacketBuffer for BytePacketBuffer {
 
     fn get(&mut self, pos: usize) -> Result<u8> {
         if pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(WireError::EndOfBuffer.into());
         }
         Ok(self.buf[pos])
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if start + len > 512 {
+            return Err(WireError::EndOfBuffer.into());
         }
         Ok(&self.buf[start..start+len as usize])
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
         if self.pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(WireError::EndOfBuffer.into());
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -352,6 +489,9 @@ impl PacketBuffer for BytePacketBuffer {
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= 512 {
+            return Err(WireError::EndOfBuffer.into());
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -372,12 +512,149 @@ impl PacketBuffer for BytePacketBuffer {
 
         Ok(())
     }
+}
 
-    fn find_label(&self, _: &str) -> Option<usize> {
-        None
+/// A read only buffer over borrowed data
+///
+/// Used for parsing packets which have already been received into memory,
+/// without copying them first.
+pub struct SlicePacketBuffer<'a> {
+    pub buf: &'a [u8],
+    pub pos: usize
+}
+
+impl<'a> SlicePacketBuffer<'a> {
+    pub fn new(buf: &'a [u8]) -> SlicePacketBuffer<'a> {
+        SlicePacketBuffer {
+            buf,
+            pos: 0
+        }
     }
+}
 
-    fn save_label(&mut self, _: &str, _: usize) {
+impl<'a> PacketBuffer for SlicePacketBuffer<'a> {
+    fn read(&mut self) -> Result<u8> {
+        if self.pos >= self.buf.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
+        let res = self.buf[self.pos];
+        self.pos += 1;
+
+        Ok(res)
+    }
+
+    fn get(&mut self, pos: usize) -> Result<u8> {
+        if pos >= self.buf.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
+        Ok(self.buf[pos])
+    }
+
+    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > self.buf.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
+        Ok(&self.buf[start..start+len])
+    }
+
+    fn write(&mut self, _: u8) -> Result<()> {
+        Err(Error::new(ErrorKind::PermissionDenied, "Buffer is read only"))
+    }
+
+    fn set(&mut self, _: usize, _: u8) -> Result<()> {
+        Err(Error::new(ErrorKind::PermissionDenied, "Buffer is read only"))
+    }
+
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        self.pos = pos;
+
+        Ok(())
+    }
+
+    fn step(&mut self, steps: usize) -> Result<()> {
+        self.pos += steps;
+
+        Ok(())
+    }
+}
+
+/// A writer which compresses names, on top of any other buffer
+///
+/// The names written are remembered along with their position, so that later
+/// occurrences of the same name, or of a suffix of it, can be replaced by a
+/// pointer. Pointers are relative to the start of the message, which is taken
+/// to be the position of the buffer when the writer is created.
+pub struct PacketWriter<'a, T> where T: PacketBuffer + 'a {
+    pub buffer: &'a mut T,
+    start: usize,
+    labels: BTreeMap<String, usize>
+}
+
+impl<'a, T> PacketWriter<'a, T> where T: PacketBuffer + 'a {
+    pub fn new(buffer: &'a mut T) -> PacketWriter<'a, T> {
+        let start = buffer.pos();
+        PacketWriter {
+            buffer,
+            start,
+            labels: BTreeMap::new()
+        }
+    }
+
+    /// Forget the names written at or after `pos`, after that part of the
+    /// message has been discarded
+    pub fn forget_labels(&mut self, pos: usize) {
+        let offset = pos - self.start;
+        self.labels.retain(|_, x| *x < offset);
+    }
+}
+
+impl<'a, T> PacketBuffer for PacketWriter<'a, T> where T: PacketBuffer + 'a {
+    fn read(&mut self) -> Result<u8> {
+        self.buffer.read()
+    }
+
+    fn get(&mut self, pos: usize) -> Result<u8> {
+        self.buffer.get(pos)
+    }
+
+    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        self.buffer.get_range(start, len)
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        self.buffer.write(val)
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        self.buffer.set(pos, val)
+    }
+
+    fn pos(&self) -> usize {
+        self.buffer.pos()
+    }
+
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        self.buffer.seek(pos)
+    }
+
+    fn step(&mut self, steps: usize) -> Result<()> {
+        self.buffer.step(steps)
+    }
+
+    fn find_label(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).cloned()
+    }
+
+    fn save_label(&mut self, label: &str, pos: usize) {
+        // Names past the range of a pointer can't be referred to
+        let offset = pos - self.start;
+        if offset <= MAX_POINTER_OFFSET {
+            self.labels.insert(label.to_string(), offset);
+        }
     }
 }
 
@@ -438,13 +715,17 @@ mod tests {
     fn test_write_qname() {
         let mut buffer = VectorPacketBuffer::new();
 
-        match buffer.write_qname(&"ns1.google.com".to_string()) {
-            Ok(_) => {},
-            Err(_) => panic!()
-        }
-        match buffer.write_qname(&"ns2.google.com".to_string()) {
-            Ok(_) => {},
-            Err(_) => panic!()
+        {
+            let mut writer = PacketWriter::new(&mut buffer);
+
+            match writer.write_qname("ns1.google.com") {
+                Ok(_) => {},
+                Err(_) => panic!()
+            }
+            match writer.write_qname("ns2.google.com") {
+                Ok(_) => {},
+                Err(_) => panic!()
+            }
         }
 
         assert_eq!(22, buffer.pos());
@@ -470,4 +751,160 @@ mod tests {
 
         assert_eq!("ns2.google.com", str2);
     }
+
+    #[test]
+    fn test_write_root_qname() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        buffer.write_qname("").unwrap();
+        buffer.write_qname(".").unwrap();
+        buffer.write_qname("google.com.").unwrap();
+
+        assert_eq!(vec![0, 0, 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0],
+                   buffer.buffer);
+    }
+
+    #[test]
+    fn test_write_invalid_qname() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        let err = buffer.write_qname("a..b").unwrap_err();
+        assert_eq!(Some(&WireError::EmptyLabel), WireError::from_io(&err));
+
+        let err = buffer.write_qname(&"a".repeat(64)).unwrap_err();
+        assert_eq!(Some(&WireError::LabelTooLong(64)), WireError::from_io(&err));
+
+        let err = buffer.write_qname(&vec!["abcdefg"; 32].join(".")).unwrap_err();
+        assert_eq!(Some(&WireError::NameTooLong), WireError::from_io(&err));
+    }
+
+    fn read_crafted_qname(data: &[u8], start: usize) -> Result<String> {
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.buffer = data.to_vec();
+        buffer.pos = start;
+
+        let mut name = String::new();
+        buffer.read_qname(&mut name)?;
+
+        Ok(name)
+    }
+
+    #[test]
+    fn test_read_invalid_qname() {
+        // A pointer to itself would previously loop forever
+        let err = read_crafted_qname(&[0xC0, 0x00], 0).unwrap_err();
+        assert_eq!(Some(&WireError::ForwardPointer(0)), WireError::from_io(&err));
+
+        // As would two pointers pointing at each other
+        let err = read_crafted_qname(&[0x01, b'a', 0xC0, 0x04, 0xC0, 0x00], 4).unwrap_err();
+        assert_eq!(Some(&WireError::ForwardPointer(4)), WireError::from_io(&err));
+
+        // Pointers into data that hasn't been read yet are refused too
+        let err = read_crafted_qname(&[0xC0, 0x02, 0x00], 0).unwrap_err();
+        assert_eq!(Some(&WireError::ForwardPointer(2)), WireError::from_io(&err));
+
+        // The reserved label types
+        let err = read_crafted_qname(&[0x41, b'a', 0x00], 0).unwrap_err();
+        assert_eq!(Some(&WireError::BadLabelType(0x41)), WireError::from_io(&err));
+        let err = read_crafted_qname(&[0x80, 0x00], 0).unwrap_err();
+        assert_eq!(Some(&WireError::BadLabelType(0x80)), WireError::from_io(&err));
+
+        // A name of 128 single character labels is 257 bytes long
+        let mut long_name = Vec::new();
+        for _ in 0..128 {
+            long_name.push(1);
+            long_name.push(b'a');
+        }
+        long_name.push(0);
+        let err = read_crafted_qname(&long_name, 0).unwrap_err();
+        assert_eq!(Some(&WireError::NameTooLong), WireError::from_io(&err));
+
+        // Truncated names
+        let err = read_crafted_qname(&[0x03, b'c', b'o'], 0).unwrap_err();
+        assert_eq!(Some(&WireError::EndOfBuffer), WireError::from_io(&err));
+
+        // Chained pointers that keep moving backwards are fine
+        let name = read_crafted_qname(&[0x03, b'c', b'o', b'm', 0x00,
+                                        0x01, b'b', 0xC0, 0x00,
+                                        0x01, b'a', 0xC0, 0x05], 9).unwrap();
+        assert_eq!("a.b.com", name);
+    }
+
+    #[test]
+    fn test_stream_buffer_eof() {
+        let data = [0x03, b'c', b'o'];
+        let mut stream = &data[..];
+        let mut buffer = StreamPacketBuffer::new(&mut stream);
+
+        let mut name = String::new();
+        let err = buffer.read_qname(&mut name).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_write_compressed_qname() {
+        // Compression works the same regardless of the underlying buffer
+        let mut buffer = BytePacketBuffer::new();
+        {
+            let mut writer = PacketWriter::new(&mut buffer);
+            writer.write_qname("ns1.google.com").unwrap();
+            writer.write_qname("ns1.google.com").unwrap();
+            writer.write_qname("com").unwrap();
+        }
+        assert_eq!(20, buffer.pos());
+
+        buffer.seek(0).unwrap();
+        for expected in &["ns1.google.com", "ns1.google.com", "com"] {
+            let mut name = String::new();
+            buffer.read_qname(&mut name).unwrap();
+            assert_eq!(*expected, name);
+        }
+
+        // Without a writer, names are written in full
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("ns1.google.com").unwrap();
+        buffer.write_qname("ns1.google.com").unwrap();
+        assert_eq!(32, buffer.pos());
+    }
+
+    #[test]
+    fn test_write_qname_offsets() {
+        // Pointers are relative to where the writer started
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_u16(0xFFFF).unwrap();
+        {
+            let mut writer = PacketWriter::new(&mut buffer);
+            writer.write_qname("google.com").unwrap();
+            writer.write_qname("www.google.com").unwrap();
+        }
+        assert_eq!(vec![0xC0, 0x00], buffer.buffer[18..20].to_vec());
+
+        // Names beyond the range of a pointer are never pointed to
+        let mut buffer = VectorPacketBuffer::new();
+        {
+            let mut writer = PacketWriter::new(&mut buffer);
+            writer.seek(0x4000).unwrap();
+            writer.write_qname("google.com").unwrap();
+            writer.write_qname("google.com").unwrap();
+        }
+        assert_eq!(0x4000 + 24, buffer.pos());
+
+        // Names which have been overwritten are no longer pointed to
+        let mut buffer = VectorPacketBuffer::new();
+        {
+            let mut writer = PacketWriter::new(&mut buffer);
+            writer.write_qname("a.com").unwrap();
+            writer.write_qname("b.a.com").unwrap();
+            writer.seek(7).unwrap();
+            writer.forget_labels(7);
+            writer.write_qname("x.org").unwrap();
+            writer.write_qname("b.a.com").unwrap();
+        }
+        assert_eq!(18, buffer.pos());
+
+        buffer.seek(14).unwrap();
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!("b.a.com", name);
+    }
 }
@@ -2,20 +2,63 @@
 
 use std::collections::{HashSet, HashMap, BTreeMap};
 use std::hash::{Hash, Hasher};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::collections::hash_map::DefaultHasher;
 use std::clone::Clone;
 use std::io::{Result, Error, ErrorKind};
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
 
 use chrono::*;
 
+use dns::buffer::{PacketBuffer, VectorPacketBuffer};
 use dns::protocol::{DnsRecord, QueryType, DnsPacket, ResultCode};
 
+/// Magic bytes at the start of a cache snapshot
+const SNAPSHOT_MAGIC: &[u8] = b"HRMSCACHE";
+
+/// Version of the snapshot format, which is to be bumped whenever the layout
+/// changes, while keeping support for reading the older versions
+const SNAPSHOT_VERSION: u16 = 1;
+
 pub enum CacheState {
     PositiveCache,
+    /// The name exists, but has no records of the type (NODATA)
     NegativeCache,
+    /// The name doesn't exist at all
+    NxDomain,
     NotCached
 }
 
+/// How far cached data can be trusted, based on the part of a response it
+/// came from, ordered from the least to the most credible (RFC 2181 §5.4.1)
+#[derive(Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum Credibility {
+    /// The additional section, such as glue from a referral
+    Additional,
+    /// The authority section
+    Authority,
+    /// The answer section of a response which isn't authoritative, such as
+    /// one from a forwarder
+    Answer,
+    /// The answer section of an authoritative response
+    AuthoritativeAnswer
+}
+
+impl Credibility {
+    /// The credibility of the answer section of a response
+    pub fn of_answer(packet: &DnsPacket) -> Credibility {
+        if packet.header.authoritative_answer {
+            Credibility::AuthoritativeAnswer
+        } else {
+            Credibility::Answer
+        }
+    }
+}
+
 #[derive(Clone,Eq,Debug)]
 pub struct RecordEntry {
     pub record: DnsRecord,
@@ -34,80 +77,170 @@ impl Hash for RecordEntry {
     }
 }
 
+/// A cached NXDOMAIN, covering every type of a name
+#[derive(Clone,Debug)]
+pub struct NxDomainEntry {
+    pub soa: Option<DnsRecord>,
+    pub ttl: u32,
+    pub timestamp: DateTime<Local>
+}
+
 #[derive(Clone,Debug)]
 pub enum RecordSet {
     NoRecords {
         qtype: QueryType,
+        soa: Option<DnsRecord>,
         ttl: u32,
         timestamp: DateTime<Local>,
     },
     Records {
         qtype: QueryType,
+        credibility: Credibility,
         records: HashSet<RecordEntry>
     }
 }
 
-#[derive(Clone,Debug)]
+#[derive(Debug)]
 pub struct DomainEntry {
     pub domain: String,
     pub record_types: HashMap<QueryType, RecordSet>,
-    pub hits: u32,
+    pub nxdomain: Option<NxDomainEntry>,
+    /// Counted atomically, so that lookups only need a shared reference
+    pub hits: AtomicU32,
     pub updates: u32
 }
 
+impl Clone for DomainEntry {
+    fn clone(&self) -> DomainEntry {
+        DomainEntry {
+            domain: self.domain.clone(),
+            record_types: self.record_types.clone(),
+            nxdomain: self.nxdomain.clone(),
+            hits: AtomicU32::new(self.get_hits()),
+            updates: self.updates
+        }
+    }
+}
+
+/// The key a name is cached under
+///
+/// Names are compared without regard to case, so entries are keyed on the
+/// lowercase version of the name. The root is keyed as `.`, whether it's
+/// given that way or as the empty name it's read as from the wire format.
+fn cache_key(qname: &str) -> String {
+    if qname.is_empty() {
+        ".".to_string()
+    } else {
+        qname.to_lowercase()
+    }
+}
+
+/// Check whether a negative entry stored at `timestamp` is still valid
+fn negative_expired(ttl: u32, timestamp: DateTime<Local>, now: DateTime<Local>) -> bool {
+    timestamp + Duration::seconds(ttl as i64) < now
+}
+
+/// Copy the SOA of a negative entry, with its TTL set to the time remaining
+/// until the entry expires
+fn negative_soa(soa: &Option<DnsRecord>, ttl: u32, timestamp: DateTime<Local>) -> Option<DnsRecord> {
+    let remaining = (timestamp + Duration::seconds(ttl as i64)).signed_duration_since(Local::now());
+
+    soa.clone().map(|mut soa| {
+        soa.set_ttl(cmp::max(remaining.num_seconds(), 0) as u32);
+        soa
+    })
+}
+
 impl DomainEntry {
     pub fn new(domain: String) -> DomainEntry {
         DomainEntry {
             domain,
             record_types: HashMap::new(),
-            hits: 0,
+            nxdomain: None,
+            hits: AtomicU32::new(0),
             updates: 0
         }
     }
 
-    pub fn store_nxdomain(&mut self, qtype: QueryType, ttl: u32) {
+    pub fn get_hits(&self) -> u32 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Record that the name doesn't exist, which replaces anything known
+    /// about it
+    pub fn store_nxdomain(&mut self, soa: Option<DnsRecord>, ttl: u32) {
+        self.updates += 1;
+
+        self.record_types.clear();
+        self.nxdomain = Some(NxDomainEntry {
+            soa,
+            ttl,
+            timestamp: Local::now()
+        });
+    }
+
+    /// Record that the name exists, but has no records of a type
+    pub fn store_nodata(&mut self, qtype: QueryType, soa: Option<DnsRecord>, ttl: u32) {
         self.updates += 1;
 
         let new_set = RecordSet::NoRecords {
             qtype,
-            ttl: ttl,
+            soa,
+            ttl,
             timestamp: Local::now()
         };
 
+        self.nxdomain = None;
         self.record_types.insert(qtype, new_set);
     }
 
-    pub fn store_record(&mut self, rec: &DnsRecord) {
-        self.updates += 1;
-
-        let entry = RecordEntry {
-                record: rec.clone(),
-                timestamp: Local::now()
-            };
+    /// Replace the records of a type with a new RRset, returning false if the
+    /// RRset was rejected
+    ///
+    /// An RRset is always replaced as a whole, rather than merged with what
+    /// was cached before. Data which hasn't expired is only replaced by data
+    /// which is at least as credible.
+    pub fn store_rrset(&mut self, qtype: QueryType, rrset: &[DnsRecord], credibility: Credibility) -> bool {
+        let now = Local::now();
 
-        if let Some(&mut RecordSet::Records { ref mut records, .. }) =
-            self.record_types.get_mut(&rec.get_querytype()) {
+        if let Some(&RecordSet::Records { credibility: current, ref records, .. }) = self.record_types.get(&qtype) {
+            let valid = records.iter().any(|entry| {
+                entry.timestamp + Duration::seconds(entry.record.get_ttl() as i64) >= now
+            });
 
-            if records.contains(&entry) {
-                records.remove(&entry);
+            if valid && current > credibility {
+                return false;
             }
-
-            records.insert(entry);
-            return;
         }
 
-        let mut records = HashSet::new();
-        records.insert(entry);
+        self.updates += 1;
+        self.nxdomain = None;
+
+        let records = rrset.iter()
+            .map(|rec| RecordEntry {
+                record: rec.clone(),
+                timestamp: now
+            })
+            .collect::<HashSet<RecordEntry>>();
 
         let new_set = RecordSet::Records {
-            qtype: rec.get_querytype(),
-            records: records
+            qtype,
+            credibility,
+            records
         };
 
-        self.record_types.insert(rec.get_querytype(), new_set);
+        self.record_types.insert(qtype, new_set);
+
+        true
     }
 
     pub fn get_cache_state(&self, qtype: QueryType) -> CacheState {
+        if let Some(ref nxdomain) = self.nxdomain {
+            if !negative_expired(nxdomain.ttl, nxdomain.timestamp, Local::now()) {
+                return CacheState::NxDomain;
+            }
+        }
+
         match self.record_types.get(&qtype) {
             Some(&RecordSet::Records { ref records, .. }) => {
                 let now = Local::now();
@@ -132,11 +265,7 @@ impl DomainEntry {
                 }
             },
             Some(&RecordSet::NoRecords { ttl, timestamp, .. }) => {
-                let now = Local::now();
-                let ttl_offset = Duration::seconds(ttl as i64);
-                let expires = timestamp + ttl_offset;
-
-                if expires < now {
+                if negative_expired(ttl, timestamp, Local::now()) {
                     CacheState::NotCached
                 } else {
                     CacheState::NegativeCache
@@ -146,6 +275,21 @@ impl DomainEntry {
         }
     }
 
+    /// Get the SOA record to return in the authority section of a negative
+    /// answer for the type
+    pub fn get_negative_soa(&self, qtype: QueryType) -> Option<DnsRecord> {
+        if let Some(ref nxdomain) = self.nxdomain {
+            return negative_soa(&nxdomain.soa, nxdomain.ttl, nxdomain.timestamp);
+        }
+
+        match self.record_types.get(&qtype) {
+            Some(&RecordSet::NoRecords { ref soa, ttl, timestamp, .. }) => negative_soa(soa, ttl, timestamp),
+            _ => None
+        }
+    }
+
+    /// Fill in the valid records of a type, with their TTL set to the time
+    /// remaining until they expire
     pub fn fill_query_result(&self, qtype: QueryType, result_vec: &mut Vec<DnsRecord>) {
 
         let now = Local::now();
@@ -164,26 +308,341 @@ impl DomainEntry {
                 }
 
                 if entry.record.get_querytype() == qtype {
-                    result_vec.push(entry.record.clone());
+                    let mut rec = entry.record.clone();
+                    rec.set_ttl(expires.signed_duration_since(now).num_seconds() as u32);
+                    result_vec.push(rec);
+                }
+            }
+        }
+    }
+
+    /// Check whether a valid record of the type is within the last `percent`
+    /// percent of its TTL
+    pub fn expires_within(&self, qtype: QueryType, percent: u32) -> bool {
+        let now = Local::now();
+
+        if let Some(RecordSet::Records { records, .. }) = self.record_types.get(&qtype) {
+            for entry in records {
+                let ttl = entry.record.get_ttl() as i64;
+                let remaining = (entry.timestamp + Duration::seconds(ttl)).signed_duration_since(now);
+                if remaining < Duration::zero() {
+                    continue;
+                }
+
+                if remaining.num_milliseconds() * 100 < ttl * 1000 * percent as i64 {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Remove records that expired more than `grace` seconds ago, returning
+    /// the number of records removed
+    ///
+    /// The grace period allows for expired records to still be served stale.
+    /// Negative entries are removed as soon as they expire, since they're
+    /// never served stale.
+    pub fn purge_expired(&mut self, grace: u32) -> usize {
+        let now = Local::now();
+        let mut purged = 0;
+
+        self.record_types.retain(|_, set| {
+            match *set {
+                RecordSet::Records { ref mut records, .. } => {
+                    let count = records.len();
+                    records.retain(|entry| {
+                        let ttl_offset = Duration::seconds(entry.record.get_ttl() as i64 + grace as i64);
+                        entry.timestamp + ttl_offset >= now
+                    });
+                    purged += count - records.len();
+
+                    !records.is_empty()
+                },
+                RecordSet::NoRecords { ttl, timestamp, .. } => {
+                    if negative_expired(ttl, timestamp, now) {
+                        purged += 1;
+                        return false;
+                    }
+
+                    true
+                }
+            }
+        });
+
+        let nxdomain_expired = match self.nxdomain {
+            Some(ref nxdomain) => negative_expired(nxdomain.ttl, nxdomain.timestamp, now),
+            None => false
+        };
+
+        if nxdomain_expired {
+            self.nxdomain = None;
+            purged += 1;
+        }
+
+        purged
+    }
+
+    /// List the valid contents of the entry, or `None` if everything in it
+    /// has expired
+    pub fn dump(&self) -> Option<DomainDump> {
+        let now = Local::now();
+
+        let mut dump = DomainDump {
+            domain: self.domain.clone(),
+            hits: self.get_hits(),
+            records: Vec::new(),
+            nodata: Vec::new(),
+            nxdomain: false
+        };
+
+        if let Some(ref nxdomain) = self.nxdomain {
+            dump.nxdomain = !negative_expired(nxdomain.ttl, nxdomain.timestamp, now);
+        }
+
+        for (qtype, set) in &self.record_types {
+            match *set {
+                RecordSet::Records { .. } => self.fill_query_result(*qtype, &mut dump.records),
+                RecordSet::NoRecords { ttl, timestamp, .. } => {
+                    if !negative_expired(ttl, timestamp, now) {
+                        dump.nodata.push(*qtype);
+                    }
+                }
+            }
+        }
+
+        if dump.records.is_empty() && dump.nodata.is_empty() && !dump.nxdomain {
+            return None;
+        }
+
+        dump.records.sort_by_key(|rec| rec.get_querytype().to_num());
+        dump.nodata.sort_by_key(|qtype| qtype.to_num());
+
+        Some(dump)
+    }
+
+    /// Check whether nothing at all is known about the name
+    pub fn is_empty(&self) -> bool {
+        self.record_types.is_empty() && self.nxdomain.is_none()
+    }
+
+    /// Fill in records that have expired no more than `max_stale` seconds ago,
+    /// along with those that are still valid, replacing their TTL with
+    /// `stale_ttl`
+    pub fn fill_stale_result(&self, qtype: QueryType, max_stale: u32, stale_ttl: u32, result_vec: &mut Vec<DnsRecord>) {
+
+        let now = Local::now();
+
+        let current_set = match self.record_types.get(&qtype) {
+            Some(x) => x,
+            None => return
+        };
+
+        if let RecordSet::Records { ref records, .. } = *current_set {
+            for entry in records {
+                let ttl_offset = Duration::seconds(entry.record.get_ttl() as i64 + max_stale as i64);
+                let stale_until = entry.timestamp + ttl_offset;
+                if stale_until < now {
+                    continue;
+                }
+
+                if entry.record.get_querytype() == qtype {
+                    let mut rec = entry.record.clone();
+                    rec.set_ttl(stale_ttl);
+                    result_vec.push(rec);
                 }
             }
         }
     }
 }
 
+/// What is currently known about a domain, as listed by
+/// `SynchronizedCache::dump`
+#[derive(Clone,Debug)]
+pub struct DomainDump {
+    pub domain: String,
+    pub hits: u32,
+    /// The valid records, with their TTL set to the time remaining
+    pub records: Vec<DnsRecord>,
+    /// Types known to have no records
+    pub nodata: Vec<QueryType>,
+    /// Whether the name is known not to exist
+    pub nxdomain: bool
+}
+
+/// Counters describing the size of the cache and what has been removed from it
+#[derive(Clone,Debug,Default)]
+pub struct CacheStatistics {
+    pub entries: usize,
+    pub evictions: u64,
+    pub purged: u64
+}
+
 #[derive(Default)]
 pub struct Cache {
-    domain_entries: BTreeMap<String, Arc<DomainEntry>>
+    domain_entries: BTreeMap<String, Arc<DomainEntry>>,
+    hints: Vec<DnsRecord>,
+    max_entries: usize,
+    min_ttl: u32,
+    max_ttl: u32,
+    evictions: u64,
+    purged: u64
 }
 
 impl Cache {
     pub fn new() -> Cache {
         Cache {
-            domain_entries: BTreeMap::new()
+            domain_entries: BTreeMap::new(),
+            hints: Vec::new(),
+            max_entries: 0,
+            min_ttl: 0,
+            max_ttl: 0,
+            evictions: 0,
+            purged: 0
+        }
+    }
+
+    /// Limit the number of domains kept in the cache, where 0 means no limit
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+
+    /// Clamp the TTL of records when they're stored, where a `max_ttl` of 0
+    /// means no limit
+    pub fn set_ttl_limits(&mut self, min_ttl: u32, max_ttl: u32) {
+        self.min_ttl = min_ttl;
+        self.max_ttl = max_ttl;
+    }
+
+    pub fn get_statistics(&self) -> CacheStatistics {
+        CacheStatistics {
+            entries: self.domain_entries.len(),
+            evictions: self.evictions,
+            purged: self.purged
         }
     }
 
-    fn get_cache_state(&mut self, qname: &str, qtype: QueryType) -> CacheState {
+    /// Remove records that expired more than `grace` seconds ago, along with
+    /// any domains left without records, returning the number of records
+    /// removed
+    pub fn purge_expired(&mut self, grace: u32) -> usize {
+        let mut purged = 0;
+
+        for domain_entry in self.domain_entries.values_mut() {
+            purged += Arc::make_mut(domain_entry).purge_expired(grace);
+        }
+
+        self.domain_entries.retain(|_, domain_entry| !domain_entry.is_empty());
+        self.purged += purged as u64;
+
+        purged
+    }
+
+    /// Remove everything but the root hints from the cache, returning the
+    /// number of domains removed
+    pub fn flush(&mut self) -> usize {
+        let count = self.domain_entries.len();
+        self.domain_entries.clear();
+        self.restore_hints();
+
+        count
+    }
+
+    /// Remove a name, or only its records of one type, returning whether
+    /// anything was removed
+    pub fn remove(&mut self, qname: &str, qtype: Option<QueryType>) -> bool {
+        let qname = &cache_key(qname);
+
+        let qtype = match qtype {
+            Some(x) => x,
+            None => {
+                let removed = self.domain_entries.remove(qname).is_some();
+                self.restore_hints();
+                return removed;
+            }
+        };
+
+        let removed = match self.domain_entries.get_mut(qname) {
+            Some(domain_entry) => Arc::make_mut(domain_entry).record_types.remove(&qtype).is_some(),
+            None => false
+        };
+
+        let is_empty = self.domain_entries.get(qname).is_some_and(|x| x.is_empty());
+        if is_empty {
+            self.domain_entries.remove(qname);
+        }
+
+        self.restore_hints();
+
+        removed
+    }
+
+    /// Remove a name along with every name below it, returning the number of
+    /// domains removed
+    pub fn remove_subtree(&mut self, zone: &str) -> usize {
+        // Every name is below the root
+        let zone = cache_key(zone);
+        let suffix = if zone == "." { String::new() } else { format!(".{}", zone) };
+
+        let count = self.domain_entries.len();
+        self.domain_entries.retain(|domain, _| *domain != zone && !domain.ends_with(&suffix));
+        let removed = count - self.domain_entries.len();
+
+        self.restore_hints();
+
+        removed
+    }
+
+    /// Store the root hints again where they've been removed, so that the
+    /// recursive resolver never runs out of root servers
+    fn restore_hints(&mut self) {
+        let missing = self.hints.iter()
+            .filter(|rec| {
+                let domain = match rec.get_domain() {
+                    Some(x) => cache_key(&x),
+                    None => return false
+                };
+
+                let rrset = self.domain_entries.get(&domain).and_then(|x| x.record_types.get(&rec.get_querytype()));
+                !matches!(rrset, Some(RecordSet::Records { .. }))
+            })
+            .cloned()
+            .collect::<Vec<DnsRecord>>();
+
+        if !missing.is_empty() {
+            self.store_rrsets(&missing, Credibility::Answer, false);
+        }
+    }
+
+    /// Make room for a new domain when the cache is full
+    ///
+    /// The domains with the fewest hits are evicted first. A tenth of the
+    /// cache is evicted at once, so that this doesn't have to happen for
+    /// every new domain. The root is never evicted, since the recursive
+    /// resolver can't do without the root servers.
+    fn evict(&mut self) {
+        if self.max_entries == 0 || self.domain_entries.len() < self.max_entries {
+            return;
+        }
+
+        let target = self.max_entries - cmp::max(self.max_entries / 10, 1);
+
+        let mut candidates = self.domain_entries.values()
+            .filter(|x| x.domain != ".")
+            .map(|x| (x.get_hits(), x.domain.clone()))
+            .collect::<Vec<(u32, String)>>();
+        candidates.sort();
+
+        let count = cmp::min(self.domain_entries.len() - target, candidates.len());
+        for (_, domain) in candidates.into_iter().take(count) {
+            self.domain_entries.remove(&domain);
+        }
+
+        self.evictions += count as u64;
+    }
+
+    fn get_cache_state(&self, qname: &str, qtype: QueryType) -> CacheState {
 
         match self.domain_entries.get(qname) {
             Some(x) => x.get_cache_state(qtype),
@@ -191,19 +650,22 @@ impl Cache {
         }
     }
 
-    fn fill_query_result(&mut self, qname: &str, qtype: QueryType, result_vec: &mut Vec<DnsRecord>, increment_stats: bool) {
-        if let Some(domain_entry) = self.domain_entries.get_mut(qname).and_then(Arc::get_mut) {
+    fn fill_query_result(&self, qname: &str, qtype: QueryType, result_vec: &mut Vec<DnsRecord>, increment_stats: bool) {
+        if let Some(domain_entry) = self.domain_entries.get(qname) {
             if increment_stats {
-                domain_entry.hits += 1
+                domain_entry.hits.fetch_add(1, Ordering::Relaxed);
             }
 
             domain_entry.fill_query_result(qtype, result_vec);
         }
     }
 
-    pub fn lookup(&mut self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
 
-        match self.get_cache_state(qname, qtype) {
+        let qname = &cache_key(qname);
+
+        let state = self.get_cache_state(qname, qtype);
+        match state {
             CacheState::PositiveCache => {
                 let mut qr = DnsPacket::new();
                 self.fill_query_result(qname, qtype, &mut qr.answers, true);
@@ -211,9 +673,15 @@ impl Cache {
 
                 Some(qr)
             },
-            CacheState::NegativeCache => {
+            CacheState::NegativeCache | CacheState::NxDomain => {
                 let mut qr = DnsPacket::new();
-                qr.header.res_code = ResultCode::NXDOMAIN;
+                if let CacheState::NxDomain = state {
+                    qr.header.res_code = ResultCode::NXDOMAIN;
+                }
+
+                if let Some(domain_entry) = self.domain_entries.get(qname) {
+                    qr.authorities.extend(domain_entry.get_negative_soa(qtype));
+                }
 
                 Some(qr)
             },
@@ -221,94 +689,534 @@ impl Cache {
         }
     }
 
+    /// Look up records for serving stale data (RFC 8767)
+    ///
+    /// Expired records are kept around, and this returns the ones that
+    /// expired at most `max_stale` seconds ago, with their TTL set to
+    /// `stale_ttl`. Negative entries are never served stale.
+    pub fn lookup_stale(&self, qname: &str, qtype: QueryType, max_stale: u32, stale_ttl: u32) -> Option<DnsPacket> {
+        let qname = &cache_key(qname);
+
+        let domain_entry = self.domain_entries.get(qname)?;
+
+        let mut qr = DnsPacket::new();
+        domain_entry.fill_stale_result(qtype, max_stale, stale_ttl, &mut qr.answers);
+
+        if qr.answers.is_empty() {
+            return None;
+        }
+
+        Some(qr)
+    }
+
+    /// Store records which didn't come from a response, such as configured
+    /// root hints, with the credibility of a non-authoritative answer
     pub fn store(&mut self, records: &[DnsRecord]) {
+        self.store_with_credibility(records, Credibility::Answer);
+    }
+
+    /// Apply the TTL limits to the TTL of a record or a negative answer
+    fn clamp_ttl(&self, ttl: u32) -> u32 {
+        let ttl = cmp::max(ttl, self.min_ttl);
+        if self.max_ttl > 0 {
+            cmp::min(ttl, self.max_ttl)
+        } else {
+            ttl
+        }
+    }
+
+    /// Store the root hints, which are exempt from the TTL limits and kept
+    /// across a flush so that the recursive resolver never runs out of root
+    /// servers
+    pub fn store_hints(&mut self, records: &[DnsRecord]) {
+        self.hints.extend_from_slice(records);
+        self.store_rrsets(records, Credibility::Answer, false);
+    }
+
+    /// Store the records from a section of a response, grouped into RRsets,
+    /// each of which replaces the cached RRset unless it's less credible
+    pub fn store_with_credibility(&mut self, records: &[DnsRecord], credibility: Credibility) {
+        self.store_rrsets(records, credibility, true);
+    }
+
+    fn store_rrsets(&mut self, records: &[DnsRecord], credibility: Credibility, clamp_ttl: bool) {
+        let mut rrsets: Vec<(String, QueryType, Vec<DnsRecord>)> = Vec::new();
 
         for rec in records {
             let domain = match rec.get_domain() {
-                Some(x) => x,
+                Some(x) => cache_key(&x),
                 None => continue
             };
 
-            if let Some(ref mut rs) = self.domain_entries.get_mut(&domain).and_then(Arc::get_mut) {
+            // Records are stored with the owner name they're keyed on, so that
+            // the same record received with different case is only kept once
+            let mut rec = rec.clone();
+            rec.set_domain(domain.clone());
+
+            if clamp_ttl {
+                let ttl = self.clamp_ttl(rec.get_ttl());
+                rec.set_ttl(ttl);
+            }
+
+            let qtype = rec.get_querytype();
+            match rrsets.iter_mut().find(|x| x.0 == domain && x.1 == qtype) {
+                Some(rrset) => rrset.2.push(rec),
+                None => rrsets.push((domain, qtype, vec![rec]))
+            }
+        }
+
+        // Less credible data is rejected as a matter of course, such as the
+        // glue that accompanies every referral, so it isn't logged
+        for (domain, qtype, rrset) in rrsets {
+            self.entry_for_update(&domain).store_rrset(qtype, &rrset, credibility);
+        }
+    }
+
+    /// Check whether the name is popular and about to expire, see
+    /// `SynchronizedCache::should_prefetch`
+    pub fn should_prefetch(&self, qname: &str, qtype: QueryType, min_hits: u32, percent: u32) -> bool {
+        match self.domain_entries.get(&cache_key(qname)) {
+            Some(entry) => entry.get_hits() >= min_hits && entry.expires_within(qtype, percent),
+            None => false
+        }
+    }
+
+    /// Serialize the valid records of the cache into a snapshot
+    ///
+    /// After a header of magic bytes and the format version, each domain is
+    /// written as its name, its hit count and its records. Every record is
+    /// preceded by the time it expires, in seconds since the epoch, so that
+    /// the time spent on disk is accounted for when loading. Negative answers
+    /// are short lived, and left out.
+    pub fn write_snapshot(&self) -> Result<Vec<u8>> {
+        let mut buffer = VectorPacketBuffer::new();
+        write_snapshot_header(&mut buffer)?;
+        self.write_snapshot_entries(&mut buffer)?;
+
+        Ok(buffer.buffer)
+    }
+
+    /// Write the domains of the cache to a snapshot, after the header
+    fn write_snapshot_entries(&self, buffer: &mut VectorPacketBuffer) -> Result<()> {
+        let now = Local::now();
 
-                rs.store_record(rec);
+        for domain_entry in self.domain_entries.values() {
+            let mut records = Vec::new();
+            for set in domain_entry.record_types.values() {
+                if let RecordSet::Records { records: ref set_records, .. } = *set {
+                    for entry in set_records {
+                        let expires = entry.timestamp + Duration::seconds(entry.record.get_ttl() as i64);
+                        if expires >= now {
+                            records.push((expires.timestamp(), &entry.record));
+                        }
+                    }
+                }
+            }
+
+            if records.is_empty() {
                 continue;
             }
 
-            let mut rs = DomainEntry::new(domain.clone());
-            rs.store_record(rec);
-            self.domain_entries.insert(domain.clone(), Arc::new(rs));
+            buffer.write_u16(domain_entry.domain.len() as u16)?;
+            for b in domain_entry.domain.as_bytes() {
+                buffer.write_u8(*b)?;
+            }
+            buffer.write_u32(domain_entry.get_hits())?;
+            buffer.write_u16(records.len() as u16)?;
+
+            for (expires, record) in records {
+                buffer.write_u32((expires >> 32) as u32)?;
+                buffer.write_u32(expires as u32)?;
+                record.write(buffer)?;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Load the records of a snapshot into the cache, returning the number of
+    /// records loaded
+    pub fn read_snapshot(&mut self, data: &[u8]) -> Result<usize> {
+        read_snapshot(data, |domain, hits, records| self.load_domain(domain, hits, records))
+    }
+
+    /// Store the records of a domain loaded from a snapshot, along with its
+    /// hit count
+    fn load_domain(&mut self, domain: &str, hits: u32, records: &[DnsRecord]) {
+        self.store(records);
+
+        if let Some(domain_entry) = self.domain_entries.get(domain) {
+            domain_entry.hits.store(hits, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the entry of a cache key for updating, creating it if needed
+    fn entry_for_update(&mut self, qname: &str) -> &mut DomainEntry {
+        if !self.domain_entries.contains_key(qname) {
+            self.evict();
+        }
+
+        let entry = self.domain_entries.entry(qname.to_string())
+            .or_insert_with(|| Arc::new(DomainEntry::new(qname.to_string())));
+
+        Arc::make_mut(entry)
+    }
+
+    /// Store an NXDOMAIN answer for a name, which covers every type
+    ///
+    /// The SOA from the authority section of the answer is kept, so that it
+    /// can be returned along with the cached answer (RFC 2308).
+    pub fn store_nxdomain(&mut self, qname: &str, soa: Option<DnsRecord>, ttl: u32) {
+        let qname = &cache_key(qname);
+        let ttl = self.clamp_ttl(ttl);
+        self.entry_for_update(qname).store_nxdomain(soa, ttl);
     }
 
-    pub fn store_nxdomain(&mut self, qname: &str, qtype: QueryType, ttl: u32) {
-        if let Some(ref mut rs) = self.domain_entries.get_mut(qname)
-            .and_then(Arc::get_mut) {
+    /// Store a NODATA answer, for a name which exists but has no records of
+    /// the type
+    pub fn store_nodata(&mut self, qname: &str, qtype: QueryType, soa: Option<DnsRecord>, ttl: u32) {
+        let qname = &cache_key(qname);
+        let ttl = self.clamp_ttl(ttl);
+        self.entry_for_update(qname).store_nodata(qtype, soa, ttl);
+    }
+}
+
+fn write_snapshot_header(buffer: &mut VectorPacketBuffer) -> Result<()> {
+    for b in SNAPSHOT_MAGIC {
+        buffer.write_u8(*b)?;
+    }
+    buffer.write_u16(SNAPSHOT_VERSION)
+}
+
+/// Parse a snapshot, passing the name, hit count and records of each domain to
+/// `load`, and returning the number of records loaded
+///
+/// Records which have expired since the snapshot was written are dropped,
+/// and the others get the time remaining as their TTL.
+fn read_snapshot<F>(data: &[u8], load: F) -> Result<usize> where F: FnMut(&str, u32, &[DnsRecord]) {
+    let mut buffer = VectorPacketBuffer::new();
+    buffer.buffer = data.to_vec();
+
+    if buffer.get_range(0, SNAPSHOT_MAGIC.len()).ok() != Some(SNAPSHOT_MAGIC) {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a cache snapshot"));
+    }
+    buffer.step(SNAPSHOT_MAGIC.len())?;
+
+    match buffer.read_u16()? {
+        1 => read_snapshot_v1(&mut buffer, load),
+        version => Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Unsupported cache snapshot version {}", version)))
+    }
+}
+
+fn read_snapshot_v1<F>(buffer: &mut VectorPacketBuffer, mut load: F) -> Result<usize> where F: FnMut(&str, u32, &[DnsRecord]) {
+    let now = Local::now().timestamp();
+    let mut count = 0;
+
+    while buffer.pos() < buffer.buffer.len() {
+        let len = buffer.read_u16()? as usize;
+        let domain = match String::from_utf8(buffer.get_range(buffer.pos(), len)?.to_vec()) {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Invalid domain in cache snapshot"))
+        };
+        buffer.step(len)?;
+
+        let hits = buffer.read_u32()?;
+        let record_count = buffer.read_u16()?;
 
-            rs.store_nxdomain(qtype, ttl);
-            return
+        let mut records = Vec::new();
+        for _ in 0..record_count {
+            let expires = ((buffer.read_u32()? as i64) << 32) | buffer.read_u32()? as i64;
+            let mut record = DnsRecord::read(buffer)?;
+
+            // The owner name is kept as it was in the cache, since the
+            // wire format can't tell the root apart from an empty name
+            if expires > now {
+                record.set_domain(domain.clone());
+                record.set_ttl((expires - now) as u32);
+                records.push(record);
+            }
+        }
+
+        if records.is_empty() {
+            continue;
         }
 
-        let mut rs = DomainEntry::new(qname.to_string());
-        rs.store_nxdomain(qtype, ttl);
-        self.domain_entries.insert(qname.to_string(), Arc::new(rs));
+        load(&domain, hits, &records);
+        count += records.len();
     }
+
+    Ok(count)
 }
 
+/// Number of shards of a `SynchronizedCache`, unless given explicitly
+const DEFAULT_SHARDS: usize = 16;
+
+/// A cache shared between threads
+///
+/// Domains are spread over a number of shards, each with a lock of its own,
+/// so that threads working on different names don't contend. Lookups only
+/// take a shared lock, and count hits atomically, so the common case of a
+/// cache hit never blocks other readers.
 #[derive(Default)]
 pub struct SynchronizedCache {
-    pub cache: RwLock<Cache>
+    shards: Vec<RwLock<Cache>>
 }
 
 impl SynchronizedCache {
     pub fn new() -> SynchronizedCache {
+        SynchronizedCache::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(count: usize) -> SynchronizedCache {
         SynchronizedCache {
-            cache: RwLock::new(Cache::new())
+            shards: (0..cmp::max(count, 1)).map(|_| RwLock::new(Cache::new())).collect()
         }
     }
 
-    pub fn list(&self) -> Result<Vec<Arc<DomainEntry>>> {
-        let cache = match self.cache.read() {
-            Ok(x) => x,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
-        };
+    fn shard_index(&self, qname: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        cache_key(qname).hash(&mut hasher);
+
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    fn shard(&self, qname: &str) -> &RwLock<Cache> {
+        &self.shards[self.shard_index(qname)]
+    }
 
+    fn read_shard(&self, qname: &str) -> Result<RwLockReadGuard<'_, Cache>> {
+        match self.shard(qname).read() {
+            Ok(x) => Ok(x),
+            Err(_) => Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        }
+    }
+
+    fn write_shard(&self, qname: &str) -> Result<RwLockWriteGuard<'_, Cache>> {
+        match self.shard(qname).write() {
+            Ok(x) => Ok(x),
+            Err(_) => Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        }
+    }
+
+    /// Apply a change to every shard
+    fn update_shards<F>(&self, mut update: F) -> Result<()> where F: FnMut(&mut Cache) {
+        for shard in &self.shards {
+            match shard.write() {
+                Ok(mut cache) => update(&mut cache),
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// List the domains of the cache, ordered by name
+    pub fn list(&self) -> Result<Vec<Arc<DomainEntry>>> {
         let mut list = Vec::new();
 
-        for rs in cache.domain_entries.values() {
-            list.push(rs.clone());
+        for shard in &self.shards {
+            let cache = match shard.read() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            for rs in cache.domain_entries.values() {
+                list.push(rs.clone());
+            }
         }
 
+        list.sort_by(|a, b| a.domain.cmp(&b.domain));
+
         Ok(list)
     }
 
+    /// List the valid contents of the cache, ordered by name
+    pub fn dump(&self) -> Result<Vec<DomainDump>> {
+        let mut dump = Vec::new();
+
+        for shard in &self.shards {
+            let cache = match shard.read() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            dump.extend(cache.domain_entries.values().filter_map(|x| x.dump()));
+        }
+
+        dump.sort_by(|a, b| a.domain.cmp(&b.domain));
+
+        Ok(dump)
+    }
+
+    /// Remove everything but the root hints from the cache, see
+    /// `Cache::flush`
+    pub fn flush(&self) -> Result<usize> {
+        let mut count = 0;
+        self.update_shards(|cache| count += cache.flush())?;
+
+        Ok(count)
+    }
+
+    /// Remove a name, or only its records of one type, returning whether
+    /// anything was removed
+    pub fn remove(&self, qname: &str, qtype: Option<QueryType>) -> Result<bool> {
+        Ok(self.write_shard(qname)?.remove(qname, qtype))
+    }
+
+    /// Remove a name along with every name below it, returning the number of
+    /// domains removed
+    ///
+    /// The names of a subtree are spread over every shard, so they all have
+    /// to be searched.
+    pub fn remove_subtree(&self, zone: &str) -> Result<usize> {
+        let mut count = 0;
+        self.update_shards(|cache| count += cache.remove_subtree(zone))?;
+
+        Ok(count)
+    }
+
+    /// Limit the number of domains kept in the cache, which is spread evenly
+    /// over the shards
+    pub fn set_max_entries(&self, max_entries: usize) -> Result<()> {
+        let shard_count = self.shards.len();
+        let per_shard = max_entries.div_ceil(shard_count);
+
+        self.update_shards(|cache| cache.set_max_entries(per_shard))
+    }
+
+    /// Write a snapshot of the cache to a file
+    ///
+    /// The snapshot is written to a temporary file first, which then replaces
+    /// the previous snapshot, so that a crash while saving never leaves a
+    /// truncated snapshot behind.
+    pub fn save_snapshot(&self, path: &Path) -> Result<()> {
+        let mut buffer = VectorPacketBuffer::new();
+        write_snapshot_header(&mut buffer)?;
+
+        for shard in &self.shards {
+            match shard.read() {
+                Ok(cache) => cache.write_snapshot_entries(&mut buffer)?,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            }
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&buffer.buffer)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Load a snapshot of the cache from a file, returning the number of
+    /// records loaded
+    pub fn load_snapshot(&self, path: &Path) -> Result<usize> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        read_snapshot(&data, |domain, hits, records| {
+            if let Ok(mut cache) = self.write_shard(domain) {
+                cache.load_domain(domain, hits, records);
+            }
+        })
+    }
+
+    pub fn set_ttl_limits(&self, min_ttl: u32, max_ttl: u32) -> Result<()> {
+        self.update_shards(|cache| cache.set_ttl_limits(min_ttl, max_ttl))
+    }
+
+    pub fn get_statistics(&self) -> Result<CacheStatistics> {
+        let mut statistics = CacheStatistics::default();
+
+        for shard in &self.shards {
+            let cache = match shard.read() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            let shard_statistics = cache.get_statistics();
+            statistics.entries += shard_statistics.entries;
+            statistics.evictions += shard_statistics.evictions;
+            statistics.purged += shard_statistics.purged;
+        }
+
+        Ok(statistics)
+    }
+
+    pub fn purge_expired(&self, grace: u32) -> Result<usize> {
+        let mut purged = 0;
+        self.update_shards(|cache| purged += cache.purge_expired(grace))?;
+
+        Ok(purged)
+    }
+
+    /// Check whether a cached name with at least `min_hits` hits is within the
+    /// last `percent` percent of its TTL, and should be refreshed
+    pub fn should_prefetch(&self, qname: &str, qtype: QueryType, min_hits: u32, percent: u32) -> bool {
+        match self.read_shard(qname) {
+            Ok(cache) => cache.should_prefetch(qname, qtype, min_hits, percent),
+            Err(_) => false
+        }
+    }
+
     pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
-        let mut cache = match self.cache.write() {
-            Ok(x) => x,
-            Err(_) => return None
-        };
+        self.read_shard(qname).ok()?.lookup(qname, qtype)
+    }
 
-        cache.lookup(qname, qtype)
+    pub fn lookup_stale(&self, qname: &str, qtype: QueryType, max_stale: u32, stale_ttl: u32) -> Option<DnsPacket> {
+        self.read_shard(qname).ok()?.lookup_stale(qname, qtype, max_stale, stale_ttl)
     }
 
+    /// Store records, which may belong to different domains, and thereby to
+    /// different shards
     pub fn store(&self, records: &[DnsRecord]) -> Result<()> {
-        let mut cache = match self.cache.write() {
-            Ok(x) => x,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
-        };
+        self.store_with_credibility(records, Credibility::Answer)
+    }
 
-        cache.store(records);
+    pub fn store_with_credibility(&self, records: &[DnsRecord], credibility: Credibility) -> Result<()> {
+        self.store_sharded(records, |cache, records| cache.store_with_credibility(records, credibility))
+    }
+
+    /// Store the root hints, see `Cache::store_hints`
+    pub fn store_hints(&self, records: &[DnsRecord]) -> Result<()> {
+        self.store_sharded(records, |cache, records| cache.store_hints(records))
+    }
+
+    /// Split records up by the shard their domain belongs to, and pass them to
+    /// `store` along with the shard
+    fn store_sharded<F>(&self, records: &[DnsRecord], store: F) -> Result<()> where F: Fn(&mut Cache, &[DnsRecord]) {
+        let mut shard_records = vec![Vec::new(); self.shards.len()];
+        for rec in records {
+            if let Some(domain) = rec.get_domain() {
+                shard_records[self.shard_index(&domain)].push(rec.clone());
+            }
+        }
+
+        for (shard, records) in self.shards.iter().zip(shard_records) {
+            if records.is_empty() {
+                continue;
+            }
+
+            match shard.write() {
+                Ok(mut cache) => store(&mut cache, &records),
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            }
+        }
 
         Ok(())
     }
 
-    pub fn store_nxdomain(&self, qname: &str, qtype: QueryType, ttl: u32) -> Result<()> {
-        let mut cache = match self.cache.write() {
-            Ok(x) => x,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
-        };
+    pub fn store_nxdomain(&self, qname: &str, soa: Option<DnsRecord>, ttl: u32) -> Result<()> {
+        self.write_shard(qname)?.store_nxdomain(qname, soa, ttl);
 
-        cache.store_nxdomain(qname, qtype, ttl);
+        Ok(())
+    }
+
+    pub fn store_nodata(&self, qname: &str, qtype: QueryType, soa: Option<DnsRecord>, ttl: u32) -> Result<()> {
+        self.write_shard(qname)?.store_nodata(qname, qtype, soa, ttl);
 
         Ok(())
     }
@@ -319,7 +1227,7 @@ mod tests {
 
     use super::*;
 
-    use dns::protocol::{DnsRecord, QueryType, ResultCode, TransientTtl};
+    use dns::protocol::{DnsRecord, QueryClass, QueryType, ResultCode, TransientTtl};
 
     #[test]
     fn test_cache() {
@@ -331,7 +1239,7 @@ mod tests {
         }
 
         // Register a negative cache entry
-        cache.store_nxdomain("www.google.com", QueryType::A, 3600);
+        cache.store_nxdomain("www.google.com", None, 3600);
 
         // Verify that we get a response, with the NXDOMAIN flag set
         if let Some(packet) = cache.lookup("www.google.com", QueryType::A) {
@@ -339,7 +1247,7 @@ mod tests {
         }
 
         // Register a negative cache entry with no TTL
-        cache.store_nxdomain("www.yahoo.com", QueryType::A, 0);
+        cache.store_nxdomain("www.yahoo.com", None, 0);
 
         // And check that no such result is actually returned, since it's expired
         if cache.lookup("www.yahoo.com", QueryType::A).is_some() {
@@ -350,16 +1258,19 @@ mod tests {
         let mut records = Vec::new();
         records.push(DnsRecord::A {
             domain: "www.google.com".to_string(),
+            class: QueryClass::IN,
             addr: "127.0.0.1".parse().unwrap(),
             ttl: TransientTtl(3600)
         });
         records.push(DnsRecord::A {
             domain: "www.yahoo.com".to_string(),
+            class: QueryClass::IN,
             addr: "127.0.0.2".parse().unwrap(),
             ttl: TransientTtl(0)
         });
         records.push(DnsRecord::CNAME {
             domain: "www.microsoft.com".to_string(),
+            class: QueryClass::IN,
             host: "www.somecdn.com".to_string(),
             ttl: TransientTtl(3600)
         });
@@ -393,6 +1304,7 @@ mod tests {
         let mut records2 = Vec::new();
         records2.push(DnsRecord::A {
             domain: "www.yahoo.com".to_string(),
+            class: QueryClass::IN,
             addr: "127.0.0.2".parse().unwrap(),
             ttl: TransientTtl(3600)
         });
@@ -406,11 +1318,503 @@ mod tests {
 
         // Check stat counter behavior
         assert_eq!(3, cache.domain_entries.len());
-        assert_eq!(1, cache.domain_entries.get(&"www.google.com".to_string()).unwrap().hits);
+        assert_eq!(1, cache.domain_entries.get(&"www.google.com".to_string()).unwrap().get_hits());
         assert_eq!(2, cache.domain_entries.get(&"www.google.com".to_string()).unwrap().updates);
-        assert_eq!(1, cache.domain_entries.get(&"www.yahoo.com".to_string()).unwrap().hits);
+        assert_eq!(1, cache.domain_entries.get(&"www.yahoo.com".to_string()).unwrap().get_hits());
         assert_eq!(3, cache.domain_entries.get(&"www.yahoo.com".to_string()).unwrap().updates);
         assert_eq!(1, cache.domain_entries.get(&"www.microsoft.com".to_string()).unwrap().updates);
-        assert_eq!(1, cache.domain_entries.get(&"www.microsoft.com".to_string()).unwrap().hits);
+        assert_eq!(1, cache.domain_entries.get(&"www.microsoft.com".to_string()).unwrap().get_hits());
+    }
+
+    #[test]
+    fn test_cache_case_insensitive() {
+        let mut cache = Cache::new();
+
+        cache.store(&[DnsRecord::A {
+            domain: "WwW.GoOgLe.CoM".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+        cache.store(&[DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+
+        // The record is only kept once, under the lowercase name
+        let packet = cache.lookup("WWW.google.com", QueryType::A).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(Some("www.google.com".to_string()), packet.answers[0].get_domain());
+
+        cache.store_nxdomain("Www.Yahoo.Com", None, 3600);
+        let packet = cache.lookup("www.yahoo.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.res_code);
+
+        // The root is the same name whether it's written as `.` or read from
+        // the wire as the empty name
+        cache.store(&[DnsRecord::NS {
+            domain: "".to_string(),
+            class: QueryClass::IN,
+            host: "a.root-servers.net".to_string(),
+            ttl: TransientTtl(3600)
+        }]);
+        let packet = cache.lookup(".", QueryType::NS).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(Some(".".to_string()), packet.answers[0].get_domain());
+        assert!(cache.lookup("", QueryType::NS).is_some());
+    }
+
+    /// Move the records of a domain into the past
+    fn age_records(cache: &mut Cache, domain: &str, qtype: QueryType, age: Duration) {
+        if let Some(entry) = cache.domain_entries.get_mut(domain).map(Arc::make_mut) {
+            if let Some(&mut RecordSet::Records { ref mut records, .. }) = entry.record_types.get_mut(&qtype) {
+                *records = records.drain()
+                    .map(|mut x| { x.timestamp = x.timestamp - age; x })
+                    .collect();
+            }
+        }
+    }
+
+    fn a_record(domain: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(ttl)
+        }
+    }
+
+    fn soa_record(ttl: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "google.com".to_string(),
+            class: QueryClass::IN,
+            m_name: "ns1.google.com".to_string(),
+            r_name: "dns-admin.google.com".to_string(),
+            serial: 1,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: TransientTtl(ttl)
+        }
+    }
+
+    #[test]
+    fn test_cache_negative() {
+        let mut cache = Cache::new();
+
+        // NODATA only covers the type, and is answered without an error
+        cache.store(&[a_record("www.google.com", 3600)]);
+        cache.store_nodata("www.google.com", QueryType::AAAA, Some(soa_record(3600)), 60);
+
+        let packet = cache.lookup("www.google.com", QueryType::AAAA).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.res_code);
+        assert_eq!(0, packet.answers.len());
+        assert_eq!(1, packet.authorities.len());
+        assert_eq!(soa_record(0), packet.authorities[0]);
+
+        // The SOA is returned with the time remaining of the negative entry
+        let ttl = packet.authorities[0].get_ttl();
+        assert!(ttl <= 60 && ttl >= 59);
+
+        assert_eq!(1, cache.lookup("www.google.com", QueryType::A).unwrap().answers.len());
+
+        // NXDOMAIN covers every type, and replaces what's known of the name
+        cache.store_nxdomain("www.google.com", Some(soa_record(3600)), 60);
+
+        for qtype in &[QueryType::A, QueryType::AAAA, QueryType::MX] {
+            let packet = cache.lookup("www.google.com", *qtype).unwrap();
+            assert_eq!(ResultCode::NXDOMAIN, packet.header.res_code);
+            assert_eq!(0, packet.answers.len());
+            assert_eq!(1, packet.authorities.len());
+        }
+
+        // Until records show up for the name again
+        cache.store(&[a_record("www.google.com", 3600)]);
+        assert_eq!(ResultCode::NOERROR, cache.lookup("www.google.com", QueryType::A).unwrap().header.res_code);
+        assert!(cache.lookup("www.google.com", QueryType::MX).is_none());
+
+        // The TTL limits apply to negative answers as well
+        cache.set_ttl_limits(120, 600);
+        cache.store_nodata("www.google.com", QueryType::AAAA, Some(soa_record(3600)), 60);
+        cache.store_nxdomain("www.yahoo.com", Some(soa_record(3600)), 3600);
+
+        let ttl = cache.lookup("www.google.com", QueryType::AAAA).unwrap().authorities[0].get_ttl();
+        assert!(ttl <= 120 && ttl >= 119);
+        let ttl = cache.lookup("www.yahoo.com", QueryType::A).unwrap().authorities[0].get_ttl();
+        assert!(ttl <= 600 && ttl >= 599);
+    }
+
+    #[test]
+    fn test_cache_snapshot() {
+        let mut cache = Cache::new();
+
+        cache.store(&[
+            DnsRecord::NS {
+                domain: ".".to_string(),
+                class: QueryClass::IN,
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600000)
+            },
+            a_record("www.google.com", 3600),
+            a_record("www.yahoo.com", 3600),
+            DnsRecord::TXT {
+                domain: "www.yahoo.com".to_string(),
+                class: QueryClass::IN,
+                data: vec![b"hello".to_vec(), b"world".to_vec()],
+                ttl: TransientTtl(600)
+            }
+        ]);
+        cache.store_nxdomain("www.microsoft.com", None, 3600);
+
+        cache.lookup("www.google.com", QueryType::A).unwrap();
+        cache.lookup("www.google.com", QueryType::A).unwrap();
+
+        // This one has expired by the time the snapshot is taken
+        age_records(&mut cache, "www.yahoo.com", QueryType::A, Duration::hours(2));
+
+        let data = cache.write_snapshot().unwrap();
+
+        let mut loaded = Cache::new();
+        assert_eq!(3, loaded.read_snapshot(&data).unwrap());
+
+        let packet = loaded.lookup(".", QueryType::NS).unwrap();
+        assert_eq!(1, packet.answers.len());
+
+        let packet = loaded.lookup("www.google.com", QueryType::A).unwrap();
+        assert_eq!(a_record("www.google.com", 0), packet.answers[0]);
+        let ttl = packet.answers[0].get_ttl();
+        assert!(ttl <= 3600 && ttl >= 3598);
+        assert_eq!(3, loaded.domain_entries.get("www.google.com").unwrap().get_hits());
+
+        assert!(loaded.lookup("www.yahoo.com", QueryType::A).is_none());
+        assert_eq!(1, loaded.lookup("www.yahoo.com", QueryType::TXT).unwrap().answers.len());
+        assert!(loaded.lookup("www.microsoft.com", QueryType::A).is_none());
+
+        // Snapshots of unknown versions, and other data, are rejected
+        let mut future = data.clone();
+        future[SNAPSHOT_MAGIC.len() + 1] = 99;
+        assert!(Cache::new().read_snapshot(&future).is_err());
+        assert!(Cache::new().read_snapshot(b"not a snapshot").is_err());
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut cache = Cache::new();
+
+        cache.store(&[a_record("www.google.com", 3600)]);
+        age_records(&mut cache, "www.google.com", QueryType::A, Duration::minutes(10));
+
+        // The TTL of a cached answer is the time remaining until it expires
+        let packet = cache.lookup("www.google.com", QueryType::A).unwrap();
+        let ttl = packet.answers[0].get_ttl();
+        assert!(ttl <= 3000 && ttl >= 2990);
+
+        // TTLs are clamped when storing
+        cache.set_ttl_limits(60, 86400);
+        cache.store(&[a_record("www.yahoo.com", 0), a_record("www.microsoft.com", 604800)]);
+
+        let packet = cache.lookup("www.yahoo.com", QueryType::A).unwrap();
+        assert!(packet.answers[0].get_ttl() <= 60 && packet.answers[0].get_ttl() >= 59);
+
+        let packet = cache.lookup("www.microsoft.com", QueryType::A).unwrap();
+        assert!(packet.answers[0].get_ttl() <= 86400 && packet.answers[0].get_ttl() >= 86399);
+
+        // Except for the root hints, which are stored after the limits have
+        // been set, and mustn't expire along with them
+        let cache = SynchronizedCache::new();
+        cache.set_ttl_limits(0, 3600).unwrap();
+        cache.store_hints(&[
+            DnsRecord::NS {
+                domain: ".".to_string(),
+                class: QueryClass::IN,
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600000)
+            },
+            a_record("a.root-servers.net", 3600000)
+        ]).unwrap();
+
+        let packet = cache.lookup(".", QueryType::NS).unwrap();
+        assert!(packet.answers[0].get_ttl() > 3599000);
+        let packet = cache.lookup("a.root-servers.net", QueryType::A).unwrap();
+        assert!(packet.answers[0].get_ttl() > 3599000);
+
+        // They also survive a flush, which removes everything else
+        cache.store(&[a_record("www.google.com", 3600)]).unwrap();
+        assert_eq!(3, cache.flush().unwrap());
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+        assert!(cache.lookup(".", QueryType::NS).is_some());
+        assert!(cache.lookup("a.root-servers.net", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_cache_remove_hints() {
+        let cache = SynchronizedCache::with_shards(4);
+        cache.store_hints(&[
+            DnsRecord::NS {
+                domain: ".".to_string(),
+                class: QueryClass::IN,
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600000)
+            },
+            a_record("a.root-servers.net", 3600000)
+        ]).unwrap();
+        cache.store(&[a_record("www.google.com", 3600)]).unwrap();
+
+        // Removing the root hints, by name or along with a subtree, leaves
+        // them in place, while the rest goes as usual
+        assert!(cache.remove(".", Some(QueryType::NS)).unwrap());
+        assert!(cache.remove(".", None).unwrap());
+        assert!(cache.remove("a.root-servers.net", None).unwrap());
+        assert_eq!(1, cache.remove_subtree("root-servers.net").unwrap());
+        assert_eq!(1, cache.remove_subtree("net").unwrap());
+        assert!(cache.lookup(".", QueryType::NS).is_some());
+        assert!(cache.lookup("a.root-servers.net", QueryType::A).is_some());
+
+        assert_eq!(3, cache.remove_subtree("").unwrap());
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+        assert!(cache.lookup(".", QueryType::NS).is_some());
+        assert!(cache.lookup("a.root-servers.net", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut cache = Cache::new();
+        cache.set_max_entries(10);
+
+        cache.store(&[DnsRecord::NS {
+            domain: ".".to_string(),
+            class: QueryClass::IN,
+            host: "a.root-servers.net".to_string(),
+            ttl: TransientTtl(3600)
+        }]);
+
+        for i in 0..9 {
+            cache.store(&[a_record(&format!("host{}.com", i), 3600)]);
+        }
+
+        // Give all hosts but the first one some hits
+        for i in 1..9 {
+            cache.lookup(&format!("host{}.com", i), QueryType::A).unwrap();
+        }
+
+        assert_eq!(10, cache.get_statistics().entries);
+        assert_eq!(0, cache.get_statistics().evictions);
+
+        // Storing a new domain when full evicts the least used one, but
+        // never the root
+        cache.store(&[a_record("new.com", 3600)]);
+
+        let stats = cache.get_statistics();
+        assert_eq!(10, stats.entries);
+        assert_eq!(1, stats.evictions);
+        assert!(cache.lookup("host0.com", QueryType::A).is_none());
+        assert!(cache.lookup("new.com", QueryType::A).is_some());
+        assert!(cache.lookup(".", QueryType::NS).is_some());
+
+        // Updating a domain which is already present doesn't evict anything
+        cache.store(&[a_record("host1.com", 60)]);
+        assert_eq!(1, cache.get_statistics().evictions);
+    }
+
+    #[test]
+    fn test_cache_purge() {
+        let mut cache = Cache::new();
+
+        cache.store(&[a_record("www.google.com", 3600), a_record("www.yahoo.com", 3600)]);
+        cache.store(&[DnsRecord::CNAME {
+            domain: "www.yahoo.com".to_string(),
+            class: QueryClass::IN,
+            host: "yahoo.com".to_string(),
+            ttl: TransientTtl(60)
+        }]);
+        cache.store_nxdomain("www.microsoft.com", None, 0);
+
+        age_records(&mut cache, "www.google.com", QueryType::A, Duration::hours(3));
+        age_records(&mut cache, "www.yahoo.com", QueryType::CNAME, Duration::hours(3));
+
+        // Within the grace period nothing but the expired negative entry goes
+        assert_eq!(1, cache.purge_expired(86400));
+        assert_eq!(2, cache.get_statistics().entries);
+
+        // After it the expired records are removed, along with the domains
+        // left without any records
+        assert_eq!(2, cache.purge_expired(3600));
+
+        let stats = cache.get_statistics();
+        assert_eq!(1, stats.entries);
+        assert_eq!(3, stats.purged);
+        assert!(cache.lookup("www.yahoo.com", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_cache_prefetch() {
+        let cache = SynchronizedCache::new();
+
+        cache.store(&[a_record("www.google.com", 3600)]).unwrap();
+
+        // Fresh records don't need prefetching
+        cache.lookup("www.google.com", QueryType::A).unwrap();
+        assert!(!cache.should_prefetch("www.google.com", QueryType::A, 1, 10));
+
+        // Records in the last part of their TTL do, once they've been
+        // hit often enough
+        age_records(&mut cache.write_shard("www.google.com").unwrap(), "www.google.com", QueryType::A, Duration::minutes(55));
+        assert!(cache.should_prefetch("www.google.com", QueryType::A, 1, 10));
+        assert!(!cache.should_prefetch("www.google.com", QueryType::A, 2, 10));
+        assert!(!cache.should_prefetch("www.google.com", QueryType::AAAA, 1, 10));
+        assert!(!cache.should_prefetch("www.yahoo.com", QueryType::A, 0, 10));
+    }
+
+    #[test]
+    fn test_synchronized_cache() {
+        let cache = SynchronizedCache::with_shards(4);
+
+        let records = (0..20).map(|i| a_record(&format!("host{}.com", i), 3600)).collect::<Vec<DnsRecord>>();
+        cache.store(&records).unwrap();
+
+        // Listing covers every shard, in order of name
+        let list = cache.list().unwrap();
+        assert_eq!(20, list.len());
+        assert!(list.windows(2).all(|x| x[0].domain < x[1].domain));
+
+        // Hits are still counted while the entries are held elsewhere
+        for _ in 0..3 {
+            cache.lookup("HOST7.com", QueryType::A).unwrap();
+        }
+        assert_eq!(3, list.iter().find(|x| x.domain == "host7.com").unwrap().get_hits());
+
+        // As are updates, which leave the listed entries as they were
+        cache.store(&[a_record("host7.com", 60)]).unwrap();
+        let entry = cache.list().unwrap().into_iter().find(|x| x.domain == "host7.com").unwrap();
+        assert_eq!(2, entry.updates);
+        assert_eq!(3, entry.get_hits());
+
+        // The limit is spread over the shards
+        cache.set_max_entries(8).unwrap();
+        for i in 20..40 {
+            cache.store(&[a_record(&format!("host{}.com", i), 3600)]).unwrap();
+        }
+        assert!(cache.get_statistics().unwrap().entries <= 8);
+        assert!(cache.get_statistics().unwrap().evictions > 0);
+    }
+
+    #[test]
+    fn test_cache_credibility() {
+        let mut cache = Cache::new();
+
+        let answer = |addr: &str| DnsRecord::A {
+            domain: "ns1.google.com".to_string(),
+            class: QueryClass::IN,
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+
+        cache.store_with_credibility(&[answer("127.0.0.1"), answer("127.0.0.2")], Credibility::AuthoritativeAnswer);
+
+        // Glue doesn't replace an authoritative answer
+        cache.store_with_credibility(&[answer("127.0.0.66")], Credibility::Additional);
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(2, packet.answers.len());
+        assert!(!packet.answers.contains(&answer("127.0.0.66")));
+
+        // Data as credible replaces the whole RRset, rather than being merged
+        // into it
+        cache.store_with_credibility(&[answer("127.0.0.3")], Credibility::AuthoritativeAnswer);
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(vec![answer("127.0.0.3")], packet.answers);
+
+        // Once expired, anything replaces it
+        age_records(&mut cache, "ns1.google.com", QueryType::A, Duration::seconds(3601));
+        cache.store_with_credibility(&[answer("127.0.0.4")], Credibility::Additional);
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(vec![answer("127.0.0.4")], packet.answers);
+
+        // And more credible data replaces less credible data
+        cache.store_with_credibility(&[answer("127.0.0.5")], Credibility::Answer);
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(vec![answer("127.0.0.5")], packet.answers);
+    }
+
+    #[test]
+    fn test_cache_administration() {
+        let cache = SynchronizedCache::with_shards(4);
+
+        cache.store(&[
+            a_record("example.com", 3600),
+            a_record("www.example.com", 3600),
+            a_record("a.b.example.com", 3600),
+            a_record("badexample.com", 3600),
+            a_record("www.google.com", 3600),
+            DnsRecord::CNAME {
+                domain: "www.google.com".to_string(),
+                class: QueryClass::IN,
+                host: "google.com".to_string(),
+                ttl: TransientTtl(3600)
+            }
+        ]).unwrap();
+        cache.store_nodata("www.google.com", QueryType::AAAA, None, 3600).unwrap();
+        cache.store_nxdomain("www.yahoo.com", None, 3600).unwrap();
+        cache.store_nxdomain("www.microsoft.com", None, 0).unwrap();
+
+        // The dump holds what's valid, with the remaining TTLs
+        let dump = cache.dump().unwrap();
+        let domains = dump.iter().map(|x| x.domain.as_str()).collect::<Vec<&str>>();
+        assert_eq!(vec!["a.b.example.com", "badexample.com", "example.com", "www.example.com",
+                        "www.google.com", "www.yahoo.com"], domains);
+
+        let google = &dump[4];
+        assert_eq!(2, google.records.len());
+        assert!(google.records.iter().all(|x| x.get_ttl() <= 3600 && x.get_ttl() >= 3599));
+        assert_eq!(vec![QueryType::AAAA], google.nodata);
+        assert!(dump[5].nxdomain);
+
+        // Removing a single type keeps the rest of the name
+        assert!(cache.remove("WWW.google.com", Some(QueryType::CNAME)).unwrap());
+        assert!(!cache.remove("www.google.com", Some(QueryType::CNAME)).unwrap());
+        assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+        assert!(cache.lookup("www.google.com", QueryType::CNAME).is_none());
+
+        assert!(cache.remove("www.google.com", None).unwrap());
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+
+        // A subtree covers the name itself and what's below it, but not
+        // names which merely end the same way
+        assert_eq!(3, cache.remove_subtree("example.com").unwrap());
+        assert!(cache.lookup("badexample.com", QueryType::A).is_some());
+
+        assert_eq!(3, cache.flush().unwrap());
+        assert_eq!(0, cache.get_statistics().unwrap().entries);
+    }
+
+    #[test]
+    fn test_cache_stale() {
+        let mut cache = Cache::new();
+
+        cache.store(&[DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(0)
+        }]);
+
+        age_records(&mut cache, "www.google.com", QueryType::A, Duration::hours(1));
+
+        // The expired record isn't returned by a regular lookup
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+
+        // But it is served stale within the window, with the stale TTL
+        let packet = cache.lookup_stale("www.google.com", QueryType::A, 7200, 30).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(30, packet.answers[0].get_ttl());
+
+        // And not once the window has passed
+        assert!(cache.lookup_stale("www.google.com", QueryType::A, 1800, 30).is_none());
+
+        // Negative entries are never served stale
+        cache.store_nxdomain("www.yahoo.com", None, 0);
+        assert!(cache.lookup_stale("www.yahoo.com", QueryType::A, 7200, 30).is_none());
     }
 }
@@ -10,20 +10,28 @@ use std::time::Duration as SleepDuration;
 use std::sync::atomic::{AtomicUsize,Ordering};
 
 use chrono::*;
+use rand::random;
 
-use dns::buffer::{PacketBuffer, BytePacketBuffer, StreamPacketBuffer};
+use dns::buffer::{PacketBuffer, BytePacketBuffer, StreamPacketBuffer, VectorPacketBuffer};
 use dns::protocol::{DnsPacket, DnsQuestion, QueryType};
 use dns::netutil::{read_packet_length, write_packet_length};
+use dns::https::{HttpsConnections, HttpsEndpoint};
+use dns::tls::{TlsAuth, TlsConnections, client_config};
+use dns::upstream::{Upstream, UpstreamProtocol};
+
+use rustls::RootCertStore;
 
 pub trait DnsClient {
     fn get_sent_count(&self) -> usize;
     fn get_failed_count(&self) -> usize;
 
     fn run(&self) -> Result<()>;
+
+    /// Send a query to a server, using the protocol of `upstream`
     fn send_query(&self,
                   qname: &str,
                   qtype: QueryType,
-                  server: (&str, u16),
+                  upstream: &Upstream,
                   recursive: bool) -> Result<DnsPacket>;
 }
 
@@ -46,18 +54,63 @@ pub struct DnsNetworkClient {
     socket: UdpSocket,
 
     /// Queries in progress
-    pending_queries: Arc<Mutex<Vec<PendingQuery>>>
+    pending_queries: Arc<Mutex<Vec<PendingQuery>>>,
+
+    /// Connections to servers queried over TLS
+    tls: TlsConnections,
+
+    /// Connections to servers queried over HTTPS
+    https: HttpsConnections
 }
 
 /// A query in progress. This struct holds the `id` if the request, and a channel
 /// endpoint for returning a response back to the thread from which the query
-/// was posed.
+/// was posed. The question is kept as sent, so that the response can be
+/// checked against it.
 struct PendingQuery {
     seq: u16,
+    question: DnsQuestion,
     timestamp: DateTime<Local>,
     tx: Sender<Option<DnsPacket>>
 }
 
+/// Randomize the case of the letters in a name
+///
+/// Servers copy the question into their response verbatim, so a response has
+/// to repeat the exact case we picked. This makes it harder to spoof responses,
+/// since an attacker has to guess the case as well as the packet id. This is
+/// commonly known as DNS 0x20.
+fn randomize_case(qname: &str) -> String {
+    qname.chars()
+        .map(|c| if random::<bool>() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+        .collect()
+}
+
+/// Check that the question of a response matches the one sent, bit for bit
+fn matches_question(packet: &DnsPacket, question: &DnsQuestion) -> bool {
+    packet.questions.len() == 1 &&
+        packet.questions[0].name == question.name &&
+        packet.questions[0].qtype == question.qtype &&
+        packet.questions[0].qclass == question.qclass
+}
+
+/// Restore the name asked for by the caller in a response to a randomized query
+fn restore_case(packet: &mut DnsPacket, question: &DnsQuestion, qname: &str) {
+    for q in &mut packet.questions {
+        q.name = qname.to_string();
+    }
+
+    let records = packet.answers.iter_mut()
+        .chain(packet.authorities.iter_mut())
+        .chain(packet.resources.iter_mut());
+
+    for rec in records {
+        if rec.get_domain().as_ref() == Some(&question.name) {
+            rec.set_domain(qname.to_string());
+        }
+    }
+}
+
 unsafe impl Send for DnsNetworkClient {}
 unsafe impl Sync for DnsNetworkClient {}
 
@@ -68,18 +121,22 @@ impl DnsNetworkClient {
             total_failed: AtomicUsize::new(0),
             seq: AtomicUsize::new(0),
             socket: UdpSocket::bind(("0.0.0.0", port)).unwrap(),
-            pending_queries: Arc::new(Mutex::new(Vec::new()))
+            pending_queries: Arc::new(Mutex::new(Vec::new())),
+            tls: TlsConnections::new(client_config(None)),
+            https: HttpsConnections::new(client_config(None))
         }
     }
 
-    /// Send a DNS query using TCP transport
-    ///
-    /// This is much simpler than using UDP, since the kernel will take care of
-    /// packet ordering, connection state, timeouts etc.
-    pub fn send_tcp_query(&self, qname: &str, qtype: QueryType, server: (&str, u16), recursive: bool) -> Result<DnsPacket> {
-        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+    /// Verify the certificates of servers queried over TLS or HTTPS using
+    /// `roots`, rather than the bundled Mozilla root certificates
+    pub fn set_tls_roots(&mut self, roots: RootCertStore) {
+        self.tls = TlsConnections::new(client_config(Some(roots.clone())));
+        self.https = HttpsConnections::new(client_config(Some(roots)));
+    }
 
-        // Prepare request
+    /// Create a query packet with a fresh id, and a question with the case of
+    /// the name randomized
+    fn create_query(&self, qname: &str, qtype: QueryType, recursive: bool) -> DnsPacket {
         let mut packet = DnsPacket::new();
 
         packet.header.id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
@@ -90,7 +147,34 @@ impl DnsNetworkClient {
         packet.header.questions = 1;
         packet.header.recursion_desired = recursive;
 
-        packet.questions.push(DnsQuestion::new(qname.into(), qtype));
+        packet.questions.push(DnsQuestion::new(randomize_case(qname), qtype));
+
+        packet
+    }
+
+    /// Check that a response received over a stream belongs to the query, and
+    /// restore the case of the name asked for
+    fn check_response(&self, mut response: DnsPacket, packet: &DnsPacket, qname: &str) -> Result<DnsPacket> {
+        let question = &packet.questions[0];
+        if response.header.id != packet.header.id || !matches_question(&response, question) {
+            let _ = self.total_failed.fetch_add(1, Ordering::Release);
+            return Err(Error::new(ErrorKind::InvalidData, "Response does not match query"));
+        }
+
+        restore_case(&mut response, question, qname);
+
+        Ok(response)
+    }
+
+    /// Send a DNS query using TCP transport
+    ///
+    /// This is much simpler than using UDP, since the kernel will take care of
+    /// packet ordering, connection state, timeouts etc.
+    pub fn send_tcp_query(&self, qname: &str, qtype: QueryType, server: (&str, u16), recursive: bool) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+
+        // Prepare request
+        let mut packet = self.create_query(qname, qtype, recursive);
 
         // Send query
         let mut req_buffer = BytePacketBuffer::new();
@@ -99,13 +183,87 @@ impl DnsNetworkClient {
         let mut socket = TcpStream::connect(server)?;
 
         write_packet_length(&mut socket, req_buffer.pos())?;
-        socket.write(&req_buffer.buf[0..req_buffer.pos])?;
+        socket.write_all(&req_buffer.buf[0..req_buffer.pos])?;
         socket.flush()?;
 
         let _ = read_packet_length(&mut socket)?;
 
         let mut stream_buffer = StreamPacketBuffer::new(&mut socket);
-        DnsPacket::from_buffer(&mut stream_buffer)
+        let response = DnsPacket::from_buffer(&mut stream_buffer)?;
+
+        self.check_response(response, &packet, qname)
+    }
+
+    /// Send a DNS query over TLS (RFC 7858)
+    ///
+    /// Connections are kept open between queries, and shared by the ones
+    /// sent at the same time, see `TlsConnections`.
+    pub fn send_tls_query(&self, qname: &str, qtype: QueryType, server: (&str, u16), auth: &TlsAuth, recursive: bool) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+
+        let mut packet = self.create_query(qname, qtype, recursive);
+
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer, 0xFFFF)?;
+
+        let data = match self.tls.send(server, auth, &req_buffer.buf[0..req_buffer.pos]) {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = self.total_failed.fetch_add(1, Ordering::Release);
+                return Err(e);
+            }
+        };
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = data;
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+
+        self.check_response(response, &packet, qname)
+    }
+
+    /// Send a DNS query over HTTPS (RFC 8484)
+    ///
+    /// The response is only as fresh as the HTTP response carrying it, so the
+    /// TTLs of the records are capped at the max-age of its Cache-Control
+    /// header.
+    pub fn send_https_query(&self, qname: &str, qtype: QueryType, server: (&str, u16), endpoint: &HttpsEndpoint, recursive: bool) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+
+        // The id is always 0, and the case of the name left alone, so that
+        // HTTP caches can tell identical queries apart. Responses can't be
+        // spoofed anyway, since they're matched to requests by HTTP.
+        let mut packet = self.create_query(qname, qtype, recursive);
+        packet.header.id = 0;
+        packet.questions[0].name = qname.to_string();
+
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer, 0xFFFF)?;
+
+        let response = match self.https.send(server, endpoint, &req_buffer.buf[0..req_buffer.pos]) {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = self.total_failed.fetch_add(1, Ordering::Release);
+                return Err(e);
+            }
+        };
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = response.message;
+        let mut result = DnsPacket::from_buffer(&mut res_buffer)?;
+
+        if let Some(max_age) = response.max_age {
+            let records = result.answers.iter_mut()
+                .chain(result.authorities.iter_mut())
+                .chain(result.resources.iter_mut());
+
+            for rec in records {
+                if rec.get_ttl() > max_age {
+                    rec.set_ttl(max_age);
+                }
+            }
+        }
+
+        self.check_response(result, &packet, qname)
     }
 
     /// Send a DNS query using UDP transport
@@ -119,17 +277,8 @@ impl DnsNetworkClient {
         let _ = self.total_sent.fetch_add(1, Ordering::Release);
 
         // Prepare request
-        let mut packet = DnsPacket::new();
-
-        packet.header.id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
-        if packet.header.id + 1 == 0xFFFF {
-            self.seq.compare_and_swap(0xFFFF, 0, Ordering::SeqCst);
-        }
-
-        packet.header.questions = 1;
-        packet.header.recursion_desired = recursive;
-
-        packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+        let mut packet = self.create_query(qname, qtype, recursive);
+        let question = packet.questions[0].clone();
 
         // Create a return channel, and add a `PendingQuery` to the list of lookups
         // in progress
@@ -138,6 +287,7 @@ impl DnsNetworkClient {
             Ok(mut pending_queries) => {
                 pending_queries.push(PendingQuery {
                     seq: packet.header.id,
+                    question: question.clone(),
                     timestamp: Local::now(),
                     tx: tx
                 });
@@ -153,7 +303,10 @@ impl DnsNetworkClient {
         // Wait for response
         if let Ok(res) = rx.recv() {
             match res {
-                Some(qr) => return Ok(qr),
+                Some(mut qr) => {
+                    restore_case(&mut qr, &question, qname);
+                    return Ok(qr);
+                },
                 None => {
                     let _ = self.total_failed.fetch_add(1, Ordering::Release);
                     return Err(Error::new(ErrorKind::TimedOut, "Request timed out"))
@@ -214,6 +367,14 @@ impl DnsClient for DnsNetworkClient {
                             for (i, pending_query) in pending_queries.iter().enumerate() {
                                 if pending_query.seq == packet.header.id {
 
+                                    // A response with the right id, but a different
+                                    // question, is most likely spoofed. Drop it, and
+                                    // keep waiting for the real one.
+                                    if !matches_question(&packet, &pending_query.question) {
+                                        println!("Discarding mismatched response for: {:?}", packet.questions.first());
+                                        break;
+                                    }
+
                                     // Matching query found, send the response
                                     let _ = pending_query.tx.send(Some(packet.clone()));
 
@@ -266,14 +427,33 @@ impl DnsClient for DnsNetworkClient {
         Ok(())
     }
 
-    fn send_query(&self, qname: &str, qtype: QueryType, server: (&str, u16), recursive: bool) -> Result<DnsPacket> {
-        let packet = self.send_udp_query(qname, qtype, server, recursive)?;
-        if !packet.header.truncated_message {
-            return Ok(packet);
-        }
+    fn send_query(&self, qname: &str, qtype: QueryType, upstream: &Upstream, recursive: bool) -> Result<DnsPacket> {
+        let server = (upstream.host.as_str(), upstream.port);
+
+        match upstream.protocol {
+            UpstreamProtocol::Udp => {
+                let packet = self.send_udp_query(qname, qtype, server, recursive)?;
+                if !packet.header.truncated_message {
+                    return Ok(packet);
+                }
+
+                println!("Truncated response - resending as TCP");
+                self.send_tcp_query(qname, qtype, server, recursive)
+            },
+            UpstreamProtocol::Tls { ref auth } => self.send_tls_query(qname, qtype, server, auth, recursive),
+            UpstreamProtocol::Https { ref endpoint, ref addrs } => {
+                // The addresses of the server are tried in turn
+                let mut result = Err(Error::new(ErrorKind::InvalidInput, "No address to connect to"));
+                for addr in addrs {
+                    result = self.send_https_query(qname, qtype, (addr.as_str(), upstream.port), endpoint, recursive);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
 
-        println!("Truncated response - resending as TCP");
-        self.send_tcp_query(qname, qtype, server, recursive)
+                result
+            }
+        }
     }
 }
 
@@ -282,19 +462,29 @@ pub mod tests {
 
     use std::io::Result;
 
-    use dns::protocol::{DnsPacket,QueryType,DnsRecord};
+    use dns::protocol::{DnsPacket,QueryClass,QueryType,DnsRecord,TransientTtl};
     use super::*;
 
     pub type StubCallback = Fn(&str, QueryType, (&str, u16), bool) -> Result<DnsPacket>;
 
     pub struct DnsStubClient {
-        callback: Box<StubCallback>
+        callback: Box<StubCallback>,
+        /// The protocol of every query sent, in order
+        protocols: Mutex<Vec<UpstreamProtocol>>
     }
 
     impl<'a> DnsStubClient {
         pub fn new(callback: Box<StubCallback>) -> DnsStubClient {
             DnsStubClient {
-                callback: callback
+                callback: callback,
+                protocols: Mutex::new(Vec::new())
+            }
+        }
+
+        pub fn get_protocols(&self) -> Vec<UpstreamProtocol> {
+            match self.protocols.lock() {
+                Ok(x) => x.clone(),
+                Err(_) => Vec::new()
             }
         }
     }
@@ -316,11 +506,58 @@ pub mod tests {
             Ok(())
         }
 
-        fn send_query(&self, qname: &str, qtype: QueryType, server: (&str, u16), recursive: bool) -> Result<DnsPacket> {
-            (self.callback)(qname, qtype, server, recursive)
+        fn send_query(&self, qname: &str, qtype: QueryType, upstream: &Upstream, recursive: bool) -> Result<DnsPacket> {
+            if let Ok(mut x) = self.protocols.lock() {
+                x.push(upstream.protocol.clone());
+            }
+
+            (self.callback)(qname, qtype, (upstream.host.as_str(), upstream.port), recursive)
         }
     }
 
+    #[test]
+    pub fn test_randomize_case() {
+        let qname = "www.some-long-domain-name.example.com";
+
+        let randomized = randomize_case(qname);
+        assert!(randomized.eq_ignore_ascii_case(qname));
+
+        // With 31 letters, the odds of never changing the case are negligible
+        let mut changed = false;
+        for _ in 0..10 {
+            changed |= randomize_case(qname) != qname;
+        }
+        assert!(changed);
+    }
+
+    #[test]
+    pub fn test_match_and_restore_case() {
+        let question = DnsQuestion::new("wWw.GooGle.cOm".to_string(), QueryType::A);
+
+        let mut response = DnsPacket::new();
+        response.questions.push(question.clone());
+        response.answers.push(DnsRecord::A {
+            domain: "wWw.GooGle.cOm".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+        assert!(matches_question(&response, &question));
+
+        restore_case(&mut response, &question, "www.google.com");
+        assert_eq!("www.google.com", response.questions[0].name);
+        assert_eq!(Some("www.google.com".to_string()), response.answers[0].get_domain());
+
+        // A response which doesn't repeat the case exactly is rejected
+        assert!(!matches_question(&response, &question));
+
+        let mut response = DnsPacket::new();
+        response.questions.push(DnsQuestion::new("wWw.GooGle.cOm".to_string(), QueryType::AAAA));
+        assert!(!matches_question(&response, &question));
+
+        assert!(!matches_question(&DnsPacket::new(), &question));
+    }
+
     #[test]
     pub fn test_udp_client() {
         let client = DnsNetworkClient::new(31456);
@@ -360,4 +597,106 @@ pub mod tests {
             _ => panic!()
         }
     }
+
+    #[test]
+    pub fn test_tls_client() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::path::Path;
+        use std::thread;
+
+        use rustls::{ServerConnection, StreamOwned};
+
+        use dns::netutil::{read_packet_length, write_packet_length};
+        use dns::tls::load_roots;
+        use dns::tls::tests::test_server_config;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // A server answering every query on a connection with a single A record
+        let config = test_server_config();
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(config).unwrap();
+            let mut stream = StreamOwned::new(conn, socket);
+
+            while let Ok(len) = read_packet_length(&mut stream) {
+                let mut req_buffer = VectorPacketBuffer::new();
+                req_buffer.buffer = vec![0; len as usize];
+                stream.read_exact(&mut req_buffer.buffer).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                response.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                let mut res_buffer = VectorPacketBuffer::new();
+                response.write(&mut res_buffer, 0xFFFF).unwrap();
+                write_packet_length(&mut stream, res_buffer.pos).unwrap();
+                stream.write_all(&res_buffer.buffer[0..res_buffer.pos]).unwrap();
+                stream.flush().unwrap();
+            }
+        });
+
+        let mut client = DnsNetworkClient::new(31458);
+        client.set_tls_roots(load_roots(Path::new("testdata/localhost.crt")).unwrap());
+
+        for _ in 0..2 {
+            let res = client.send_tls_query("google.com",
+                                            QueryType::A,
+                                            ("127.0.0.1", port),
+                                            &TlsAuth::new("localhost"),
+                                            true).unwrap();
+
+            assert_eq!(res.questions[0].name, "google.com");
+            match res.answers[0] {
+                DnsRecord::A { ref domain, .. } => {
+                    assert_eq!("google.com", domain);
+                },
+                _ => panic!()
+            }
+        }
+
+        assert_eq!(2, client.get_sent_count());
+        assert_eq!(0, client.get_failed_count());
+    }
+
+    #[test]
+    pub fn test_https_client() {
+        use std::path::Path;
+
+        use dns::https::tests::start_doh_server;
+        use dns::tls::load_roots;
+
+        let (port, _, _) = start_doh_server();
+
+        let mut client = DnsNetworkClient::new(31459);
+        client.set_tls_roots(load_roots(Path::new("testdata/localhost.crt")).unwrap());
+
+        let (endpoint, _) = HttpsEndpoint::parse(&format!("https://localhost:{}/dns-query", port)).unwrap();
+        let res = client.send_https_query("Google.com",
+                                          QueryType::A,
+                                          ("127.0.0.1", port),
+                                          &endpoint,
+                                          true).unwrap();
+
+        assert_eq!(res.questions[0].name, "Google.com");
+        match res.answers[0] {
+            DnsRecord::A { ref domain, ref ttl, .. } => {
+                assert_eq!("Google.com", domain);
+
+                // The TTL is capped at what's left of the max-age
+                assert_eq!(240, ttl.0);
+            },
+            _ => panic!()
+        }
+    }
 }
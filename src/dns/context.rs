@@ -1,15 +1,20 @@
 //! The `ServerContext in this thread holds the common state across the server
 
-use std::io::Result;
+use std::io::{Result, ErrorKind};
 use std::sync::Arc;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread::{Builder,sleep};
+use std::time::Duration;
 
 use std::sync::Mutex;
-use dns::resolve::{DnsResolver,RecursiveDnsResolver,ForwardingDnsResolver};
+use dns::resolve::{DnsResolver,RecursiveDnsResolver,ForwardingDnsResolver,AuthorityDnsResolver,RoutingDnsResolver};
 use dns::client::{DnsClient,DnsNetworkClient};
 use dns::cache::SynchronizedCache;
 use dns::authority::Authority;
 use dns::filter::DnsFilter;
+use dns::inflight::InflightQueries;
+use dns::prefetch::RefreshQueue;
+use dns::upstream::{Upstream, UpstreamPool, UpstreamStrategy};
 use dns::utils::current_time_millis;
 
 pub struct ServerStatistics {
@@ -72,8 +77,29 @@ impl ServerStatistics {
 pub enum ResolveStrategy {
     Recursive,
     Forward {
-        host: String,
-        port: u16
+        upstreams: Arc<UpstreamPool>
+    },
+    /// Only answer from the local zones, refusing everything else
+    Authority
+}
+
+impl ResolveStrategy {
+    /// Parse the target of a route, which is either `recursive`, `authority`
+    /// or a comma separated list of servers to forward to
+    pub fn parse(target: &str, strategy: UpstreamStrategy) -> Option<ResolveStrategy> {
+        match target {
+            "recursive" => Some(ResolveStrategy::Recursive),
+            "authority" => Some(ResolveStrategy::Authority),
+            _ => {
+                let upstreams = target.split(',')
+                    .map(Upstream::parse)
+                    .collect::<Option<Vec<Upstream>>>()?;
+
+                Some(ResolveStrategy::Forward {
+                    upstreams: Arc::new(UpstreamPool::new(upstreams, strategy))
+                })
+            }
+        }
     }
 }
 
@@ -83,14 +109,62 @@ pub struct ServerContext {
     pub client: Box<DnsClient + Sync + Send>,
     pub dns_bind_ip: String,
     pub dns_port: u16,
+    pub api_bind_ip: String,
     pub api_port: u16,
     pub resolve_strategy: ResolveStrategy,
+    /// Strategies for the names under a suffix, overriding `resolve_strategy`
+    pub routes: Vec<(String, ResolveStrategy)>,
     pub allow_recursive: bool,
     pub threads_udp: usize,
     pub threads_tcp: usize,
     pub enable_api: bool,
+    /// Whether the API may flush and remove cache entries
+    pub api_allow_delete: bool,
     pub filter: DnsFilter,
-    pub statistics: Mutex<ServerStatistics>
+    pub statistics: Mutex<ServerStatistics>,
+    /// Answer for CHAOS TXT `version.bind`, refused when not set
+    pub chaos_version: Option<String>,
+    /// Answer for CHAOS TXT `hostname.bind`, refused when not set
+    pub chaos_hostname: Option<String>,
+    /// Answer for CHAOS TXT `id.server`, refused when not set
+    pub chaos_id: Option<String>,
+    /// Seconds past expiry during which cached records are served when
+    /// resolution fails (RFC 8767), or 0 to never serve stale data
+    pub stale_window: u32,
+    /// TTL of records served from stale data
+    pub stale_ttl: u32,
+    /// Queries being resolved, which identical queries wait for rather than
+    /// resolving them again
+    pub inflight: InflightQueries,
+    /// Names waiting to be resolved in the background, after serving stale
+    /// data or when popular entries are about to expire
+    pub refresh_queue: RefreshQueue,
+    pub threads_refresh: usize,
+    /// Hits required for a cached name to be refreshed before it expires
+    pub prefetch_min_hits: u32,
+    /// Percentage of the TTL at the end of which popular names are refreshed,
+    /// or 0 to never prefetch
+    pub prefetch_threshold: u32,
+    /// Maximum number of domains kept in the cache, or 0 for no limit
+    pub cache_max_entries: usize,
+    /// Seconds between purges of expired records from the cache
+    pub cache_sweep_interval: u64,
+    /// Lowest TTL of records stored in the cache
+    pub cache_min_ttl: u32,
+    /// Highest TTL of records stored in the cache, or 0 for no limit
+    pub cache_max_ttl: u32,
+    /// File the cache is saved to and loaded from across restarts
+    pub cache_snapshot_path: Option<PathBuf>,
+    /// Seconds between saving snapshots of the cache
+    pub cache_snapshot_interval: u64,
+    /// Port of the DNS over TLS listener
+    pub tls_port: u16,
+    /// PEM files holding the certificate chain and private key of the DNS
+    /// over TLS listener, which is only started when both are given
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// Seconds a DNS over TLS connection is kept open without a query
+    pub tls_idle_timeout: u64
 }
 
 impl Default for ServerContext {
@@ -107,14 +181,37 @@ impl ServerContext {
             client: Box::new(DnsNetworkClient::new(34555)),
             dns_bind_ip: "0.0.0.0".to_owned(),
             dns_port: 53,
+            api_bind_ip: "127.0.0.1".to_owned(),
             api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            routes: Vec::new(),
             allow_recursive: true,
             threads_udp: 32,
             threads_tcp: 32,
             enable_api: true,
+            api_allow_delete: false,
             filter: DnsFilter::new(),
-            statistics: Mutex::new(ServerStatistics::new())
+            statistics: Mutex::new(ServerStatistics::new()),
+            chaos_version: None,
+            chaos_hostname: None,
+            chaos_id: None,
+            stale_window: 86400,
+            stale_ttl: 30,
+            inflight: InflightQueries::new(),
+            refresh_queue: RefreshQueue::new(),
+            threads_refresh: 4,
+            prefetch_min_hits: 10,
+            prefetch_threshold: 10,
+            cache_max_entries: 100000,
+            cache_sweep_interval: 60,
+            cache_min_ttl: 0,
+            cache_max_ttl: 0,
+            cache_snapshot_path: None,
+            cache_snapshot_interval: 300,
+            tls_port: 853,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_idle_timeout: 10
         }
     }
 
@@ -128,17 +225,103 @@ impl ServerContext {
         // Load authority data
         self.authority.load()?;
 
+        self.cache.set_max_entries(self.cache_max_entries)?;
+        self.cache.set_ttl_limits(self.cache_min_ttl, self.cache_max_ttl)?;
+
+        // Load the cache from the last run. Not having a snapshot yet is fine.
+        if let Some(ref path) = self.cache_snapshot_path {
+            match self.cache.load_snapshot(path) {
+                Ok(count) => println!("Loaded {} records from cache snapshot {}", count, path.display()),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => println!("Failed to load cache snapshot {}: {:?}", path.display(), e)
+            }
+        }
+
         Ok(())
     }
 
-    pub fn create_resolver(&self, ptr: Arc<ServerContext>) -> Box<DnsResolver> {
-        match self.resolve_strategy {
-            ResolveStrategy::Recursive => Box::new(RecursiveDnsResolver::new(ptr)),
-            ResolveStrategy::Forward { ref host, port } => {
-                Box::new(ForwardingDnsResolver::new(ptr, (host.clone(), port)))
+    /// Save a snapshot of the cache, if a path for it has been configured
+    pub fn save_cache_snapshot(&self) {
+        if let Some(ref path) = self.cache_snapshot_path {
+            if let Err(e) = self.cache.save_snapshot(path) {
+                println!("Failed to save cache snapshot {}: {:?}", path.display(), e);
             }
         }
     }
+
+    /// Start a thread which periodically saves a snapshot of the cache
+    pub fn run_cache_snapshots(context: Arc<ServerContext>) -> Result<()> {
+        if context.cache_snapshot_path.is_none() {
+            return Ok(());
+        }
+
+        Builder::new().name("ServerContext-cache-snapshots".into()).spawn(move || {
+            loop {
+                sleep(Duration::from_secs(context.cache_snapshot_interval));
+                context.save_cache_snapshot();
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Start a thread which periodically purges expired records from the cache
+    ///
+    /// Records are kept for the stale window past their expiry, so that they
+    /// can still be served when resolution fails.
+    pub fn run_cache_sweeper(context: Arc<ServerContext>) -> Result<()> {
+        Builder::new().name("ServerContext-cache-sweeper".into()).spawn(move || {
+            loop {
+                sleep(Duration::from_secs(context.cache_sweep_interval));
+
+                match context.cache.purge_expired(context.stale_window) {
+                    Ok(0) => {},
+                    Ok(purged) => println!("Purged {} expired records from cache", purged),
+                    Err(e) => println!("Failed to purge cache: {:?}", e)
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Route the names under a suffix, such as `corp.example` or
+    /// `*.corp.example`, to a strategy of their own
+    pub fn add_route(&mut self, suffix: &str, strategy: ResolveStrategy) {
+        let suffix = suffix.trim_start_matches("*.").trim_matches('.').to_lowercase();
+        self.routes.retain(|x| x.0 != suffix);
+        self.routes.push((suffix, strategy));
+    }
+
+    /// Get the strategy for resolving a name, from the route with the longest
+    /// suffix matching it, or `resolve_strategy` when there's none
+    pub fn get_resolve_strategy(&self, qname: &str) -> &ResolveStrategy {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+
+        self.routes.iter()
+            .filter(|(suffix, _)| suffix.is_empty() || qname == *suffix || qname.ends_with(&format!(".{}", suffix)))
+            .max_by_key(|x| x.0.len())
+            .map(|x| &x.1)
+            .unwrap_or(&self.resolve_strategy)
+    }
+
+    pub fn create_resolver(&self, ptr: Arc<ServerContext>) -> Box<dyn DnsResolver> {
+        if !self.routes.is_empty() {
+            return Box::new(RoutingDnsResolver::new(ptr));
+        }
+
+        self.create_resolver_for(ptr, &self.resolve_strategy)
+    }
+
+    pub fn create_resolver_for(&self, ptr: Arc<ServerContext>, strategy: &ResolveStrategy) -> Box<dyn DnsResolver> {
+        match *strategy {
+            ResolveStrategy::Recursive => Box::new(RecursiveDnsResolver::new(ptr)),
+            ResolveStrategy::Forward { ref upstreams } => {
+                Box::new(ForwardingDnsResolver::new(ptr, upstreams.clone()))
+            },
+            ResolveStrategy::Authority => Box::new(AuthorityDnsResolver::new(ptr))
+        }
+    }
 }
 
 #[cfg(test)]
@@ -162,14 +345,37 @@ pub mod tests {
             client: Box::new(DnsStubClient::new(callback)),
             dns_bind_ip: "0.0.0.0".to_owned(),
             dns_port: 53,
+            api_bind_ip: "127.0.0.1".to_owned(),
             api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            routes: Vec::new(),
             allow_recursive: true,
             threads_udp: 32,
             threads_tcp: 32,
             enable_api: true,
+            api_allow_delete: false,
             filter: DnsFilter::new(),
             statistics: Mutex::new(ServerStatistics::new()),
+            chaos_version: None,
+            chaos_hostname: None,
+            chaos_id: None,
+            stale_window: 86400,
+            stale_ttl: 30,
+            inflight: InflightQueries::new(),
+            refresh_queue: RefreshQueue::new(),
+            threads_refresh: 4,
+            prefetch_min_hits: 10,
+            prefetch_threshold: 10,
+            cache_max_entries: 100000,
+            cache_sweep_interval: 60,
+            cache_min_ttl: 0,
+            cache_max_ttl: 0,
+            cache_snapshot_path: None,
+            cache_snapshot_interval: 300,
+            tls_port: 853,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_idle_timeout: 10
         })
 
     }
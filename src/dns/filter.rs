@@ -6,6 +6,7 @@ use std::collections::HashSet;
 use dns::protocol::DnsPacket;
 use dns::protocol::DnsRecord;
 use dns::protocol::TransientTtl;
+use dns::protocol::QueryClass;
 use dns::protocol::ResultCode;
 use dns::utils::current_time_millis;
 
@@ -46,7 +47,7 @@ impl DnsFilter {
     }
 
     pub fn contains(&self, domain: &str) -> bool {
-        return self.blocked.contains(domain);
+        self.blocked.contains(&domain.to_lowercase())
     }
 
     pub fn fill_blocked_response(request: &mut DnsPacket) {
@@ -67,6 +68,7 @@ impl DnsFilter {
 
         let record = DnsRecord::SOA {
             domain,
+            class: QueryClass::IN,
             m_name: SOA_FAKE_DOMAIN.to_owned(),
             r_name: SOA_FAKE_DOMAIN.to_owned(),
             serial: current_time_millis() as u32,
@@ -104,6 +106,6 @@ impl DnsFilter {
         }
 
         println!("Rule {} -> {}", rule, line);
-        self.blocked.insert(line);
+        self.blocked.insert(line.to_lowercase());
     }
 }
\ No newline at end of file
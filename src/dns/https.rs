@@ -0,0 +1,658 @@
+//! DNS over HTTPS (RFC 8484)
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{HeaderMap, Method, Request, StatusCode};
+use http::header::{ACCEPT, AGE, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use rustls::ClientConfig;
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::timeout_at;
+use tokio_rustls::TlsConnector;
+
+use dns::tls::{SpkiPin, TlsAuth, pinned_client_config};
+
+/// Timeout for connecting to a server, and for each query sent to it
+const HTTPS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest DNS message which can be received
+const MAX_MESSAGE_SIZE: usize = 0xFFFF;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How a query is sent in an HTTP request
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+pub enum HttpsMethod {
+    /// As the base64url encoded `dns` parameter of a GET request, which
+    /// makes it possible for HTTP caches along the way to answer it
+    Get,
+    /// As the body of a POST request
+    Post
+}
+
+/// A DNS over HTTPS endpoint, such as `https://dns.example/dns-query`
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct HttpsEndpoint {
+    /// The host of the URL, which the certificate is verified against and
+    /// which is sent as the authority of every request
+    pub auth: TlsAuth,
+    pub port: u16,
+    /// The path of the URL, along with any query string
+    pub path: String,
+    pub method: HttpsMethod
+}
+
+impl HttpsEndpoint {
+    /// Parse the URL of an endpoint, returning it along with the host
+    ///
+    /// The URI template of RFC 8484, `https://dns.example/dns-query{?dns}`,
+    /// is accepted as well. Queries are sent using POST, unless asked
+    /// otherwise with `method=get` after a `#`, which can also be followed by
+    /// `pin=DIGEST` for pinning the public key of the server, in the same way
+    /// as for DNS over TLS. Parameters are separated by `&`. An IPv6 address
+    /// as the host is enclosed in brackets, as in `https://[2001:db8::1]/`.
+    pub fn parse(url: &str) -> Option<(HttpsEndpoint, Vec<(String, String)>)> {
+        let url = url.strip_prefix("https://")?;
+
+        let mut parts = url.splitn(2, '#');
+        let url = parts.next()?;
+        let url = url.strip_suffix("{?dns}").unwrap_or(url);
+
+        let (authority, path) = match url.find('/') {
+            Some(idx) => (&url[..idx], &url[idx..]),
+            None => (url, "/dns-query")
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(idx) if !authority[idx..].contains(']') => {
+                (&authority[..idx], authority[idx+1..].parse::<u16>().ok()?)
+            },
+            _ => (authority, 443)
+        };
+        let host = match host.strip_prefix('[') {
+            Some(x) => x.strip_suffix(']')?,
+            None => host
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        let mut endpoint = HttpsEndpoint {
+            auth: TlsAuth::new(&host.to_lowercase()),
+            port,
+            path: path.to_string(),
+            method: HttpsMethod::Post
+        };
+
+        // Parameters other than the ones known here are left for the caller,
+        // in the order given
+        let mut params = Vec::new();
+        if let Some(fragment) = parts.next() {
+            for param in fragment.split('&') {
+                let (key, value) = param.split_once('=')?;
+
+                match key {
+                    "method" if value == "get" => endpoint.method = HttpsMethod::Get,
+                    "method" if value == "post" => endpoint.method = HttpsMethod::Post,
+                    "method" => return None,
+                    "pin" => endpoint.auth.pins.push(SpkiPin::parse(value)?),
+                    _ => params.push((key.to_string(), value.to_string()))
+                }
+            }
+        }
+
+        Some((endpoint, params))
+    }
+
+    fn authority(&self) -> String {
+        let host = if self.auth.server_name.contains(':') {
+            format!("[{}]", self.auth.server_name)
+        } else {
+            self.auth.server_name.clone()
+        };
+
+        if self.port == 443 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for HttpsEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "https://{}{}", self.authority(), self.path)
+    }
+}
+
+/// A DNS message received over HTTPS, along with how long it may be cached
+/// according to the HTTP headers of the response
+pub struct HttpsResponse {
+    pub message: Vec<u8>,
+    pub max_age: Option<u32>
+}
+
+/// The freshness lifetime of an HTTP response, which is the max-age of its
+/// Cache-Control header less the time it's spent in caches along the way
+fn freshness_lifetime(headers: &HeaderMap) -> Option<u32> {
+    let cache_control = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+    let max_age = cache_control.split(',')
+        .filter_map(|x| {
+            let directive = x.trim().to_lowercase();
+            directive.strip_prefix("max-age=").and_then(|x| x.trim_matches('"').parse::<u32>().ok())
+        })
+        .next()?;
+
+    let age = headers.get(AGE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+
+    Some(max_age.saturating_sub(age))
+}
+
+/// Check whether the Content-Type header of a response declares a DNS
+/// message, ignoring any parameters and the case of the media type
+fn is_dns_message(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.split(';').next())
+        .is_some_and(|x| x.trim().eq_ignore_ascii_case(DNS_MESSAGE))
+}
+
+/// HTTP/2 connections to servers speaking DNS over HTTPS
+///
+/// Every query is a stream of its own on a connection, so a single connection
+/// per server is kept open and shared by all queries sent to it. The HTTP/2
+/// machinery is asynchronous, and is driven by a small runtime which is only
+/// started once the first query is sent.
+pub struct HttpsConnections {
+    config: Arc<ClientConfig>,
+    runtime: OnceLock<Runtime>,
+    connections: Mutex<HashMap<(String, u16, TlsAuth), SendRequest<Bytes>>>
+}
+
+impl HttpsConnections {
+    pub fn new(config: Arc<ClientConfig>) -> HttpsConnections {
+        HttpsConnections {
+            config,
+            runtime: OnceLock::new(),
+            connections: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn runtime(&self) -> Result<&Runtime> {
+        if let Some(runtime) = self.runtime.get() {
+            return Ok(runtime);
+        }
+
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("HttpsConnections")
+            .enable_all()
+            .build()?;
+
+        Ok(self.runtime.get_or_init(|| runtime))
+    }
+
+    /// Run a future to completion on the runtime, giving up at `deadline`
+    fn block_on<F: Future>(&self, deadline: Instant, future: F) -> Result<F::Output> {
+        let runtime = self.runtime()?;
+        let _guard = runtime.enter();
+
+        runtime.block_on(timeout_at(deadline.into(), future))
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Request timed out"))
+    }
+
+    fn connect(&self, addr: SocketAddr, auth: &TlsAuth, deadline: Instant) -> Result<SendRequest<Bytes>> {
+        let config = if auth.pins.is_empty() {
+            self.config.clone()
+        } else {
+            pinned_client_config(&auth.pins)
+        };
+
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let name = ServerName::try_from(auth.server_name.clone())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid server name"))?;
+
+        let socket = self.block_on(deadline, TcpStream::connect(addr))??;
+        socket.set_nodelay(true)?;
+
+        let stream = self.block_on(deadline, connector.connect(name, socket))??;
+
+        let (client, connection) = self.block_on(deadline, h2::client::handshake(stream))?
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        // The connection is driven in the background until it's closed, after
+        // which the requests sent on it fail and a new one is opened
+        self.runtime()?.spawn(connection);
+
+        Ok(client)
+    }
+
+    /// Get a client for the open connection to a server, or open a new one,
+    /// returning whether it was reused
+    fn get_client(&self, addr: SocketAddr, auth: &TlsAuth, deadline: Instant) -> Result<(SendRequest<Bytes>, bool)> {
+        let key = (addr.ip().to_string(), addr.port(), auth.clone());
+
+        if let Ok(connections) = self.connections.lock() {
+            if let Some(client) = connections.get(&key) {
+                return Ok((client.clone(), true));
+            }
+        }
+
+        let client = self.connect(addr, auth, deadline)?;
+
+        match self.connections.lock() {
+            Ok(mut connections) => {
+                connections.insert(key, client.clone());
+            },
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        }
+
+        Ok((client, false))
+    }
+
+    fn remove_client(&self, addr: SocketAddr, auth: &TlsAuth) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&(addr.ip().to_string(), addr.port(), auth.clone()));
+        }
+    }
+
+    fn create_request(endpoint: &HttpsEndpoint, message: &[u8]) -> Result<Request<()>> {
+        let path = match endpoint.method {
+            HttpsMethod::Get => {
+                let separator = if endpoint.path.contains('?') { '&' } else { '?' };
+                let dns = base64::encode_config(message, base64::URL_SAFE_NO_PAD);
+                format!("{}{}dns={}", endpoint.path, separator, dns)
+            },
+            HttpsMethod::Post => endpoint.path.clone()
+        };
+
+        let mut request = Request::builder()
+            .uri(format!("https://{}{}", endpoint.authority(), path))
+            .header(ACCEPT, DNS_MESSAGE);
+
+        request = match endpoint.method {
+            HttpsMethod::Get => request.method(Method::GET),
+            HttpsMethod::Post => request.method(Method::POST)
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .header(CONTENT_LENGTH, message.len())
+        };
+
+        request.body(())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    fn query(&self, client: SendRequest<Bytes>, endpoint: &HttpsEndpoint, message: &[u8], deadline: Instant) -> Result<HttpsResponse> {
+        let h2_error = |e: h2::Error| Error::new(ErrorKind::ConnectionAborted, e.to_string());
+
+        let request = HttpsConnections::create_request(endpoint, message)?;
+        let has_body = endpoint.method == HttpsMethod::Post;
+
+        let mut client = self.block_on(deadline, client.ready())?.map_err(h2_error)?;
+        let (response, mut stream) = client.send_request(request, !has_body).map_err(h2_error)?;
+        if has_body {
+            stream.send_data(Bytes::copy_from_slice(message), true).map_err(h2_error)?;
+        }
+
+        let response = self.block_on(deadline, response)?
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Error::new(ErrorKind::Other, format!("Server answered with HTTP status {}", response.status())));
+        }
+
+        if !is_dns_message(response.headers()) {
+            return Err(Error::new(ErrorKind::InvalidData, "Server answered with unexpected content type"));
+        }
+
+        let max_age = freshness_lifetime(response.headers());
+
+        let mut body = response.into_body();
+        let mut message = Vec::new();
+        while let Some(chunk) = self.block_on(deadline, body.data())? {
+            let chunk = chunk.map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+
+            message.extend_from_slice(&chunk);
+            if message.len() > MAX_MESSAGE_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "Response too large"));
+            }
+        }
+
+        Ok(HttpsResponse {
+            message,
+            max_age
+        })
+    }
+
+    /// Send a DNS message to an endpoint on a server, and return the response
+    pub fn send(&self, server: (&str, u16), endpoint: &HttpsEndpoint, message: &[u8]) -> Result<HttpsResponse> {
+        let addr = match server.to_socket_addrs()?.next() {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid server address"))
+        };
+
+        let deadline = Instant::now() + HTTPS_TIMEOUT;
+
+        let (client, reused) = self.get_client(addr, &endpoint.auth, deadline)?;
+        match self.query(client, endpoint, message, deadline) {
+            // The server may have closed the connection since it was last
+            // used, in which case the query is retried on a new one
+            Err(ref err) if reused && err.kind() == ErrorKind::ConnectionAborted => {
+                self.remove_client(addr, &endpoint.auth);
+
+                let (client, _) = self.get_client(addr, &endpoint.auth, deadline)?;
+                self.query(client, endpoint, message, deadline)
+            },
+            Err(err) => {
+                if err.kind() != ErrorKind::Other && err.kind() != ErrorKind::InvalidData {
+                    self.remove_client(addr, &endpoint.auth);
+                }
+
+                Err(err)
+            },
+            result => result
+        }
+    }
+
+    /// Check whether a connection to a server is open
+    pub fn is_connected(&self, server: (&str, u16), auth: &TlsAuth) -> bool {
+        match self.connections.lock() {
+            Ok(connections) => connections.contains_key(&(server.0.to_string(), server.1, auth.clone())),
+            Err(_) => false
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use std::future::poll_fn;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Poll;
+    use std::thread;
+
+    use http::Response;
+    use tokio_rustls::TlsAcceptor;
+
+    use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+    use dns::protocol::{DnsPacket, DnsRecord, QueryClass, TransientTtl};
+    use dns::tls::tests::{test_client_config, test_server_config};
+
+    use super::*;
+
+    /// Answer a query with an A record for the name asked for
+    fn answer(request: &[u8]) -> Vec<u8> {
+        let mut req_buffer = VectorPacketBuffer::new();
+        req_buffer.buffer = request.to_vec();
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+        let mut response = DnsPacket::new();
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.questions = request.questions.clone();
+        response.answers.push(DnsRecord::A {
+            domain: request.questions[0].name.clone(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        response.write(&mut res_buffer, 0xFFFF).unwrap();
+        res_buffer.buffer[0..res_buffer.pos()].to_vec()
+    }
+
+    /// Take the query out of a request, returning it along with the method
+    /// used for sending it
+    fn parse_request(request: &http::request::Parts, body: &[u8]) -> Option<(Method, Vec<u8>)> {
+        if request.method == Method::POST {
+            if request.headers.get(CONTENT_TYPE)?.to_str().ok()? != DNS_MESSAGE {
+                return None;
+            }
+
+            return Some((Method::POST, body.to_vec()));
+        }
+
+        let query = request.uri.query()?;
+        let dns = query.split('&').filter_map(|x| x.strip_prefix("dns=")).next()?;
+
+        Some((Method::GET, base64::decode_config(dns, base64::URL_SAFE_NO_PAD).ok()?))
+    }
+
+    /// Serve a connection until it's closed, reading the bodies of all open
+    /// streams as they come in
+    fn serve<T>(runtime: &Runtime, mut conn: h2::server::Connection<T, Bytes>, received: &Mutex<Vec<Method>>)
+        where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin {
+
+        let mut streams = Vec::new();
+        runtime.block_on(poll_fn(move |cx| {
+            loop {
+                match conn.poll_accept(cx) {
+                    Poll::Ready(Some(Ok((request, respond)))) => {
+                        let (parts, body) = request.into_parts();
+                        streams.push((parts, body, respond, Vec::new()));
+                    },
+                    Poll::Ready(_) => return Poll::Ready(()),
+                    Poll::Pending => break
+                }
+            }
+
+            streams.retain_mut(|&mut (ref parts, ref mut body, ref mut respond, ref mut data)| {
+                loop {
+                    match body.poll_data(cx) {
+                        Poll::Ready(Some(Ok(chunk))) => {
+                            let _ = body.flow_control().release_capacity(chunk.len());
+                            data.extend_from_slice(&chunk);
+                        },
+                        Poll::Ready(Some(Err(_))) => return false,
+                        Poll::Ready(None) => break,
+                        Poll::Pending => return true
+                    }
+                }
+
+                let (status, message) = match parse_request(parts, data) {
+                    Some((method, query)) if parts.uri.path() == "/dns-query" => {
+                        received.lock().unwrap().push(method);
+                        (StatusCode::OK, answer(&query))
+                    },
+                    _ => (StatusCode::BAD_REQUEST, Vec::new())
+                };
+
+                let response = Response::builder()
+                    .status(status)
+                    .header(CONTENT_TYPE, DNS_MESSAGE)
+                    .header(CACHE_CONTROL, "public, max-age=300")
+                    .header(AGE, "60")
+                    .body(())
+                    .unwrap();
+
+                if let Ok(mut send) = respond.send_response(response, false) {
+                    let _ = send.send_data(Bytes::from(message), true);
+                }
+
+                false
+            });
+
+            Poll::Pending
+        }));
+    }
+
+    /// Start a server answering every query on `/dns-query` with an A record
+    /// with a TTL of an hour, and a max-age of five minutes of which one has
+    /// passed, returning its port, a count of the connections accepted and
+    /// the methods of the requests received
+    pub fn start_doh_server() -> (u16, Arc<AtomicUsize>, Arc<Mutex<Vec<Method>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let methods = Arc::new(Mutex::new(Vec::new()));
+
+        let mut config = (*test_server_config()).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let accepted = connections.clone();
+        let received = methods.clone();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+
+                let acceptor = acceptor.clone();
+                let received = received.clone();
+                thread::spawn(move || {
+                    let socket = socket.unwrap();
+                    socket.set_nonblocking(true).unwrap();
+
+                    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+                    let _guard = runtime.enter();
+
+                    let socket = tokio::net::TcpStream::from_std(socket).unwrap();
+                    let stream = match runtime.block_on(acceptor.accept(socket)) {
+                        Ok(x) => x,
+                        Err(_) => return
+                    };
+                    let conn = runtime.block_on(h2::server::handshake(stream));
+
+                    serve(&runtime, conn.unwrap(), &received);
+                });
+            }
+        });
+
+        (port, connections, methods)
+    }
+
+    #[test]
+    fn test_https_endpoint_parse() {
+        let (endpoint, params) = HttpsEndpoint::parse("https://dns.example/dns-query").unwrap();
+        assert_eq!("dns.example", endpoint.auth.server_name);
+        assert_eq!(443, endpoint.port);
+        assert_eq!("/dns-query", endpoint.path);
+        assert_eq!(HttpsMethod::Post, endpoint.method);
+        assert!(params.is_empty());
+        assert_eq!("https://dns.example/dns-query", endpoint.to_string());
+
+        let (endpoint, params) = HttpsEndpoint::parse("https://DNS.example:8443/resolve?x=1{?dns}#method=get&addr=10.0.0.1").unwrap();
+        assert_eq!("dns.example", endpoint.auth.server_name);
+        assert_eq!(8443, endpoint.port);
+        assert_eq!("/resolve?x=1", endpoint.path);
+        assert_eq!(HttpsMethod::Get, endpoint.method);
+        assert_eq!(vec![("addr".to_string(), "10.0.0.1".to_string())], params);
+        assert_eq!("https://dns.example:8443/resolve?x=1", endpoint.to_string());
+
+        let (endpoint, _) = HttpsEndpoint::parse("https://1.1.1.1").unwrap();
+        assert_eq!("/dns-query", endpoint.path);
+
+        let (endpoint, _) = HttpsEndpoint::parse("https://[2001:DB8::1]:8443/dns-query").unwrap();
+        assert_eq!("2001:db8::1", endpoint.auth.server_name);
+        assert_eq!(8443, endpoint.port);
+        assert_eq!("https://[2001:db8::1]:8443/dns-query", endpoint.to_string());
+
+        let (endpoint, _) = HttpsEndpoint::parse("https://[2001:db8::1]/dns-query").unwrap();
+        assert_eq!("2001:db8::1", endpoint.auth.server_name);
+        assert_eq!(443, endpoint.port);
+
+        assert!(HttpsEndpoint::parse("http://dns.example/dns-query").is_none());
+        assert!(HttpsEndpoint::parse("https://dns.example:https/dns-query").is_none());
+        assert!(HttpsEndpoint::parse("https://[2001:db8::1/dns-query").is_none());
+        assert!(HttpsEndpoint::parse("https://dns.example/dns-query#method=put").is_none());
+        assert!(HttpsEndpoint::parse("https://dns.example/dns-query#pin=hello").is_none());
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, freshness_lifetime(&headers));
+
+        headers.insert(CACHE_CONTROL, "public, Max-Age=300".parse().unwrap());
+        assert_eq!(Some(300), freshness_lifetime(&headers));
+
+        headers.insert(AGE, "60".parse().unwrap());
+        assert_eq!(Some(240), freshness_lifetime(&headers));
+
+        headers.insert(AGE, "600".parse().unwrap());
+        assert_eq!(Some(0), freshness_lifetime(&headers));
+
+        headers.insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+        assert_eq!(None, freshness_lifetime(&headers));
+    }
+
+    #[test]
+    fn test_is_dns_message() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_dns_message(&headers));
+
+        headers.insert(CONTENT_TYPE, "application/dns-message".parse().unwrap());
+        assert!(is_dns_message(&headers));
+
+        headers.insert(CONTENT_TYPE, "Application/DNS-Message; charset=binary".parse().unwrap());
+        assert!(is_dns_message(&headers));
+
+        headers.insert(CONTENT_TYPE, "application/dns-json".parse().unwrap());
+        assert!(!is_dns_message(&headers));
+    }
+
+    #[test]
+    fn test_https_connections() {
+        let (port, connections, methods) = start_doh_server();
+        let https = Arc::new(HttpsConnections::new(test_client_config()));
+
+        let mut query = DnsPacket::new();
+        query.questions.push(::dns::protocol::DnsQuestion::new("google.com".to_string(), ::dns::protocol::QueryType::A));
+        let mut req_buffer = VectorPacketBuffer::new();
+        query.write(&mut req_buffer, 0xFFFF).unwrap();
+        let request = req_buffer.buffer[0..req_buffer.pos()].to_vec();
+
+        let (mut endpoint, _) = HttpsEndpoint::parse(&format!("https://localhost:{}/dns-query", port)).unwrap();
+
+        let response = https.send(("127.0.0.1", port), &endpoint, &request).unwrap();
+        assert_eq!(answer(&request), response.message);
+        assert_eq!(Some(240), response.max_age);
+
+        endpoint.method = HttpsMethod::Get;
+        let response = https.send(("127.0.0.1", port), &endpoint, &request).unwrap();
+        assert_eq!(answer(&request), response.message);
+
+        // Queries sent at the same time share the connection
+        let threads = (0..4).map(|_| {
+            let https = https.clone();
+            let endpoint = endpoint.clone();
+            let request = request.clone();
+            thread::spawn(move || https.send(("127.0.0.1", port), &endpoint, &request).unwrap())
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            assert_eq!(answer(&request), thread.join().unwrap().message);
+        }
+
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+        assert!(https.is_connected(("127.0.0.1", port), &endpoint.auth));
+        assert_eq!(vec![Method::POST, Method::GET], methods.lock().unwrap()[0..2].to_vec());
+
+        // Errors are reported as such
+        let (endpoint, _) = HttpsEndpoint::parse(&format!("https://localhost:{}/resolve", port)).unwrap();
+        assert!(https.send(("127.0.0.1", port), &endpoint, &request).is_err());
+
+        // And the certificate has to be valid for the host of the URL
+        let (endpoint, _) = HttpsEndpoint::parse(&format!("https://dns.google:{}/dns-query", port)).unwrap();
+        assert!(https.send(("127.0.0.1", port), &endpoint, &request).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! coalescing of identical queries that are resolved at the same time
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use dns::protocol::{DnsPacket, QueryType};
+
+/// How long to wait for another thread to resolve a query, before resolving
+/// it separately
+///
+/// This is a safety net rather than a timeout on the resolution itself. The
+/// recursive resolver resolves the names of name servers along the way, and
+/// two threads could end up waiting for each other. A thread which runs into
+/// a query it's resolving itself doesn't wait at all, see
+/// `InflightQueries::resolve`.
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+type QueryKey = (String, QueryType);
+
+/// The result of a resolution, kept in a form that can be handed to every
+/// waiting thread
+type SharedResult = ::std::result::Result<DnsPacket, (ErrorKind, String)>;
+
+struct InflightQuery {
+    /// The thread resolving the query
+    leader: ThreadId,
+    result: Mutex<Option<SharedResult>>,
+    cond: Condvar
+}
+
+impl InflightQuery {
+    fn new() -> InflightQuery {
+        InflightQuery {
+            leader: thread::current().id(),
+            result: Mutex::new(None),
+            cond: Condvar::new()
+        }
+    }
+}
+
+/// The queries currently being resolved
+///
+/// The first thread to ask for a name and type resolves it, while the threads
+/// asking for the same while it's in progress wait for its result. This keeps
+/// a burst of queries for an uncached name from turning into a burst of
+/// upstream queries, and leaves less room for racing a forged response
+/// against them. Only queries of class IN reach the resolver, which
+/// `handle_query` asserts, so the class isn't part of the key.
+#[derive(Default)]
+pub struct InflightQueries {
+    queries: Mutex<HashMap<QueryKey, Arc<InflightQuery>>>
+}
+
+/// Completes a query when the thread resolving it is done, even if it
+/// panicked, so that the waiting threads are never left hanging
+struct CompletionGuard<'a> {
+    queries: &'a InflightQueries,
+    key: QueryKey,
+    query: Arc<InflightQuery>
+}
+
+impl<'a> CompletionGuard<'a> {
+    fn complete(&self, result: SharedResult) {
+        if let Ok(mut x) = self.query.result.lock() {
+            if x.is_none() {
+                *x = Some(result);
+            }
+        }
+    }
+}
+
+impl<'a> Drop for CompletionGuard<'a> {
+    fn drop(&mut self) {
+        self.complete(Err((ErrorKind::Other, "Resolution failed".to_string())));
+        self.query.cond.notify_all();
+
+        if let Ok(mut queries) = self.queries.queries.lock() {
+            queries.remove(&self.key);
+        }
+    }
+}
+
+impl InflightQueries {
+    pub fn new() -> InflightQueries {
+        InflightQueries {
+            queries: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Resolve a query using `perform`, unless the same query is already
+    /// being resolved, in which case its result is used instead
+    ///
+    /// When the thread resolving a query asks for it again, such as when the
+    /// recursive resolver looks up the name of a name server which lies in
+    /// the zone it serves, `perform` is run right away, since waiting would
+    /// mean waiting for itself.
+    pub fn resolve<F>(&self, qname: &str, qtype: QueryType, perform: F) -> Result<DnsPacket>
+        where F: FnOnce() -> Result<DnsPacket> {
+
+        let key = (qname.to_lowercase(), qtype);
+
+        let (query, is_leader) = {
+            let mut queries = match self.queries.lock() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            match queries.get(&key) {
+                Some(query) => (query.clone(), false),
+                None => {
+                    let query = Arc::new(InflightQuery::new());
+                    queries.insert(key.clone(), query.clone());
+                    (query, true)
+                }
+            }
+        };
+
+        if !is_leader {
+            if query.leader == thread::current().id() {
+                return perform();
+            }
+
+            if let Some(result) = wait_for(&query) {
+                return result.map_err(|(kind, msg)| Error::new(kind, msg));
+            }
+
+            return perform();
+        }
+
+        let guard = CompletionGuard {
+            queries: self,
+            key,
+            query
+        };
+
+        let result = perform();
+        guard.complete(match result {
+            Ok(ref packet) => Ok(packet.clone()),
+            Err(ref err) => Err((err.kind(), err.to_string()))
+        });
+
+        result
+    }
+
+    /// Check whether a query is being resolved
+    pub fn is_inflight(&self, qname: &str, qtype: QueryType) -> bool {
+        match self.queries.lock() {
+            Ok(queries) => queries.contains_key(&(qname.to_lowercase(), qtype)),
+            Err(_) => false
+        }
+    }
+}
+
+/// Wait for the result of a query resolved by another thread, giving up
+/// after `MAX_WAIT`
+fn wait_for(query: &InflightQuery) -> Option<SharedResult> {
+    let deadline = Instant::now() + MAX_WAIT;
+
+    let mut result = query.result.lock().ok()?;
+    loop {
+        if let Some(ref x) = *result {
+            return Some(x.clone());
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+
+        result = query.cond.wait_timeout(result, deadline - now).ok()?.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use dns::protocol::{DnsPacket, DnsRecord, QueryClass, QueryType, TransientTtl};
+
+    use super::*;
+
+    #[test]
+    fn test_inflight_queries() {
+        let inflight = Arc::new(InflightQueries::new());
+        let performed = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..10).map(|_| {
+            let inflight = inflight.clone();
+            let performed = performed.clone();
+
+            thread::spawn(move || {
+                inflight.resolve("Google.com", QueryType::A, || {
+                    performed.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(200));
+
+                    let mut packet = DnsPacket::new();
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        class: QueryClass::IN,
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+
+                    Ok(packet)
+                })
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            let packet = thread.join().unwrap().unwrap();
+            assert_eq!(1, packet.answers.len());
+        }
+
+        // Every thread got the answer of a single resolution
+        assert_eq!(1, performed.load(Ordering::SeqCst));
+        assert!(!inflight.is_inflight("google.com", QueryType::A));
+    }
+
+    #[test]
+    fn test_inflight_queries_error() {
+        let inflight = Arc::new(InflightQueries::new());
+
+        let leader = {
+            let inflight = inflight.clone();
+            thread::spawn(move || {
+                inflight.resolve("google.com", QueryType::A, || {
+                    thread::sleep(Duration::from_millis(200));
+                    Err(Error::new(ErrorKind::NotFound, "No DNS server found"))
+                })
+            })
+        };
+
+        while !inflight.is_inflight("google.com", QueryType::A) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Errors are passed on to the waiting threads as well
+        let result = inflight.resolve("google.com", QueryType::A, || panic!());
+        assert_eq!(ErrorKind::NotFound, result.unwrap_err().kind());
+        assert_eq!(ErrorKind::NotFound, leader.join().unwrap().unwrap_err().kind());
+
+        // And once done, the query is resolved again
+        let result = inflight.resolve("google.com", QueryType::A, || Ok(DnsPacket::new()));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_inflight_queries_reentrant() {
+        let inflight = InflightQueries::new();
+        let start = Instant::now();
+
+        // The thread resolving a query doesn't wait for itself when it asks
+        // for the same query again
+        let result = inflight.resolve("google.com", QueryType::A, || {
+            inflight.resolve("Google.com", QueryType::A, || Ok(DnsPacket::new()))
+        });
+
+        assert!(result.is_ok());
+        assert!(start.elapsed() < MAX_WAIT);
+        assert!(!inflight.is_inflight("google.com", QueryType::A));
+    }
+}
@@ -4,11 +4,17 @@ pub mod authority;
 pub mod buffer;
 pub mod cache;
 pub mod client;
+pub mod inflight;
+pub mod prefetch;
 pub mod protocol;
 pub mod resolve;
 pub mod server;
+pub mod tls;
+pub mod https;
+pub mod upstream;
 pub mod context;
 pub mod filter;
+pub mod view;
 mod utils;
 
 mod netutil;
@@ -0,0 +1,194 @@
+//! background resolution of names, for keeping the cache up to date
+
+use std::collections::{HashSet, VecDeque};
+use std::io::Result;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::Builder;
+
+use dns::context::ServerContext;
+use dns::protocol::QueryType;
+
+#[derive(Default)]
+struct RefreshState {
+    queue: VecDeque<(String, QueryType)>,
+    pending: HashSet<(String, QueryType)>
+}
+
+/// A queue of names to resolve in the background
+///
+/// Names are refreshed by a small pool of worker threads, which resolve them
+/// using the resolver of the context, bypassing the cache. A name is only
+/// queued once, until its refresh has completed, so that a burst of queries
+/// for a popular name doesn't cause a burst of refreshes.
+#[derive(Default)]
+pub struct RefreshQueue {
+    state: Mutex<RefreshState>,
+    cond: Condvar
+}
+
+impl RefreshQueue {
+    pub fn new() -> RefreshQueue {
+        RefreshQueue {
+            state: Mutex::new(RefreshState::default()),
+            cond: Condvar::new()
+        }
+    }
+
+    /// Queue a name for refreshing, returning false if a refresh of the same
+    /// name and type is already pending
+    pub fn enqueue(&self, qname: &str, qtype: QueryType) -> bool {
+        let key = (qname.to_lowercase(), qtype);
+
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => return false
+        };
+
+        if !state.pending.insert(key.clone()) {
+            return false;
+        }
+
+        state.queue.push_back(key);
+        self.cond.notify_one();
+
+        true
+    }
+
+    /// Check whether a refresh of a name is queued or in progress
+    pub fn is_pending(&self, qname: &str, qtype: QueryType) -> bool {
+        match self.state.lock() {
+            Ok(state) => state.pending.contains(&(qname.to_lowercase(), qtype)),
+            Err(_) => false
+        }
+    }
+
+    /// Start the worker threads of the refresh queue held by the context
+    pub fn run(context: Arc<ServerContext>, thread_count: usize) -> Result<()> {
+        for thread_id in 0..thread_count {
+            let context = context.clone();
+
+            let name = "RefreshQueue-worker-".to_string() + &thread_id.to_string();
+            Builder::new().name(name).spawn(move || {
+                let queue = &context.refresh_queue;
+
+                loop {
+                    let (qname, qtype) = {
+                        let mut state = match queue.state.lock() {
+                            Ok(x) => x,
+                            Err(_) => return
+                        };
+
+                        loop {
+                            if let Some(key) = state.queue.pop_front() {
+                                break key;
+                            }
+
+                            state = match queue.cond.wait(state) {
+                                Ok(x) => x,
+                                Err(_) => return
+                            };
+                        }
+                    };
+
+                    // Go straight to the resolution, since a regular lookup
+                    // would be answered from the cache
+                    let mut resolver = context.create_resolver(context.clone());
+                    if let Err(err) = resolver.perform(&qname, qtype) {
+                        println!("Failed to refresh {:?} {}: {:?}", qtype, qname, err);
+                    }
+
+                    if let Ok(mut state) = queue.state.lock() {
+                        state.pending.remove(&(qname, qtype));
+                    }
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Queue a refresh of a cached name, if it's popular and about to expire
+///
+/// Names with at least `prefetch_min_hits` hits are refreshed when queried
+/// within the last `prefetch_threshold` percent of their TTL, so that clients
+/// asking for them never have to wait for an expired entry to be resolved.
+pub fn prefetch_if_needed(context: &ServerContext, qname: &str, qtype: QueryType) {
+    if context.prefetch_threshold == 0 {
+        return;
+    }
+
+    if context.cache.should_prefetch(qname, qtype, context.prefetch_min_hits, context.prefetch_threshold) &&
+        context.refresh_queue.enqueue(qname, qtype) {
+        println!("Prefetching {:?} {}", qtype, qname);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use dns::protocol::{DnsPacket, DnsRecord, QueryClass, QueryType, TransientTtl};
+
+    use super::*;
+
+    use dns::context::ResolveStrategy;
+    use dns::upstream::UpstreamPool;
+    use dns::context::tests::create_test_context;
+
+    #[test]
+    fn test_refresh_queue() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let queries_clone = queries.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                queries_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        upstreams: UpstreamPool::single("127.0.0.1", 53)
+                    };
+            },
+            None => panic!()
+        }
+
+        // Refreshes of the same name are only queued once
+        assert!(context.refresh_queue.enqueue("google.com", QueryType::A));
+        assert!(!context.refresh_queue.enqueue("GOOGLE.com", QueryType::A));
+        assert!(context.refresh_queue.enqueue("google.com", QueryType::AAAA));
+        assert!(context.refresh_queue.is_pending("google.com", QueryType::A));
+
+        RefreshQueue::run(context.clone(), 2).unwrap();
+
+        for _ in 0..100 {
+            if !context.refresh_queue.is_pending("google.com", QueryType::A) &&
+                !context.refresh_queue.is_pending("google.com", QueryType::AAAA) {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(2, queries.load(Ordering::SeqCst));
+        assert!(context.cache.lookup("google.com", QueryType::A).is_some());
+
+        // Once done, the name can be queued again
+        assert!(context.refresh_queue.enqueue("google.com", QueryType::A));
+    }
+}
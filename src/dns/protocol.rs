@@ -1,15 +1,16 @@
 //! implements the DNS protocol in a transport agnostic fashion
 
-//use std::io::{Error, ErrorKind};
+use std::cmp;
 use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash,Hasher};
-use std::io::Result;
+use std::io::{Result, Error, ErrorKind};
 use std::net::{Ipv4Addr,Ipv6Addr};
+use std::str::FromStr;
 
 use rand::random;
 
-use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::buffer::{PacketBuffer, PacketWriter, VectorPacketBuffer, WireError};
 
 /// `QueryType` represents the requested Record Type of a query
 ///
@@ -63,6 +64,45 @@ impl QueryType {
     }
 }
 
+/// `QueryClass` represents the class of a question or a record
+///
+/// Nearly everything is IN, but CH is still used for server identification
+/// queries such as `version.bind`. Unknown classes are retained as integers,
+/// in the same way as for `QueryType`.
+#[derive(PartialEq,Eq,Debug,Clone,Hash,Copy,PartialOrd,Ord)]
+pub enum QueryClass {
+    UNKNOWN(u16),
+    IN, // 1
+    CH, // 3
+    HS, // 4
+    NONE, // 254
+    ANY // 255
+}
+
+impl QueryClass {
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            QueryClass::UNKNOWN(x) => x,
+            QueryClass::IN => 1,
+            QueryClass::CH => 3,
+            QueryClass::HS => 4,
+            QueryClass::NONE => 254,
+            QueryClass::ANY => 255
+        }
+    }
+
+    pub fn from_num(num: u16) -> QueryClass {
+        match num {
+            1 => QueryClass::IN,
+            3 => QueryClass::CH,
+            4 => QueryClass::HS,
+            254 => QueryClass::NONE,
+            255 => QueryClass::ANY,
+            _ => QueryClass::UNKNOWN(num)
+        }
+    }
+}
+
 #[derive(Copy,Clone,Debug,Eq,Ord)]
 pub struct TransientTtl(pub u32);
 
@@ -93,27 +133,32 @@ impl Hash for TransientTtl {
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
+        class: QueryClass,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: TransientTtl
     }, // 0
     A {
         domain: String,
+        class: QueryClass,
         addr: Ipv4Addr,
         ttl: TransientTtl
     }, // 1
     NS {
         domain: String,
+        class: QueryClass,
         host: String,
         ttl: TransientTtl
     }, // 2
     CNAME {
         domain: String,
+        class: QueryClass,
         host: String,
         ttl: TransientTtl
     }, // 5
     SOA {
         domain: String,
+        class: QueryClass,
         m_name: String,
         r_name: String,
         serial: u32,
@@ -125,22 +170,26 @@ pub enum DnsRecord {
     }, // 6
     MX {
         domain: String,
+        class: QueryClass,
         priority: u16,
         host: String,
         ttl: TransientTtl
     }, // 15
     TXT {
         domain: String,
-        data: String,
+        class: QueryClass,
+        data: Vec<Vec<u8>>,
         ttl: TransientTtl
     }, // 16
     AAAA {
         domain: String,
+        class: QueryClass,
         addr: Ipv6Addr,
         ttl: TransientTtl
     }, // 28
     SRV {
         domain: String,
+        class: QueryClass,
         priority: u16,
         weight: u16,
         port: u16,
@@ -150,7 +199,7 @@ pub enum DnsRecord {
     OPT {
         packet_len: u16,
         flags: u32,
-        data: String
+        data: Vec<u8>
     } // 41
 }
 
@@ -162,11 +211,33 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let class = buffer.read_u16()?;
+        let class_num = buffer.read_u16()?;
+        let class = QueryClass::from_num(class_num);
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
-        match qtype {
+        // Records without RDATA are used by dynamic updates (RFC 2136), with
+        // the class ANY or NONE, to express deletions and prerequisites. They
+        // can't be decoded as their actual type, and are kept as opaque
+        // records instead. Any other record of a known type needs its RDATA.
+        if data_len == 0 && qtype != QueryType::OPT {
+            if let QueryClass::ANY | QueryClass::NONE = class {
+                return Ok(DnsRecord::UNKNOWN {
+                    domain,
+                    class,
+                    qtype: qtype_num,
+                    data: Vec::new(),
+                    ttl: TransientTtl(ttl)
+                });
+            }
+
+            if let QueryType::UNKNOWN(_) = qtype {} else {
+                return Err(WireError::EmptyRdata(qtype_num).into());
+            }
+        }
+
+        let rdata_start = buffer.pos();
+        let record: Result<DnsRecord> = match qtype {
             QueryType::A  => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(((raw_addr >> 24) & 0xFF) as u8,
@@ -176,6 +247,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::A {
                     domain: domain,
+                    class,
                     addr: addr,
                     ttl: TransientTtl(ttl)
                 })
@@ -196,6 +268,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::AAAA {
                     domain: domain,
+                    class,
                     addr: addr,
                     ttl: TransientTtl(ttl)
                 })
@@ -206,6 +279,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::NS {
                     domain: domain,
+                    class,
                     host: ns,
                     ttl: TransientTtl(ttl)
                 })
@@ -216,6 +290,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::CNAME {
                     domain: domain,
+                    class,
                     host: cname,
                     ttl: TransientTtl(ttl)
                 })
@@ -230,6 +305,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::SRV {
                     domain: domain,
+                    class,
                     priority: priority,
                     weight: weight,
                     port: port,
@@ -244,6 +320,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::MX {
                     domain: domain,
+                    class,
                     priority: priority,
                     host: mx,
                     ttl: TransientTtl(ttl)
@@ -264,6 +341,7 @@ impl DnsRecord {
 
                 Ok(DnsRecord::SOA {
                     domain: domain,
+                    class,
                     m_name: m_name,
                     r_name: r_name,
                     serial: serial,
@@ -275,53 +353,83 @@ impl DnsRecord {
                 })
             },
             QueryType::TXT => {
-                let mut txt = String::new();
-
-                let cur_pos = buffer.pos();
-                txt.push_str(&String::from_utf8_lossy(buffer.get_range(cur_pos, data_len as usize)?));
+                // The RDATA is a sequence of character-strings, each of them
+                // prefixed by a single length byte.
+                let mut data = Vec::new();
+
+                let end_pos = buffer.pos() + data_len as usize;
+                while buffer.pos() < end_pos {
+                    let len = buffer.read()? as usize;
+                    let cur_pos = buffer.pos();
+                    if cur_pos + len > end_pos {
+                        return Err(WireError::RdataLength {
+                            qtype: qtype_num,
+                            expected: data_len as usize,
+                            consumed: cur_pos + len - rdata_start
+                        }.into());
+                    }
 
-                buffer.step(data_len as usize)?;
+                    data.push(buffer.get_range(cur_pos, len)?.to_vec());
+                    buffer.step(len)?;
+                }
 
                 Ok(DnsRecord::TXT {
-                    domain: domain,
-                    data: txt,
+                    domain,
+                    class,
+                    data,
                     ttl: TransientTtl(ttl)
                 })
             },
             QueryType::OPT => {
-                let mut data = String::new();
-
                 let cur_pos = buffer.pos();
-                data.push_str(&String::from_utf8_lossy(buffer.get_range(cur_pos, data_len as usize)?));
+                let data = buffer.get_range(cur_pos, data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::OPT {
-                    packet_len: class,
+                    packet_len: class_num,
                     flags: ttl,
-                    data: data
+                    data
                 })
             },
             QueryType::UNKNOWN(_) => {
+                // Types we don't know are kept as opaque RDATA (RFC 3597), so
+                // that they can be cached and passed on unchanged.
+                let cur_pos = buffer.pos();
+                let data = buffer.get_range(cur_pos, data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
-                    domain: domain,
+                    domain,
+                    class,
                     qtype: qtype_num,
-                    data_len: data_len,
+                    data,
                     ttl: TransientTtl(ttl)
                 })
             }
+        };
+        let record = record?;
+
+        // Whatever the type, the RDATA must be consumed exactly
+        let consumed = buffer.pos() - rdata_start;
+        if consumed != data_len as usize {
+            return Err(WireError::RdataLength {
+                qtype: qtype_num,
+                expected: data_len as usize,
+                consumed
+            }.into());
         }
+
+        Ok(record)
     }
 
     pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
         let start_pos = buffer.pos();
 
         match *self {
-            DnsRecord::A { ref domain, ref addr, ttl: TransientTtl(ttl) } => {
+            DnsRecord::A { ref domain, class, ref addr, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -331,10 +439,10 @@ impl DnsRecord {
                 buffer.write_u8(octets[2])?;
                 buffer.write_u8(octets[3])?;
             },
-            DnsRecord::AAAA { ref domain, ref addr, ttl: TransientTtl(ttl) } => {
+            DnsRecord::AAAA { ref domain, class, ref addr, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(16)?;
 
@@ -342,10 +450,10 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             },
-            DnsRecord::NS { ref domain, ref host, ttl: TransientTtl(ttl) } => {
+            DnsRecord::NS { ref domain, class, ref host, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -356,10 +464,10 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             },
-            DnsRecord::CNAME { ref domain, ref host, ttl: TransientTtl(ttl) } => {
+            DnsRecord::CNAME { ref domain, class, ref host, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -370,10 +478,10 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             },
-            DnsRecord::SRV { ref domain, priority, weight, port, ref host, ttl: TransientTtl(ttl) } => {
+            DnsRecord::SRV { ref domain, class, priority, weight, port, ref host, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::SRV.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -387,10 +495,10 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             },
-            DnsRecord::MX { ref domain, priority, ref host, ttl: TransientTtl(ttl) } => {
+            DnsRecord::MX { ref domain, class, priority, ref host, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -404,6 +512,7 @@ impl DnsRecord {
             },
             DnsRecord::SOA {
                 ref domain,
+                class,
                 ref m_name,
                 ref r_name,
                 serial,
@@ -416,7 +525,7 @@ impl DnsRecord {
 
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::SOA.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -433,27 +542,94 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             },
-            DnsRecord::TXT { ref domain, ref data, ttl: TransientTtl(ttl) } => {
+            DnsRecord::TXT { ref domain, class, ref data, ttl: TransientTtl(ttl) } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::TXT.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
-                buffer.write_u16(data.len() as u16)?;
 
-                for b in data.as_bytes() {
-                    buffer.write_u8(*b)?;
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // Character-strings can be at most 255 bytes long, so anything
+                // longer is split across several of them. The RDATA holds at
+                // least one string, so no data at all is written as an empty
+                // string.
+                if data.is_empty() {
+                    buffer.write_u8(0)?;
+                }
+
+                for txt in data {
+                    if txt.is_empty() {
+                        buffer.write_u8(0)?;
+                    }
+
+                    for chunk in txt.chunks(255) {
+                        buffer.write_u8(chunk.len() as u8)?;
+                        for b in chunk {
+                            buffer.write_u8(*b)?;
+                        }
+                    }
                 }
+
+                let size = buffer.pos() - (pos + 2);
+                if size > 0xFFFF {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("TXT data of {} bytes exceeds 65535 bytes", size)));
+                }
+                buffer.set_u16(pos, size as u16)?;
             },
-            DnsRecord::OPT { .. } => {
+            DnsRecord::OPT { packet_len, flags, ref data } => {
+                // The OPT pseudo record is owned by the root, and uses the class
+                // and ttl fields for the EDNS parameters
+                buffer.write_u8(0)?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+                write_rdata_len(buffer, data.len())?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             },
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::UNKNOWN { ref domain, class, qtype, ref data, ttl: TransientTtl(ttl) } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                write_rdata_len(buffer, data.len())?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
 
+    /// Build a record from RDATA given in the generic presentation format of
+    /// RFC 3597, i.e. `\# <length> <hex data>`.
+    ///
+    /// If the type is one we know, the data is decoded into the corresponding
+    /// variant, otherwise an `UNKNOWN` record carrying the raw bytes is returned.
+    pub fn from_generic_rdata(domain: &str, class: QueryClass, qtype: QueryType, ttl: u32, rdata: &str) -> Result<DnsRecord> {
+        let data = parse_generic_rdata(rdata)?;
+
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_qname(domain)?;
+        buffer.write_u16(qtype.to_num())?;
+        buffer.write_u16(class.to_num())?;
+        buffer.write_u32(ttl)?;
+        write_rdata_len(&mut buffer, data.len())?;
+        for b in &data {
+            buffer.write_u8(*b)?;
+        }
+
+        buffer.seek(0)?;
+        DnsRecord::read(&mut buffer)
+    }
+
     pub fn get_querytype(&self) -> QueryType {
         match *self {
             DnsRecord::A { .. } => QueryType::A,
@@ -462,7 +638,7 @@ impl DnsRecord {
             DnsRecord::CNAME { .. } => QueryType::CNAME,
             DnsRecord::SRV { .. } => QueryType::SRV,
             DnsRecord::MX { .. } => QueryType::MX,
-            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(qtype),
             DnsRecord::SOA { .. } => QueryType::SOA,
             DnsRecord::TXT { .. } => QueryType::TXT,
             DnsRecord::OPT { .. } => QueryType::OPT
@@ -498,6 +674,511 @@ impl DnsRecord {
             DnsRecord::OPT { .. } => 0
         }
     }
+
+    pub fn get_class(&self) -> QueryClass {
+        match *self {
+            DnsRecord::A { class, .. } |
+            DnsRecord::AAAA { class, .. } |
+            DnsRecord::NS { class, .. } |
+            DnsRecord::CNAME { class, .. } |
+            DnsRecord::SRV { class, .. } |
+            DnsRecord::MX { class, .. } |
+            DnsRecord::UNKNOWN { class, .. } |
+            DnsRecord::SOA { class, .. } |
+            DnsRecord::TXT { class, .. } => class,
+            DnsRecord::OPT { .. } => QueryClass::NONE
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: String) {
+        match *self {
+            DnsRecord::A { ref mut domain, .. } |
+            DnsRecord::AAAA { ref mut domain, .. } |
+            DnsRecord::NS { ref mut domain, .. } |
+            DnsRecord::CNAME { ref mut domain, .. } |
+            DnsRecord::SRV { ref mut domain, .. } |
+            DnsRecord::MX { ref mut domain, .. } |
+            DnsRecord::UNKNOWN { ref mut domain, .. } |
+            DnsRecord::SOA { ref mut domain, .. } |
+            DnsRecord::TXT { ref mut domain, .. } => *domain = new_domain,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::A { ref mut ttl, .. } |
+            DnsRecord::AAAA { ref mut ttl, .. } |
+            DnsRecord::NS { ref mut ttl, .. } |
+            DnsRecord::CNAME { ref mut ttl, .. } |
+            DnsRecord::SRV { ref mut ttl, .. } |
+            DnsRecord::MX { ref mut ttl, .. } |
+            DnsRecord::UNKNOWN { ref mut ttl, .. } |
+            DnsRecord::SOA { ref mut ttl, .. } |
+            DnsRecord::TXT { ref mut ttl, .. } => *ttl = TransientTtl(new_ttl),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let DnsRecord::OPT { packet_len, flags, .. } = *self {
+            return write!(f, "; OPT udp: {} flags: {:08x}", packet_len, flags);
+        }
+
+        let domain = self.get_domain().unwrap_or_default();
+        write!(f, "{}\t{}\t{}\t{}\t", fqdn(&domain), self.get_ttl(), class_name(self.get_class()), type_name(self.get_querytype()))?;
+
+        self.fmt_rdata(f)
+    }
+}
+
+impl DnsRecord {
+    /// Format the RDATA of the record alone, in zone file syntax
+    pub fn rdata_to_string(&self) -> String {
+        struct Rdata<'a>(&'a DnsRecord);
+
+        impl<'a> fmt::Display for Rdata<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt_rdata(f)
+            }
+        }
+
+        Rdata(self).to_string()
+    }
+
+    fn fmt_rdata(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DnsRecord::A { ref addr, .. } => write!(f, "{}", addr),
+            DnsRecord::AAAA { ref addr, .. } => write!(f, "{}", addr),
+            DnsRecord::NS { ref host, .. } |
+            DnsRecord::CNAME { ref host, .. } => write!(f, "{}", fqdn(host)),
+            DnsRecord::MX { priority, ref host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            DnsRecord::SRV { priority, weight, port, ref host, .. } => {
+                write!(f, "{} {} {} {}", priority, weight, port, fqdn(host))
+            },
+            DnsRecord::SOA { ref m_name, ref r_name, serial, refresh, retry, expire, minimum, .. } => {
+                write!(f, "{} {} {} {} {} {} {}", fqdn(m_name), fqdn(r_name), serial, refresh, retry, expire, minimum)
+            },
+            DnsRecord::TXT { ref data, .. } => {
+                let strings = data.iter().map(|x| quote_character_string(x)).collect::<Vec<String>>();
+                write!(f, "{}", strings.join(" "))
+            },
+            DnsRecord::UNKNOWN { ref data, .. } => write!(f, "{}", format_generic_rdata(data)),
+            DnsRecord::OPT { .. } => Ok(())
+        }
+    }
+}
+
+/// Presentation name of a record type, using the `TYPE<n>` form of RFC 3597
+/// for types we don't know
+pub fn type_name(qtype: QueryType) -> String {
+    match qtype {
+        QueryType::UNKNOWN(x) => format!("TYPE{}", x),
+        _ => format!("{:?}", qtype)
+    }
+}
+
+/// Presentation name of a class, using the `CLASS<n>` form of RFC 3597 for
+/// classes we don't know
+pub fn class_name(class: QueryClass) -> String {
+    match class {
+        QueryClass::UNKNOWN(x) => format!("CLASS{}", x),
+        _ => format!("{:?}", class)
+    }
+}
+
+/// Parse a record type written by `type_name`
+pub fn parse_type_name(name: &str) -> Option<QueryType> {
+    let name = name.to_uppercase();
+    let qtype = match name.as_str() {
+        "A" => QueryType::A,
+        "NS" => QueryType::NS,
+        "CNAME" => QueryType::CNAME,
+        "SOA" => QueryType::SOA,
+        "MX" => QueryType::MX,
+        "TXT" => QueryType::TXT,
+        "AAAA" => QueryType::AAAA,
+        "SRV" => QueryType::SRV,
+        "OPT" => QueryType::OPT,
+        _ => return name.strip_prefix("TYPE")
+            .and_then(|x| x.parse::<u16>().ok())
+            .map(QueryType::from_num)
+    };
+
+    Some(qtype)
+}
+
+/// Parse a class written by `class_name`
+pub fn parse_class_name(name: &str) -> Option<QueryClass> {
+    let name = name.to_uppercase();
+    let class = match name.as_str() {
+        "IN" => QueryClass::IN,
+        "CH" | "CHAOS" => QueryClass::CH,
+        "HS" => QueryClass::HS,
+        "NONE" => QueryClass::NONE,
+        "ANY" => QueryClass::ANY,
+        _ => return name.strip_prefix("CLASS")
+            .and_then(|x| x.parse::<u16>().ok())
+            .map(QueryClass::from_num)
+    };
+
+    Some(class)
+}
+
+/// A word of zone file text
+///
+/// `raw` holds the text as written, while `data` has quotes removed and
+/// escapes resolved, which is what character-strings need.
+struct Token {
+    raw: String,
+    data: Vec<u8>
+}
+
+fn parse_error(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Split a line of zone file text into words
+///
+/// Words are separated by whitespace, unless quoted, and a `;` starts a
+/// comment running to the end of the line. Parentheses are skipped, so that
+/// records spanning several lines can be parsed in one go.
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let bytes = text.as_bytes();
+    let is_separator = |c: u8| c.is_ascii_whitespace() || c == b'(' || c == b')' || c == b';' || c == b'"';
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            },
+            c if c.is_ascii_whitespace() || c == b'(' || c == b')' => {
+                i += 1;
+                continue;
+            },
+            _ => {}
+        }
+
+        let start = i;
+        let quoted = bytes[i] == b'"';
+        if quoted {
+            i += 1;
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if i >= bytes.len() {
+                if quoted {
+                    return Err(parse_error("Unterminated quoted string".to_string()));
+                }
+                break;
+            }
+
+            let c = bytes[i];
+            if quoted && c == b'"' {
+                i += 1;
+                break;
+            }
+            if !quoted && is_separator(c) {
+                break;
+            }
+
+            // Either a `\DDD` decimal escape, or a backslash quoting the
+            // following character
+            if c == b'\\' {
+                let digits = bytes.get(i+1..i+4).filter(|x| x.iter().all(u8::is_ascii_digit));
+                if let Some(digits) = digits {
+                    let value = digits.iter().fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                    if value > 255 {
+                        return Err(parse_error(format!("Invalid escape in {}", text)));
+                    }
+                    data.push(value as u8);
+                    i += 4;
+                } else if i + 1 < bytes.len() {
+                    data.push(bytes[i+1]);
+                    i += 2;
+                } else {
+                    return Err(parse_error(format!("Dangling escape in {}", text)));
+                }
+                continue;
+            }
+
+            data.push(c);
+            i += 1;
+        }
+
+        tokens.push(Token {
+            raw: String::from_utf8_lossy(&bytes[start..i]).into_owned(),
+            data
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// Names are kept without the trailing dot of fully qualified names
+fn parse_name(name: &str) -> String {
+    name.trim_end_matches('.').to_string()
+}
+
+fn parse_field<T: FromStr>(rdata: &[Token], idx: usize, qtype: QueryType) -> Result<T> {
+    rdata.get(idx)
+        .and_then(|x| x.raw.parse::<T>().ok())
+        .ok_or_else(|| parse_error(format!("Invalid or missing RDATA field {} for {}", idx + 1, type_name(qtype))))
+}
+
+fn parse_name_field(rdata: &[Token], idx: usize, qtype: QueryType) -> Result<String> {
+    rdata.get(idx)
+        .map(|x| parse_name(&x.raw))
+        .ok_or_else(|| parse_error(format!("Missing RDATA field {} for {}", idx + 1, type_name(qtype))))
+}
+
+impl FromStr for DnsRecord {
+    type Err = Error;
+
+    /// Parse a record in zone file syntax, as written by `Display`
+    ///
+    /// The owner name, TTL and type are required, while the class defaults to
+    /// IN. The TTL and the class can come in either order. Records of any
+    /// type may also be given using the generic RDATA syntax of RFC 3597.
+    fn from_str(text: &str) -> Result<DnsRecord> {
+        let tokens = tokenize(text)?;
+
+        let domain = match tokens.first() {
+            Some(token) => parse_name(&token.raw),
+            None => return Err(parse_error("Empty record".to_string()))
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        let mut pos = 1;
+        let qtype = loop {
+            let word = match tokens.get(pos) {
+                Some(token) => &token.raw,
+                None => return Err(parse_error(format!("Missing record type in {}", text)))
+            };
+            pos += 1;
+
+            if ttl.is_none() {
+                if let Ok(x) = word.parse::<u32>() {
+                    ttl = Some(x);
+                    continue;
+                }
+            }
+
+            if class.is_none() {
+                if let Some(x) = parse_class_name(word) {
+                    class = Some(x);
+                    continue;
+                }
+            }
+
+            match parse_type_name(word) {
+                Some(x) => break x,
+                None => return Err(parse_error(format!("Unknown record type {}", word)))
+            }
+        };
+
+        let ttl = match ttl {
+            Some(x) => x,
+            None => return Err(parse_error(format!("Missing TTL in {}", text)))
+        };
+        let class = class.unwrap_or(QueryClass::IN);
+
+        let rdata = &tokens[pos..];
+        if rdata.first().map(|x| x.raw.as_str()) == Some("\\#") {
+            let generic = rdata.iter().map(|x| x.raw.as_str()).collect::<Vec<&str>>().join(" ");
+            return DnsRecord::from_generic_rdata(&domain, class, qtype, ttl, &generic);
+        }
+
+        let field_count = match qtype {
+            QueryType::A | QueryType::AAAA | QueryType::NS | QueryType::CNAME => 1,
+            QueryType::MX => 2,
+            QueryType::SRV => 4,
+            QueryType::SOA => 7,
+            QueryType::TXT => rdata.len().max(1),
+            QueryType::OPT | QueryType::UNKNOWN(_) => {
+                return Err(parse_error(format!("{} records must use the generic RDATA syntax", type_name(qtype))));
+            }
+        };
+
+        if rdata.len() != field_count {
+            return Err(parse_error(format!("Expected {} RDATA fields for {}, found {}",
+                                           field_count, type_name(qtype), rdata.len())));
+        }
+
+        let ttl = TransientTtl(ttl);
+        let record = match qtype {
+            QueryType::A => DnsRecord::A {
+                domain,
+                class,
+                addr: parse_field(rdata, 0, qtype)?,
+                ttl
+            },
+            QueryType::AAAA => DnsRecord::AAAA {
+                domain,
+                class,
+                addr: parse_field(rdata, 0, qtype)?,
+                ttl
+            },
+            QueryType::NS => DnsRecord::NS {
+                domain,
+                class,
+                host: parse_name_field(rdata, 0, qtype)?,
+                ttl
+            },
+            QueryType::CNAME => DnsRecord::CNAME {
+                domain,
+                class,
+                host: parse_name_field(rdata, 0, qtype)?,
+                ttl
+            },
+            QueryType::MX => DnsRecord::MX {
+                domain,
+                class,
+                priority: parse_field(rdata, 0, qtype)?,
+                host: parse_name_field(rdata, 1, qtype)?,
+                ttl
+            },
+            QueryType::SRV => DnsRecord::SRV {
+                domain,
+                class,
+                priority: parse_field(rdata, 0, qtype)?,
+                weight: parse_field(rdata, 1, qtype)?,
+                port: parse_field(rdata, 2, qtype)?,
+                host: parse_name_field(rdata, 3, qtype)?,
+                ttl
+            },
+            QueryType::SOA => DnsRecord::SOA {
+                domain,
+                class,
+                m_name: parse_name_field(rdata, 0, qtype)?,
+                r_name: parse_name_field(rdata, 1, qtype)?,
+                serial: parse_field(rdata, 2, qtype)?,
+                refresh: parse_field(rdata, 3, qtype)?,
+                retry: parse_field(rdata, 4, qtype)?,
+                expire: parse_field(rdata, 5, qtype)?,
+                minimum: parse_field(rdata, 6, qtype)?,
+                ttl
+            },
+            QueryType::TXT => DnsRecord::TXT {
+                domain,
+                class,
+                data: rdata.iter().map(|x| x.data.clone()).collect(),
+                ttl
+            },
+            QueryType::OPT | QueryType::UNKNOWN(_) => unreachable!()
+        };
+
+        Ok(record)
+    }
+}
+
+/// Write the length of RDATA, refusing RDATA which is too long for it
+fn write_rdata_len<T: PacketBuffer>(buffer: &mut T, len: usize) -> Result<()> {
+    if len > 0xFFFF {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("RDATA of {} bytes exceeds 65535 bytes", len)));
+    }
+
+    buffer.write_u16(len as u16)
+}
+
+/// Check whether a name lies within a zone, comparing whole labels so that
+/// `evilgoogle.com` isn't considered part of `google.com`, and ignoring case
+pub fn in_bailiwick(name: &str, zone: &str) -> bool {
+    let zone = zone.trim_end_matches('.');
+    if zone.is_empty() {
+        return true;
+    }
+
+    let name = name.trim_end_matches('.').to_lowercase();
+    let zone = zone.to_lowercase();
+
+    name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Append the trailing dot that makes a name fully qualified in zone files
+fn fqdn(name: &str) -> String {
+    if name.is_empty() || name.ends_with('.') {
+        format!("{}.", name.trim_end_matches('.'))
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// Quote a character-string the way zone files do
+///
+/// Quotes and backslashes are escaped with a backslash, while bytes outside
+/// the printable ASCII range are written as `\DDD` decimal escapes.
+pub fn quote_character_string(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len() + 2);
+    result.push('"');
+    for &b in data {
+        match b {
+            b'"' | b'\\' => {
+                result.push('\\');
+                result.push(b as char);
+            },
+            0x20..=0x7E => result.push(b as char),
+            _ => result.push_str(&format!("\\{:03}", b))
+        }
+    }
+    result.push('"');
+
+    result
+}
+
+/// Parse the generic RDATA syntax of RFC 3597, `\# <length> <hex data>`
+///
+/// The hex data may be split into several whitespace separated words, and is
+/// omitted altogether when the length is zero.
+pub fn parse_generic_rdata(text: &str) -> Result<Vec<u8>> {
+    let mut words = text.split_whitespace();
+
+    if words.next() != Some("\\#") {
+        return Err(Error::new(ErrorKind::InvalidData, "Generic RDATA must start with \\#"));
+    }
+
+    let len = match words.next().and_then(|x| x.parse::<u16>().ok()) {
+        Some(x) => x as usize,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Invalid generic RDATA length"))
+    };
+
+    let hex = words.collect::<String>();
+    if hex.len() != len * 2 {
+        return Err(Error::new(ErrorKind::InvalidData, "Generic RDATA length does not match data"));
+    }
+
+    let mut data = Vec::with_capacity(len);
+    for i in 0..len {
+        match u8::from_str_radix(hex.get(i*2..i*2+2).unwrap_or(""), 16) {
+            Ok(b) => data.push(b),
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Invalid hex in generic RDATA"))
+        }
+    }
+
+    Ok(data)
+}
+
+/// Format RDATA using the generic syntax of RFC 3597
+pub fn format_generic_rdata(data: &[u8]) -> String {
+    let mut result = format!("\\# {}", data.len());
+    if !data.is_empty() {
+        result.push(' ');
+        for b in data {
+            result.push_str(&format!("{:02x}", b));
+        }
+    }
+
+    result
 }
 
 /// The result code for a DNS query, as described in the specification
@@ -508,7 +1189,12 @@ pub enum ResultCode {
     SERVFAIL = 2,
     NXDOMAIN = 3,
     NOTIMP = 4,
-    REFUSED = 5
+    REFUSED = 5,
+    YXDOMAIN = 6,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10
 }
 
 impl Default for ResultCode {
@@ -525,11 +1211,55 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
             0 | _ => ResultCode::NOERROR
         }
     }
 }
 
+/// The kind of request carried by a packet, as described in the specification
+///
+/// Opcodes we don't know are retained as integers, so that they can be echoed
+/// back when replying.
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub enum Opcode {
+    UNKNOWN(u8),
+    #[default]
+    QUERY, // 0
+    IQUERY, // 1
+    STATUS, // 2
+    NOTIFY, // 4
+    UPDATE // 5
+}
+
+impl Opcode {
+    pub fn to_num(&self) -> u8 {
+        match *self {
+            Opcode::UNKNOWN(x) => x,
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::UNKNOWN(num)
+        }
+    }
+}
+
 /// Representation of a DNS header
 #[derive(Clone,Debug,Default)]
 pub struct DnsHeader {
@@ -538,7 +1268,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool, // 1 bit
     pub truncated_message: bool, // 1 bit
     pub authoritative_answer: bool, // 1 bit
-    pub opcode: u8, // 4 bits
+    pub opcode: Opcode, // 4 bits
     pub response: bool, // 1 bit
 
     pub res_code: ResultCode, // 4 bits
@@ -561,7 +1291,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
 
             res_code: ResultCode::NOERROR,
@@ -583,7 +1313,7 @@ impl DnsHeader {
         buffer.write_u8(((self.recursion_desired as u8)) |
             ((self.truncated_message as u8) << 1) |
             ((self.authoritative_answer as u8) << 2) |
-            (self.opcode << 3) |
+            (self.opcode.to_num() << 3) |
             ((self.response as u8) << 7) as u8)?;
 
         buffer.write_u8((self.res_code.clone() as u8) |
@@ -613,7 +1343,7 @@ impl DnsHeader {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
         self.res_code = ResultCode::from_num(b & 0x0F);
@@ -633,28 +1363,30 @@ impl DnsHeader {
 }
 
 impl fmt::Display for DnsHeader {
+    /// The header lines of `dig` output
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "DnsHeader:\n")?;
-        write!(f, "\tid: {0}\n", self.id)?;
-
-        write!(f, "\trecursion_desired: {0}\n", self.recursion_desired)?;
-        write!(f, "\ttruncated_message: {0}\n", self.truncated_message)?;
-        write!(f, "\tauthoritative_answer: {0}\n", self.authoritative_answer)?;
-        write!(f, "\topcode: {0}\n", self.opcode)?;
-        write!(f, "\tresponse: {0}\n", self.response)?;
-
-        write!(f, "\trescode: {:?}\n", self.res_code)?;
-        write!(f, "\tchecking_disabled: {0}\n", self.checking_disabled)?;
-        write!(f, "\tauthed_data: {0}\n", self.authed_data)?;
-        write!(f, "\tz: {0}\n", self.z)?;
-        write!(f, "\trecursion_available: {0}\n", self.recursion_available)?;
-
-        write!(f, "\tquestions: {0}\n", self.questions)?;
-        write!(f, "\tanswers: {0}\n", self.answers)?;
-        write!(f, "\tauthoritative_entries: {0}\n", self.authoritative_entries)?;
-        write!(f, "\tresource_entries: {0}\n", self.resource_entries)?;
-
-        Ok(())
+        let opcode = match self.opcode {
+            Opcode::UNKNOWN(x) => format!("OPCODE{}", x),
+            opcode => format!("{:?}", opcode)
+        };
+        writeln!(f, ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}", opcode, self.res_code, self.id)?;
+
+        let flags = [
+            (self.response, "qr"),
+            (self.authoritative_answer, "aa"),
+            (self.truncated_message, "tc"),
+            (self.recursion_desired, "rd"),
+            (self.recursion_available, "ra"),
+            (self.authed_data, "ad"),
+            (self.checking_disabled, "cd")
+        ];
+        let flags = flags.iter()
+            .filter(|x| x.0)
+            .map(|x| format!(" {}", x.1))
+            .collect::<String>();
+
+        writeln!(f, ";; flags:{}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+                 flags, self.questions, self.answers, self.authoritative_entries, self.resource_entries)
     }
 }
 
@@ -662,14 +1394,20 @@ impl fmt::Display for DnsHeader {
 #[derive(Debug,Clone,PartialEq,Eq)]
 pub struct DnsQuestion {
     pub name: String,
-    pub qtype: QueryType
+    pub qtype: QueryType,
+    pub qclass: QueryClass
 }
 
 impl DnsQuestion {
     pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
+        DnsQuestion::with_class(name, qtype, QueryClass::IN)
+    }
+
+    pub fn with_class(name: String, qtype: QueryType, qclass: QueryClass) -> DnsQuestion {
         DnsQuestion {
-            name: name,
-            qtype: qtype
+            name,
+            qtype,
+            qclass
         }
     }
 
@@ -682,7 +1420,7 @@ impl DnsQuestion {
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass.to_num())?;
 
         Ok(())
     }
@@ -690,19 +1428,16 @@ impl DnsQuestion {
     pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
-        let _ = buffer.read_u16()?; // class
+        self.qclass = QueryClass::from_num(buffer.read_u16()?); // class
 
         Ok(())
     }
 }
 
 impl fmt::Display for DnsQuestion {
+    /// A question as listed in the question section of `dig` output
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "DnsQuestion:\n")?;
-        write!(f, "\tname: {0}\n", self.name)?;
-        write!(f, "\trecord type: {:?}\n", self.qtype)?;
-
-        Ok(())
+        write!(f, ";{}\t\t{}\t{}", fqdn(&self.name), class_name(self.qclass), type_name(self.qtype))
     }
 }
 
@@ -761,27 +1496,7 @@ impl DnsPacket {
 
     #[allow(dead_code)]
     pub fn print(&self) {
-        println!("{}", self.header);
-
-        println!("questions:");
-        for x in &self.questions {
-            println!("\t{:?}", x);
-        }
-
-        println!("answers:");
-        for x in &self.answers {
-            println!("\t{:?}", x);
-        }
-
-        println!("authorities:");
-        for x in &self.authorities {
-            println!("\t{:?}", x);
-        }
-
-        println!("resources:");
-        for x in &self.resources {
-            println!("\t{:?}", x);
-        }
+        println!("{}", self);
     }
 
     pub fn set_start_time(&mut self, time: u64) {
@@ -792,10 +1507,18 @@ impl DnsPacket {
         self.start_time
     }
 
+    /// Get the SOA record of the authority section, as included in negative
+    /// answers
+    pub fn get_soa(&self) -> Option<&DnsRecord> {
+        self.authorities.iter().find(|rec| rec.get_querytype() == QueryType::SOA)
+    }
+
+    /// Get the TTL of a negative answer, which is the lower of the TTL of the
+    /// SOA record and its minimum field (RFC 2308)
     pub fn get_ttl_from_soa(&self) -> Option<u32> {
         for answer in &self.authorities {
-            if let DnsRecord::SOA { minimum, .. } = *answer {
-                return Some(minimum);
+            if let DnsRecord::SOA { minimum, ttl: TransientTtl(ttl), .. } = *answer {
+                return Some(cmp::min(minimum, ttl));
             }
         }
 
@@ -843,18 +1566,19 @@ impl DnsPacket {
         let mut new_authorities = Vec::new();
         for auth in &self.authorities {
             if let DnsRecord::NS { ref domain, ref host, .. } = *auth {
-                if domain.is_empty() || !qname.ends_with(domain) {
+                if domain.is_empty() || !in_bailiwick(qname, domain) {
                     continue;
                 }
 
                 for rsrc in &self.resources {
-                    if let DnsRecord::A{ ref domain, ref addr, ttl: TransientTtl(ttl) } = *rsrc {
-                        if domain != host {
+                    if let DnsRecord::A{ ref domain, class, ref addr, ttl: TransientTtl(ttl) } = *rsrc {
+                        if !domain.eq_ignore_ascii_case(host) {
                             continue;
                         }
 
                         let rec = DnsRecord::A {
                             domain: host.clone(),
+                            class,
                             addr: *addr,
                             ttl: TransientTtl(ttl)
                         };
@@ -880,7 +1604,7 @@ impl DnsPacket {
         let mut new_authorities = Vec::new();
         for auth in &self.authorities {
             if let DnsRecord::NS { ref domain, ref host, .. } = *auth {
-                if domain.is_empty() || !qname.ends_with(domain) {
+                if domain.is_empty() || !in_bailiwick(qname, domain) {
                     continue;
                 }
 
@@ -896,63 +1620,177 @@ impl DnsPacket {
         None
     }
 
+    /// The zone delegated to by the NS records of the response, lowercased
+    /// so that zones can be compared regardless of the case they're sent in
     pub fn get_ns_zone(&self) -> String {
         for auth in &self.authorities {
             if let DnsRecord::NS { ref domain, .. } = *auth {
                 if !domain.is_empty() {
-                    return domain.clone();
+                    return domain.to_lowercase();
                 }
             }
         }
         String::from("")
     }
 
+    /// Write the packet, with name compression, to a buffer
+    ///
+    /// Records are written one RRset at a time, so records of an RRset which
+    /// aren't adjacent in a section are moved up to follow the first of them.
+    /// If an RRset doesn't fit within `max_size` bytes, it's left out along
+    /// with everything following it, and the header counts reflect what was
+    /// actually written. Running out of space in the answer or authority
+    /// section sets the truncation flag, while a partial additional section
+    /// is allowed without it (RFC 2181).
     pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T, max_size: usize) -> Result<()> {
-        let mut test_buffer = VectorPacketBuffer::new();
+        let mut writer = PacketWriter::new(buffer);
+        let start = writer.pos();
+
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = 0;
+        self.header.authoritative_entries = 0;
+        self.header.resource_entries = 0;
+
+        // The header is written again once the counts are known
+        self.header.write(&mut writer)?;
 
-        let mut size = self.header.binary_len();
-        for ref question in &self.questions {
-            size += question.binary_len();
-            question.write(&mut test_buffer)?;
+        for question in &self.questions {
+            question.write(&mut writer)?;
         }
 
-        let mut record_count = self.answers.len() + self.authorities.len() + self.resources.len();
+        let mut counts = [0; 3];
+        let mut truncated = false;
+
+        {
+            let sections = [&self.answers, &self.authorities, &self.resources];
+            'sections: for (i, section) in sections.iter().enumerate() {
+                for rrset in group_rrsets(section) {
+                    let rrset_start = writer.pos();
+
+                    let mut fits = true;
+                    for rec in &rrset {
+                        match rec.write(&mut writer) {
+                            Ok(_) => {},
+                            Err(ref e) if WireError::from_io(e) == Some(&WireError::EndOfBuffer) => {
+                                fits = false;
+                                break;
+                            },
+                            Err(e) => return Err(e)
+                        }
+                    }
 
-        for (i, rec) in self.answers.iter().chain(self.authorities.iter()).chain(self.resources.iter()).enumerate() {
-            size += rec.write(&mut test_buffer)?;
-            if size > max_size {
-                record_count = i;
-                self.header.truncated_message = true;
-                break;
-            } else if i < self.answers.len() {
-                self.header.answers += 1;
-            } else if i < self.answers.len() + self.authorities.len() {
-                self.header.authoritative_entries += 1;
-            } else {
-                self.header.resource_entries += 1;
+                    if !fits || writer.pos() - start > max_size {
+                        writer.seek(rrset_start)?;
+                        writer.forget_labels(rrset_start);
+                        truncated = i < 2;
+                        break 'sections;
+                    }
+
+                    counts[i] += rrset.len() as u16;
+                }
             }
         }
 
-        self.header.questions = self.questions.len() as u16;
-        self.header.write(buffer)?;
+        self.header.answers = counts[0];
+        self.header.authoritative_entries = counts[1];
+        self.header.resource_entries = counts[2];
+        if truncated {
+            self.header.truncated_message = true;
+        }
 
-        for question in &self.questions {
-            question.write(buffer)?;
+        let end = writer.pos();
+        writer.seek(start)?;
+        self.header.write(&mut writer)?;
+        writer.seek(end)?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for DnsPacket {
+    /// The packet in the format of `dig` output
+    ///
+    /// The header counts are taken from the sections, rather than from the
+    /// header, so that packets which haven't been written yet come out right.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut header = self.header.clone();
+        header.questions = self.questions.len() as u16;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = self.authorities.len() as u16;
+        header.resource_entries = self.resources.len() as u16;
+        write!(f, "{}", header)?;
+
+        for rec in &self.resources {
+            if let DnsRecord::OPT { packet_len, flags, .. } = *rec {
+                let do_flag = if flags & 0x8000 != 0 { " do" } else { "" };
+                writeln!(f)?;
+                writeln!(f, ";; OPT PSEUDOSECTION:")?;
+                writeln!(f, "; EDNS: version: {}, flags:{}; udp: {}", (flags >> 16) & 0xFF, do_flag, packet_len)?;
+            }
+        }
+
+        if !self.questions.is_empty() {
+            writeln!(f)?;
+            writeln!(f, ";; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, "{}", question)?;
+            }
         }
 
-        for rec in self.answers.iter().chain(self.authorities.iter()).chain(self.resources.iter()).take(record_count) {
-            rec.write(buffer)?;
+        let resources = self.resources.iter()
+            .filter(|x| x.get_querytype() != QueryType::OPT)
+            .collect::<Vec<&DnsRecord>>();
+        let sections = [
+            ("ANSWER", self.answers.iter().collect::<Vec<&DnsRecord>>()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", resources)
+        ];
+
+        for &(name, ref records) in &sections {
+            if records.is_empty() {
+                continue;
+            }
+
+            writeln!(f)?;
+            writeln!(f, ";; {} SECTION:", name)?;
+            for rec in records {
+                writeln!(f, "{}", rec)?;
+            }
         }
 
         Ok(())
     }
 }
 
+/// Group the records of a section into RRsets, ordered by first appearance
+fn group_rrsets(records: &[DnsRecord]) -> Vec<Vec<&DnsRecord>> {
+    let mut rrsets: Vec<Vec<&DnsRecord>> = Vec::new();
+
+    for rec in records {
+        let existing = rrsets.iter().position(|rrset| {
+            let first = rrset[0];
+            first.get_querytype() == rec.get_querytype() &&
+                first.get_class() == rec.get_class() &&
+                match (first.get_domain(), rec.get_domain()) {
+                    (Some(a), Some(b)) => a.eq_ignore_ascii_case(&b),
+                    _ => false
+                }
+        });
+
+        match existing {
+            Some(idx) => rrsets[idx].push(rec),
+            None => rrsets.push(vec![rec])
+        }
+    }
+
+    rrsets
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+    use dns::buffer::{BytePacketBuffer, PacketBuffer, VectorPacketBuffer};
 
     #[test]
     fn test_packet() {
@@ -964,21 +1802,25 @@ mod tests {
         //packet.answers.push(DnsRecord::A("ns1.google.com".to_string(), "127.0.0.1".parse::<Ipv4Addr>().unwrap(), 3600));
         packet.answers.push(DnsRecord::NS {
             domain: "google.com".to_string(),
+            class: QueryClass::IN,
             host: "ns1.google.com".to_string(),
             ttl: TransientTtl(3600)
         });
         packet.answers.push(DnsRecord::NS {
             domain: "google.com".to_string(),
+            class: QueryClass::IN,
             host: "ns2.google.com".to_string(),
             ttl: TransientTtl(3600)
         });
         packet.answers.push(DnsRecord::NS {
             domain: "google.com".to_string(),
+            class: QueryClass::IN,
             host: "ns3.google.com".to_string(),
             ttl: TransientTtl(3600)
         });
         packet.answers.push(DnsRecord::NS {
             domain: "google.com".to_string(),
+            class: QueryClass::IN,
             host: "ns4.google.com".to_string(),
             ttl: TransientTtl(3600)
         });
@@ -996,4 +1838,647 @@ mod tests {
         assert_eq!(packet.answers[2], parsed_packet.answers[2]);
         assert_eq!(packet.answers[3], parsed_packet.answers[3]);
     }
+
+    fn build_truncation_packet() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            class: QueryClass::IN,
+            host: "cdn.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        for i in 0..10 {
+            packet.answers.push(DnsRecord::A {
+                domain: "cdn.example.com".to_string(),
+                class: QueryClass::IN,
+                addr: Ipv4Addr::new(127, 0, 0, i),
+                ttl: TransientTtl(3600)
+            });
+        }
+        for i in 0..4 {
+            packet.authorities.push(DnsRecord::NS {
+                domain: "example.com".to_string(),
+                class: QueryClass::IN,
+                host: format!("ns{}.example.com", i),
+                ttl: TransientTtl(3600)
+            });
+            packet.resources.push(DnsRecord::A {
+                domain: format!("ns{}.example.com", i),
+                class: QueryClass::IN,
+                addr: Ipv4Addr::new(127, 0, 1, i),
+                ttl: TransientTtl(3600)
+            });
+        }
+
+        packet
+    }
+
+    fn write_truncated(packet: &mut DnsPacket, max_size: usize) -> DnsPacket {
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, max_size).unwrap();
+        assert!(buffer.pos() <= max_size);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.header.answers as usize, parsed.answers.len());
+
+        parsed
+    }
+
+    #[test]
+    fn test_packet_truncation() {
+        // Everything fits, and names are compressed
+        let mut packet = build_truncation_packet();
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+        assert_eq!(347, buffer.pos());
+        assert!(!packet.header.truncated_message);
+
+        // Writing a packet twice gives the same counts
+        packet.write(&mut VectorPacketBuffer::new(), 512).unwrap();
+        assert_eq!(11, packet.header.answers);
+        assert_eq!(4, packet.header.authoritative_entries);
+        assert_eq!(4, packet.header.resource_entries);
+
+        // The A RRset doesn't fit, so it's left out entirely
+        let mut packet = build_truncation_packet();
+        let parsed = write_truncated(&mut packet, 150);
+        assert!(parsed.header.truncated_message);
+        assert_eq!(1, parsed.answers.len());
+        assert!(parsed.authorities.is_empty());
+        assert!(parsed.resources.is_empty());
+
+        // The NS RRset doesn't fit either
+        let mut packet = build_truncation_packet();
+        let parsed = write_truncated(&mut packet, 250);
+        assert!(parsed.header.truncated_message);
+        assert_eq!(11, parsed.answers.len());
+        assert!(parsed.authorities.is_empty());
+
+        // Leaving out additional records doesn't set the truncation flag
+        let mut packet = build_truncation_packet();
+        let parsed = write_truncated(&mut packet, 335);
+        assert!(!parsed.header.truncated_message);
+        assert_eq!(11, parsed.answers.len());
+        assert_eq!(4, parsed.authorities.len());
+        assert_eq!(3, parsed.resources.len());
+        assert_eq!(packet.resources[..3], parsed.resources[..]);
+    }
+
+    #[test]
+    fn test_packet_truncation_fixed_buffer() {
+        // Running out of room in a fixed size buffer truncates the packet
+        // the same way as the size limit does
+        let mut packet = build_truncation_packet();
+        for i in 0..30 {
+            packet.answers.push(DnsRecord::TXT {
+                domain: "www.example.com".to_string(),
+                class: QueryClass::IN,
+                data: vec![vec![b'a' + i; 20]],
+                ttl: TransientTtl(3600)
+            });
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+        assert!(packet.header.truncated_message);
+        assert_eq!(11, packet.header.answers);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(packet.answers[..11], parsed.answers[..]);
+        assert!(parsed.authorities.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_record() {
+        let record = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            qtype: 65280,
+            data: vec![0x0a, 0x00, 0x00, 0x01, 0xff],
+            ttl: TransientTtl(3600)
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        let size = record.write(&mut buffer).unwrap();
+        assert_eq!(buffer.pos(), size);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(size, buffer.pos());
+        match parsed {
+            DnsRecord::UNKNOWN { ref domain, qtype, ref data, ttl, .. } => {
+                assert_eq!("example.com", domain);
+                assert_eq!(65280, qtype);
+                assert_eq!(&vec![0x0a, 0x00, 0x00, 0x01, 0xff], data);
+                assert_eq!(3600, ttl.0);
+            },
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_generic_rdata() {
+        assert_eq!(vec![0x0a, 0x00, 0x00, 0x01], parse_generic_rdata("\\# 4 0A000001").unwrap());
+        assert_eq!(vec![0x0a, 0x00, 0x00, 0x01], parse_generic_rdata("\\# 4 0a00 0001").unwrap());
+        assert_eq!(Vec::<u8>::new(), parse_generic_rdata("\\# 0").unwrap());
+        assert!(parse_generic_rdata("\\# 3 0a0000 01").is_err());
+        assert!(parse_generic_rdata("# 1 00").is_err());
+        assert!(parse_generic_rdata("\\# 1 zz").is_err());
+
+        assert_eq!("\\# 4 0a000001", format_generic_rdata(&[0x0a, 0x00, 0x00, 0x01]));
+        assert_eq!("\\# 0", format_generic_rdata(&[]));
+
+        // Known types given in generic form are decoded as usual
+        let rec = DnsRecord::from_generic_rdata("example.com", QueryClass::IN, QueryType::A, 300, "\\# 4 0A000001").unwrap();
+        assert_eq!(DnsRecord::A {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            addr: "10.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(300)
+        }, rec);
+        assert!(DnsRecord::from_generic_rdata("example.com", QueryClass::IN, QueryType::A, 300, "\\# 2 0A00").is_err());
+
+        // While unknown types keep their raw data
+        let rec = DnsRecord::from_generic_rdata("example.com", QueryClass::IN, QueryType::UNKNOWN(731), 300, "\\# 2 abcd").unwrap();
+        assert_eq!(QueryType::UNKNOWN(731), rec.get_querytype());
+        if let DnsRecord::UNKNOWN { ref data, .. } = rec {
+            assert_eq!(&vec![0xab, 0xcd], data);
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn test_txt_record() {
+        let long = vec![b'a'; 300];
+        let record = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            data: vec![b"v=spf1 -all".to_vec(), long.clone()],
+            ttl: TransientTtl(3600)
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        let size = record.write(&mut buffer).unwrap();
+
+        // name (13) + type, class, ttl, rdlength (10) + 1+11 + 1+255 + 1+45
+        assert_eq!(13 + 10 + 12 + 256 + 46, size);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(size, buffer.pos());
+
+        match parsed {
+            DnsRecord::TXT { ref data, .. } => {
+                assert_eq!(3, data.len());
+                assert_eq!(b"v=spf1 -all".to_vec(), data[0]);
+                assert_eq!(long[..255].to_vec(), data[1]);
+                assert_eq!(long[255..].to_vec(), data[2]);
+            },
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_txt_record_limits() {
+        // Without any data, a single empty string is written
+        let record = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            data: Vec::new(),
+            ttl: TransientTtl(3600)
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        let size = record.write(&mut buffer).unwrap();
+        assert_eq!(13 + 10 + 1, size);
+        assert_eq!(&[0, 1, 0], &buffer.buffer[size-3..size]);
+
+        buffer.seek(0).unwrap();
+        match DnsRecord::read(&mut buffer).unwrap() {
+            DnsRecord::TXT { ref data, .. } => assert_eq!(vec![Vec::<u8>::new()], *data),
+            _ => panic!()
+        }
+
+        // RDATA longer than its 16 bit length can express is refused
+        let record = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            data: vec![vec![b'a'; 0xFFFF]],
+            ttl: TransientTtl(3600)
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        let err = record.write(&mut buffer).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_raw_rdata_limits() {
+        // Records carrying raw RDATA refuse data too long for its length
+        // field, rather than writing a length which has wrapped around
+        let records = vec![
+            DnsRecord::UNKNOWN {
+                domain: "example.com".to_string(),
+                class: QueryClass::IN,
+                qtype: 65280,
+                data: vec![0; 0x10000],
+                ttl: TransientTtl(3600)
+            },
+            DnsRecord::OPT {
+                packet_len: 4096,
+                flags: 0,
+                data: vec![0; 0x10000]
+            }
+        ];
+
+        for record in records {
+            let mut buffer = VectorPacketBuffer::new();
+            let err = record.write(&mut buffer).unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+
+        // Up to the limit, they're written as they are
+        let record = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            qtype: 65280,
+            data: vec![0; 0xFFFF],
+            ttl: TransientTtl(3600)
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        assert_eq!(13 + 10 + 0xFFFF, record.write(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn test_txt_record_display() {
+        let record = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            data: vec![b"say \"hi\"".to_vec(), b"back\\slash\x01".to_vec()],
+            ttl: TransientTtl(300)
+        };
+
+        assert_eq!("example.com.\t300\tIN\tTXT\t\"say \\\"hi\\\"\" \"back\\\\slash\\001\"",
+                   record.to_string());
+    }
+
+    #[test]
+    fn test_packet_display() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1337;
+        packet.header.response = true;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            class: QueryClass::IN,
+            host: "example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(300)
+        });
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0x8000,
+            data: Vec::new()
+        });
+
+        assert_eq!(";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 1337\n\
+                    ;; flags: qr rd ra; QUERY: 1, ANSWER: 2, AUTHORITY: 0, ADDITIONAL: 1\n\
+                    \n\
+                    ;; OPT PSEUDOSECTION:\n\
+                    ; EDNS: version: 0, flags: do; udp: 4096\n\
+                    \n\
+                    ;; QUESTION SECTION:\n\
+                    ;www.example.com.\t\tIN\tA\n\
+                    \n\
+                    ;; ANSWER SECTION:\n\
+                    www.example.com.\t3600\tIN\tCNAME\texample.com.\n\
+                    example.com.\t300\tIN\tA\t127.0.0.1\n",
+                   packet.to_string());
+
+        packet.header.res_code = ResultCode::NXDOMAIN;
+        packet.header.opcode = Opcode::UNKNOWN(3);
+        packet.header.response = false;
+        packet.header.recursion_desired = false;
+        packet.header.recursion_available = false;
+        assert_eq!(";; ->>HEADER<<- opcode: OPCODE3, status: NXDOMAIN, id: 1337\n\
+                    ;; flags:; QUERY: 0, ANSWER: 0, AUTHORITY: 0, ADDITIONAL: 0\n",
+                   packet.header.to_string());
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let records = vec![
+            DnsRecord::A { domain: "example.com".to_string(), class: QueryClass::IN,
+                           addr: "127.0.0.1".parse().unwrap(), ttl: TransientTtl(300) },
+            DnsRecord::AAAA { domain: "example.com".to_string(), class: QueryClass::IN,
+                              addr: "2001:db8::1".parse().unwrap(), ttl: TransientTtl(300) },
+            DnsRecord::NS { domain: "example.com".to_string(), class: QueryClass::IN,
+                            host: "ns1.example.com".to_string(), ttl: TransientTtl(86400) },
+            DnsRecord::CNAME { domain: "www.example.com".to_string(), class: QueryClass::IN,
+                               host: "example.com".to_string(), ttl: TransientTtl(60) },
+            DnsRecord::MX { domain: "example.com".to_string(), class: QueryClass::IN,
+                            priority: 10, host: "mail.example.com".to_string(), ttl: TransientTtl(3600) },
+            DnsRecord::SRV { domain: "_sip._tcp.example.com".to_string(), class: QueryClass::IN,
+                             priority: 10, weight: 20, port: 5060, host: "sip.example.com".to_string(),
+                             ttl: TransientTtl(3600) },
+            DnsRecord::SOA { domain: "example.com".to_string(), class: QueryClass::IN,
+                             m_name: "ns1.example.com".to_string(), r_name: "admin.example.com".to_string(),
+                             serial: 2018010101, refresh: 3600, retry: 600, expire: 604800, minimum: 300,
+                             ttl: TransientTtl(3600) },
+            DnsRecord::TXT { domain: "example.com".to_string(), class: QueryClass::IN,
+                             data: vec![b"say \"hi\"".to_vec(), b"back\\slash\x01".to_vec(), Vec::new()],
+                             ttl: TransientTtl(300) },
+            DnsRecord::TXT { domain: "version.bind".to_string(), class: QueryClass::CH,
+                             data: vec![b"hermes".to_vec()], ttl: TransientTtl(0) },
+            DnsRecord::UNKNOWN { domain: "example.com".to_string(), class: QueryClass::IN,
+                                 qtype: 65280, data: vec![0x0a, 0x00, 0xff], ttl: TransientTtl(300) }
+        ];
+
+        // Everything that is written can be read back
+        for rec in &records {
+            let parsed = rec.to_string().parse::<DnsRecord>().unwrap();
+            assert_eq!(*rec, parsed);
+            assert_eq!(rec.get_ttl(), parsed.get_ttl());
+        }
+
+        // The class is optional, and may come before the TTL. Comments,
+        // parentheses and unquoted character-strings are allowed as well.
+        let rec = "example.com. 300 A 127.0.0.1 ; localhost".parse::<DnsRecord>().unwrap();
+        assert_eq!(records[0], rec);
+        let rec = "example.com. IN 300 A 127.0.0.1".parse::<DnsRecord>().unwrap();
+        assert_eq!(records[0], rec);
+        let rec = "example.com. 3600 IN SOA ns1.example.com. admin.example.com. ( 2018010101 ; serial\n\
+                   3600 600 604800 300 )".parse::<DnsRecord>().unwrap();
+        assert_eq!(records[6], rec);
+        let rec = "example.com. 300 TXT hello\\032world".parse::<DnsRecord>().unwrap();
+        assert_eq!(DnsRecord::TXT { domain: "example.com".to_string(), class: QueryClass::IN,
+                                    data: vec![b"hello world".to_vec()], ttl: TransientTtl(300) }, rec);
+
+        // Known types can be given as generic RDATA too
+        let rec = "example.com. 300 IN A \\# 4 7f000001".parse::<DnsRecord>().unwrap();
+        assert_eq!(records[0], rec);
+
+        let invalid = [
+            "",
+            "example.com.",
+            "example.com. 300 IN",
+            "example.com. IN A 127.0.0.1",
+            "example.com. 300 IN BOGUS 127.0.0.1",
+            "example.com. 300 IN A 127.0.0.256",
+            "example.com. 300 IN A 127.0.0.1 127.0.0.2",
+            "example.com. 300 IN MX mail.example.com.",
+            "example.com. 300 IN TXT \"unterminated",
+            "example.com. 300 IN TXT \"\\256\"",
+            "example.com. 300 IN TYPE65280 0a00ff",
+            "example.com. 300 IN A \\# 4 7f00"
+        ];
+        for text in invalid.iter() {
+            assert!(text.parse::<DnsRecord>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_empty_rdata() {
+        // A delete from a dynamic update, with class ANY and no RDATA
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_qname("www.example.com").unwrap();
+        buffer.write_u16(QueryType::A.to_num()).unwrap();
+        buffer.write_u16(QueryClass::ANY.to_num()).unwrap();
+        buffer.write_u32(0).unwrap();
+        buffer.write_u16(0).unwrap();
+        let len = buffer.pos();
+
+        buffer.seek(0).unwrap();
+        let record = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(len, buffer.pos());
+        assert_eq!(QueryType::A, record.get_querytype());
+        assert_eq!(QueryClass::ANY, record.get_class());
+
+        let mut buffer2 = VectorPacketBuffer::new();
+        record.write(&mut buffer2).unwrap();
+        assert_eq!(buffer.buffer, buffer2.buffer);
+
+        // Records of known types otherwise need their RDATA, while records of
+        // unknown types can do without
+        let types = [QueryType::A, QueryType::AAAA, QueryType::MX, QueryType::TXT,
+                     QueryType::SOA, QueryType::UNKNOWN(65280)];
+        for qtype in types.iter() {
+            let mut buffer = VectorPacketBuffer::new();
+            buffer.write_qname("www.example.com").unwrap();
+            buffer.write_u16(qtype.to_num()).unwrap();
+            buffer.write_u16(QueryClass::IN.to_num()).unwrap();
+            buffer.write_u32(3600).unwrap();
+            buffer.write_u16(0).unwrap();
+
+            buffer.seek(0).unwrap();
+            match DnsRecord::read(&mut buffer) {
+                Ok(DnsRecord::UNKNOWN { qtype: 65280, ref data, .. }) => assert!(data.is_empty()),
+                Ok(record) => panic!("{:?}", record),
+                Err(err) => assert_eq!(Some(&WireError::EmptyRdata(qtype.to_num())),
+                                       WireError::from_io(&err))
+            }
+        }
+    }
+
+    #[test]
+    fn test_rdata_length_mismatch() {
+        // An A record claiming 6 bytes of RDATA
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_qname("example.com").unwrap();
+        buffer.write_u16(QueryType::A.to_num()).unwrap();
+        buffer.write_u16(QueryClass::IN.to_num()).unwrap();
+        buffer.write_u32(3600).unwrap();
+        buffer.write_u16(6).unwrap();
+        buffer.write_u32(0x7F000001).unwrap();
+        buffer.write_u16(0).unwrap();
+
+        buffer.seek(0).unwrap();
+        let err = DnsRecord::read(&mut buffer).unwrap_err();
+        assert_eq!(Some(&WireError::RdataLength { qtype: 1, expected: 6, consumed: 4 }),
+                   WireError::from_io(&err));
+
+        // A TXT string running past the end of the RDATA
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_qname("example.com").unwrap();
+        buffer.write_u16(QueryType::TXT.to_num()).unwrap();
+        buffer.write_u16(QueryClass::IN.to_num()).unwrap();
+        buffer.write_u32(3600).unwrap();
+        buffer.write_u16(3).unwrap();
+        buffer.write_u8(5).unwrap();
+        for b in b"hello" {
+            buffer.write_u8(*b).unwrap();
+        }
+
+        buffer.seek(0).unwrap();
+        let err = DnsRecord::read(&mut buffer).unwrap_err();
+        assert_eq!(Some(&WireError::RdataLength { qtype: 16, expected: 3, consumed: 6 }),
+                   WireError::from_io(&err));
+    }
+
+    mod roundtrip {
+
+        use proptest::prelude::*;
+        use proptest::collection::vec;
+
+        use super::super::*;
+        use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+
+        fn arb_name() -> BoxedStrategy<String> {
+            vec("[a-zA-Z0-9-]{1,12}", 0..5).prop_map(|labels| labels.join(".")).boxed()
+        }
+
+        fn arb_class() -> BoxedStrategy<QueryClass> {
+            prop_oneof![
+                Just(QueryClass::IN),
+                any::<u16>().prop_map(QueryClass::from_num)
+            ].boxed()
+        }
+
+        fn arb_record() -> BoxedStrategy<DnsRecord> {
+            let ttl = any::<u32>().prop_map(TransientTtl);
+
+            prop_oneof![
+                (arb_name(), arb_class(), any::<[u8; 4]>(), ttl.clone())
+                    .prop_map(|(domain, class, addr, ttl)| DnsRecord::A { domain, class, addr: addr.into(), ttl }),
+                (arb_name(), arb_class(), any::<[u8; 16]>(), ttl.clone())
+                    .prop_map(|(domain, class, addr, ttl)| DnsRecord::AAAA { domain, class, addr: addr.into(), ttl }),
+                (arb_name(), arb_class(), arb_name(), ttl.clone())
+                    .prop_map(|(domain, class, host, ttl)| DnsRecord::NS { domain, class, host, ttl }),
+                (arb_name(), arb_class(), arb_name(), ttl.clone())
+                    .prop_map(|(domain, class, host, ttl)| DnsRecord::CNAME { domain, class, host, ttl }),
+                (arb_name(), arb_class(), any::<u16>(), arb_name(), ttl.clone())
+                    .prop_map(|(domain, class, priority, host, ttl)| DnsRecord::MX { domain, class, priority, host, ttl }),
+                (arb_name(), arb_class(), any::<(u16, u16, u16)>(), arb_name(), ttl.clone())
+                    .prop_map(|(domain, class, (priority, weight, port), host, ttl)| {
+                        DnsRecord::SRV { domain, class, priority, weight, port, host, ttl }
+                    }),
+                (arb_name(), arb_class(), arb_name(), arb_name(), any::<[u32; 5]>(), ttl.clone())
+                    .prop_map(|(domain, class, m_name, r_name, times, ttl)| DnsRecord::SOA {
+                        domain, class, m_name, r_name,
+                        serial: times[0], refresh: times[1], retry: times[2], expire: times[3], minimum: times[4],
+                        ttl
+                    }),
+                (arb_name(), arb_class(), vec(vec(any::<u8>(), 0..256), 1..4), ttl.clone())
+                    .prop_map(|(domain, class, data, ttl)| DnsRecord::TXT { domain, class, data, ttl }),
+                (arb_name(), arb_class(), 256u16..65280, vec(any::<u8>(), 0..64), ttl.clone())
+                    .prop_map(|(domain, class, qtype, data, ttl)| DnsRecord::UNKNOWN { domain, class, qtype, data, ttl }),
+                (any::<u16>(), any::<u32>(), vec(any::<u8>(), 0..64))
+                    .prop_map(|(packet_len, flags, data)| DnsRecord::OPT { packet_len, flags, data })
+            ].boxed()
+        }
+
+        fn arb_question() -> BoxedStrategy<DnsQuestion> {
+            (arb_name(), any::<u16>(), arb_class())
+                .prop_map(|(name, qtype, qclass)| DnsQuestion::with_class(name, QueryType::from_num(qtype), qclass))
+                .boxed()
+        }
+
+        fn arb_packet() -> BoxedStrategy<DnsPacket> {
+            (any::<u16>(), 0u8..16, 0u8..11, any::<[bool; 8]>(),
+             vec(arb_question(), 0..3), vec(arb_record(), 0..4), vec(arb_record(), 0..4), vec(arb_record(), 0..4))
+                .prop_map(|(id, opcode, res_code, flags, questions, answers, authorities, resources)| {
+                    let mut packet = DnsPacket::new();
+                    packet.header.id = id;
+                    packet.header.opcode = Opcode::from_num(opcode);
+                    packet.header.res_code = ResultCode::from_num(res_code);
+                    packet.header.recursion_desired = flags[0];
+                    packet.header.truncated_message = flags[1];
+                    packet.header.authoritative_answer = flags[2];
+                    packet.header.response = flags[3];
+                    packet.header.checking_disabled = flags[4];
+                    packet.header.authed_data = flags[5];
+                    packet.header.z = flags[6];
+                    packet.header.recursion_available = flags[7];
+                    packet.questions = questions;
+                    packet.answers = answers;
+                    packet.authorities = authorities;
+                    packet.resources = resources;
+                    packet
+                })
+                .boxed()
+        }
+
+        fn assert_same_records(expected: &[DnsRecord], actual: &[DnsRecord]) {
+            // Records are written grouped by RRset, so compare in that order
+            let expected = group_rrsets(expected).into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<DnsRecord>>();
+            assert_eq!(expected, actual);
+
+            // Equality of records disregards the ttl, so check it separately
+            for (a, b) in expected.iter().zip(actual) {
+                assert_eq!(a.get_ttl(), b.get_ttl());
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn test_packet_roundtrip(packet in arb_packet()) {
+                let mut packet = packet;
+                let mut buffer = VectorPacketBuffer::new();
+                packet.write(&mut buffer, 0xFFFF).unwrap();
+
+                buffer.seek(0).unwrap();
+                let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+                assert_eq!(buffer.buffer.len(), buffer.pos());
+
+                assert_eq!(packet.header.id, parsed.header.id);
+                assert_eq!(packet.header.opcode, parsed.header.opcode);
+                assert_eq!(packet.header.res_code, parsed.header.res_code);
+                assert_eq!(packet.header.recursion_desired, parsed.header.recursion_desired);
+                assert_eq!(packet.header.truncated_message, parsed.header.truncated_message);
+                assert_eq!(packet.header.authoritative_answer, parsed.header.authoritative_answer);
+                assert_eq!(packet.header.response, parsed.header.response);
+                assert_eq!(packet.header.checking_disabled, parsed.header.checking_disabled);
+                assert_eq!(packet.header.authed_data, parsed.header.authed_data);
+                assert_eq!(packet.header.z, parsed.header.z);
+                assert_eq!(packet.header.recursion_available, parsed.header.recursion_available);
+
+                assert_eq!(packet.questions, parsed.questions);
+                assert_same_records(&packet.answers, &parsed.answers);
+                assert_same_records(&packet.authorities, &parsed.authorities);
+                assert_same_records(&packet.resources, &parsed.resources);
+            }
+
+            #[test]
+            fn test_parse_arbitrary_data(data in vec(any::<u8>(), 0..600)) {
+                // Arbitrary input may fail to parse, but must never panic or hang
+                let mut buffer = VectorPacketBuffer::new();
+                buffer.buffer = data;
+                let _ = DnsPacket::from_buffer(&mut buffer);
+            }
+
+            #[test]
+            fn test_parse_corrupted_packet(packet in arb_packet(),
+                                           corruptions in vec((any::<prop::sample::Index>(), any::<u8>()), 1..8)) {
+                let mut packet = packet;
+                let mut buffer = VectorPacketBuffer::new();
+                packet.write(&mut buffer, 0xFFFF).unwrap();
+
+                for (idx, val) in corruptions {
+                    let pos = idx.index(buffer.buffer.len());
+                    buffer.buffer[pos] = val;
+                }
+
+                buffer.seek(0).unwrap();
+                let _ = DnsPacket::from_buffer(&mut buffer);
+            }
+        }
+    }
 }
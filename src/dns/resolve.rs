@@ -5,8 +5,11 @@ use std::vec::Vec;
 use std::io::{Error, ErrorKind};
 use std::sync::Arc;
 
-use dns::protocol::{QueryType, DnsPacket, ResultCode};
+use dns::protocol::{QueryType, DnsPacket, DnsRecord, ResultCode, in_bailiwick};
+use dns::cache::Credibility;
 use dns::context::ServerContext;
+use dns::prefetch::prefetch_if_needed;
+use dns::upstream::{Upstream, UpstreamPool};
 use dns::utils::current_thread_name;
 use std::collections::HashSet;
 
@@ -16,14 +19,12 @@ pub trait DnsResolver {
 
     fn resolve(&mut self, qname: &str, qtype: QueryType, recursive: bool) -> Result<DnsPacket> {
 
-        if let QueryType::UNKNOWN(_) = qtype {
-            let mut packet = DnsPacket::new();
-            packet.header.res_code = ResultCode::NOTIMP;
-            return Ok(packet);
-        }
-
         let context = self.get_context();
 
+        // Resolution is case insensitive. The case of the question is
+        // preserved in the reply by the server, rather than here.
+        let qname = &qname.to_lowercase();
+
         if let Some(qr) = context.authority.query(qname, qtype) {
             return Ok(qr);
         }
@@ -35,34 +36,110 @@ pub trait DnsResolver {
         }
 
         if let Some(qr) = context.cache.lookup(qname, qtype) {
+            prefetch_if_needed(&context, qname, qtype);
             return Ok(qr);
         }
 
         if qtype == QueryType::A || qtype == QueryType::AAAA {
             if let Some(qr) = context.cache.lookup(qname, QueryType::CNAME) {
+                prefetch_if_needed(&context, qname, QueryType::CNAME);
                 return Ok(qr);
             }
         }
 
-        self.perform(qname, qtype)
+        // Identical queries arriving while this one is resolved share its
+        // result, rather than each being sent upstream
+        context.inflight.resolve(qname, qtype, || self.perform(qname, qtype))
     }
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
 }
 
+/// Cache a negative answer (RFC 2308), returning whether the answer was one
+///
+/// An NXDOMAIN covers every type of the name, while NODATA, an empty answer
+/// with the SOA of the zone in the authority section, only covers the type
+/// asked for. Negative answers without an SOA carry no TTL, and are never
+/// cached.
+fn store_negative(context: &ServerContext, qname: &str, qtype: QueryType, response: &DnsPacket) -> bool {
+    let nxdomain = response.header.res_code == ResultCode::NXDOMAIN;
+    let nodata = response.header.res_code == ResultCode::NOERROR &&
+        response.answers.is_empty() &&
+        response.get_soa().is_some();
+
+    if !nxdomain && !nodata {
+        return false;
+    }
+
+    // An NXDOMAIN following a CNAME is about the target of the alias, rather
+    // than the name asked for
+    if !response.answers.is_empty() {
+        let _ = context.cache.store_with_credibility(&response.answers, Credibility::of_answer(response));
+        return true;
+    }
+
+    if let Some(ttl) = response.get_ttl_from_soa() {
+        let soa = response.get_soa().cloned();
+        if nxdomain {
+            let _ = context.cache.store_nxdomain(qname, soa, ttl);
+        } else {
+            let _ = context.cache.store_nodata(qname, qtype, soa, ttl);
+        }
+    }
+
+    true
+}
+
+/// Drop the records of a response which lie outside the zone delegated to
+/// the server that sent it
+///
+/// A server can only speak for its own zone, so anything else it includes,
+/// such as glue for names in other zones, could be an attempt to poison the
+/// cache. Whatever is dropped is logged.
+fn filter_bailiwick(response: &mut DnsPacket, zone: &str, ns: &str) {
+    let sections = vec![&mut response.answers, &mut response.authorities, &mut response.resources];
+    for section in sections {
+        section.retain(|rec: &DnsRecord| {
+            // Records without a name, such as OPT, are about the message
+            // itself
+            let domain = match rec.get_domain() {
+                Some(x) => x,
+                None => return true
+            };
+
+            if in_bailiwick(&domain, zone) {
+                return true;
+            }
+
+            println!("{}: ignoring out of bailiwick record {:?} from ns {} for zone {}",
+                     current_thread_name(), rec, ns, zone);
+
+            false
+        });
+    }
+}
+
+/// Cache every section of a response, ranked by the section it's in
+fn store_response(context: &ServerContext, response: &DnsPacket) {
+    let _ = context.cache.store_with_credibility(&response.answers, Credibility::of_answer(response));
+    let _ = context.cache.store_with_credibility(&response.authorities, Credibility::Authority);
+    let _ = context.cache.store_with_credibility(&response.resources, Credibility::Additional);
+}
+
 /// A Forwarding DNS Resolver
 ///
-/// This resolver uses an external DNS server to service a query
+/// This resolver uses external DNS servers to service a query, picking
+/// between them as described by `UpstreamPool`
 pub struct ForwardingDnsResolver {
     context: Arc<ServerContext>,
-    server: (String, u16)
+    upstreams: Arc<UpstreamPool>
 }
 
 impl ForwardingDnsResolver {
-    pub fn new(context: Arc<ServerContext>, server: (String, u16)) -> ForwardingDnsResolver {
+    pub fn new(context: Arc<ServerContext>, upstreams: Arc<UpstreamPool>) -> ForwardingDnsResolver {
         ForwardingDnsResolver {
-            context: context,
-            server: server
+            context,
+            upstreams
         }
     }
 }
@@ -74,20 +151,75 @@ impl DnsResolver for ForwardingDnsResolver {
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
 
-        let &(ref host, port) = &self.server;
-        let result = self.context.client.send_query(qname,
-                                                    qtype,
-                                                    (host.as_str(), port),
-                                                    true);
+        let client = &*self.context.client;
+        let result = self.upstreams.query(|upstream| client.send_query(qname, qtype, upstream, true));
 
         if let Ok(ref qr) = result {
-            let _ = self.context.cache.store(&qr.answers);
+            if !store_negative(&self.context, qname, qtype, qr) {
+                let _ = self.context.cache.store_with_credibility(&qr.answers, Credibility::of_answer(qr));
+            }
         }
 
         result
     }
 }
 
+/// A resolver for names which are only answered from the local zones
+///
+/// Those are looked up before resolving, so anything reaching this resolver
+/// is refused.
+pub struct AuthorityDnsResolver {
+    context: Arc<ServerContext>
+}
+
+impl AuthorityDnsResolver {
+    pub fn new(context: Arc<ServerContext>) -> AuthorityDnsResolver {
+        AuthorityDnsResolver {
+            context
+        }
+    }
+}
+
+impl DnsResolver for AuthorityDnsResolver {
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.context.clone()
+    }
+
+    fn perform(&mut self, _: &str, _: QueryType) -> Result<DnsPacket> {
+        let mut packet = DnsPacket::new();
+        packet.header.res_code = ResultCode::REFUSED;
+
+        Ok(packet)
+    }
+}
+
+/// A resolver picking the strategy for every name it resolves from the
+/// routes of the context, see `ServerContext::get_resolve_strategy`
+pub struct RoutingDnsResolver {
+    context: Arc<ServerContext>
+}
+
+impl RoutingDnsResolver {
+    pub fn new(context: Arc<ServerContext>) -> RoutingDnsResolver {
+        RoutingDnsResolver {
+            context
+        }
+    }
+}
+
+impl DnsResolver for RoutingDnsResolver {
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.context.clone()
+    }
+
+    fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let strategy = self.context.get_resolve_strategy(qname);
+        let mut resolver = self.context.create_resolver_for(self.context.clone(), strategy);
+
+        resolver.perform(qname, qtype)
+    }
+}
+
 /// A Recursive DNS resolver
 ///
 /// This resolver can answer any request using the root servers of the internet
@@ -115,6 +247,10 @@ impl DnsResolver for RecursiveDnsResolver {
         // and finally "".
         let mut tentative_ns = None;
 
+        // The zone delegated to the name server, which it's trusted to answer
+        // for
+        let mut zone = String::new();
+
         let labels = qname.split('.').collect::<Vec<&str>>();
         for lbl_idx in 0..labels.len()+1 {
             let mut domain = labels[lbl_idx..].join(".");
@@ -130,6 +266,7 @@ impl DnsResolver for RecursiveDnsResolver {
 
                 Some(addr) => {
                     tentative_ns = Some(addr);
+                    zone = domain;
                     break;
                 },
                 None => continue
@@ -146,22 +283,17 @@ impl DnsResolver for RecursiveDnsResolver {
         loop {
             println!("{}: attempting to lookup {:?} {} with ns {}", current_thread_name(), qtype, qname, ns);
 
-            let ns_copy = ns.clone();
-            let server = (ns_copy.as_str(), 53);
-            let response = self.context.client.send_query(qname, qtype.clone(), server, false)?;
+            let server = Upstream::new(&ns, 53);
+            let mut response = self.context.client.send_query(qname, qtype, &server, false)?;
+            filter_bailiwick(&mut response, &zone, &ns);
 
             // If we've got an actual answer, we're done!
             if !response.answers.is_empty() && response.header.res_code == ResultCode::NOERROR {
-                let _ = self.context.cache.store(&response.answers);
-                let _ = self.context.cache.store(&response.authorities);
-                let _ = self.context.cache.store(&response.resources);
+                store_response(&self.context, &response);
                 return Ok(response.clone());
             }
 
-            if response.header.res_code == ResultCode::NXDOMAIN {
-                if let Some(ttl) = response.get_ttl_from_soa() {
-                    let _ = self.context.cache.store_nxdomain(qname, qtype, ttl);
-                }
+            if store_negative(&self.context, qname, qtype, &response) {
                 return Ok(response.clone());
             }
 
@@ -174,12 +306,10 @@ impl DnsResolver for RecursiveDnsResolver {
             if let Some(new_ns) = response.get_resolved_ns(qname) {
                 // If there is such a record, we can retry the loop with that NS
                 ns = new_ns.clone();
-                let _ = self.context.cache.store(&response.answers);
-                let _ = self.context.cache.store(&response.authorities);
-                let _ = self.context.cache.store(&response.resources);
-                let zone = response.get_ns_zone();
+                store_response(&self.context, &response);
+                zone = response.get_ns_zone();
                 if !zone.is_empty() {
-                    zones.insert(zone);
+                    zones.insert(zone.clone());
                 }
                 continue;
             }
@@ -195,14 +325,15 @@ impl DnsResolver for RecursiveDnsResolver {
             // Recursively resolve the NS
             let recursive_response = self.resolve(&new_ns_name, QueryType::A, true)?;
 
-            let zone = recursive_response.get_ns_zone();
-            if !zone.is_empty() {
-                zones.insert(zone);
+            let ns_zone = recursive_response.get_ns_zone();
+            if !ns_zone.is_empty() {
+                zones.insert(ns_zone);
             }
 
             // Pick a random IP and restart
             if let Some(new_ns) = recursive_response.get_random_a() {
                 ns = new_ns.clone();
+                zone = response.get_ns_zone();
             } else {
                 return Ok(response.clone())
             }
@@ -214,12 +345,14 @@ impl DnsResolver for RecursiveDnsResolver {
 mod tests {
 
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    use dns::protocol::{DnsPacket, QueryType, DnsRecord, ResultCode, TransientTtl};
+    use dns::protocol::{DnsPacket, QueryClass, QueryType, DnsRecord, ResultCode, TransientTtl};
 
     use super::*;
 
     use dns::context::ResolveStrategy;
+    use dns::upstream::{Upstream, UpstreamStrategy};
     use dns::context::tests::create_test_context;
 
     #[test]
@@ -231,6 +364,7 @@ mod tests {
                 if qname == "google.com" {
                     packet.answers.push(DnsRecord::A {
                         domain: "google.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse().unwrap(),
                         ttl: TransientTtl(3600)
                     });
@@ -244,8 +378,7 @@ mod tests {
         match Arc::get_mut(&mut context) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                        host: "127.0.0.1".to_string(),
-                        port: 53
+                        upstreams: UpstreamPool::single("127.0.0.1", 53)
                     };
             },
             None => panic!()
@@ -289,7 +422,7 @@ mod tests {
 
             assert_eq!("google.com", list[0].domain);
             assert_eq!(1, list[0].record_types.len());
-            assert_eq!(1, list[0].hits);
+            assert_eq!(1, list[0].get_hits());
 
         };
 
@@ -306,6 +439,115 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_forwarding_resolver_failover() {
+        let mut context = create_test_context(
+            Box::new(|qname, _, (server, _), _| {
+                if server == "127.0.0.1" {
+                    return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+                }
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    class: QueryClass::IN,
+                    addr: server.parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        let upstreams = Arc::new(UpstreamPool::new(vec![Upstream::new("127.0.0.1", 53),
+                                                        Upstream::new("127.0.0.2", 5353)],
+                                                   UpstreamStrategy::Failover));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        upstreams: upstreams.clone()
+                    };
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // The first server times out, so the answer comes from the second
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        match res.answers[0] {
+            DnsRecord::A { ref addr, .. } => assert_eq!("127.0.0.2", addr.to_string()),
+            _ => panic!()
+        }
+
+        // And after timing out repeatedly, it's marked down
+        let _ = resolver.resolve("yahoo.com", QueryType::A, true);
+        let _ = resolver.resolve("bing.com", QueryType::A, true);
+        assert!(upstreams.is_down(0));
+        assert!(!upstreams.is_down(1));
+    }
+
+    #[test]
+    fn test_routing_resolver() {
+        let mut context = create_test_context(
+            Box::new(|qname, _, (server, port), recursive| {
+                let mut packet = DnsPacket::new();
+
+                // Only the forwarders are asked to recurse
+                if recursive {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        class: QueryClass::IN,
+                        addr: server.parse().unwrap(),
+                        ttl: TransientTtl(port as u32)
+                    });
+                } else {
+                    packet.header.res_code = ResultCode::NXDOMAIN;
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.add_route("*.corp.example", ResolveStrategy::parse("127.0.0.2", UpstreamStrategy::Failover).unwrap());
+                ctx.add_route("consul.", ResolveStrategy::parse("127.0.0.3:8600", UpstreamStrategy::Failover).unwrap());
+                ctx.add_route("lan", ResolveStrategy::Authority);
+                ctx.add_route("public.corp.example", ResolveStrategy::Recursive);
+            },
+            None => panic!()
+        }
+
+        let forwarded_to = |qname: &str| {
+            let mut resolver = context.create_resolver(context.clone());
+            let res = resolver.resolve(qname, QueryType::A, true).ok()?;
+            match res.answers.first() {
+                Some(&DnsRecord::A { ref addr, ttl: TransientTtl(port), .. }) => Some(format!("{}:{}", addr, port)),
+                _ => None
+            }
+        };
+
+        assert_eq!(Some("127.0.0.2:53".to_string()), forwarded_to("www.corp.example"));
+        assert_eq!(Some("127.0.0.2:53".to_string()), forwarded_to("CORP.example"));
+        assert_eq!(Some("127.0.0.3:8600".to_string()), forwarded_to("web.service.consul"));
+
+        // Everything else is resolved recursively, which fails without any
+        // name servers
+        assert_eq!(None, forwarded_to("notconsul"));
+
+        // The longest matching suffix wins
+        assert!(match *context.get_resolve_strategy("www.public.corp.example") {
+            ResolveStrategy::Recursive => true,
+            _ => false
+        });
+
+        // Names which are only answered from the local zones are refused
+        let mut resolver = context.create_resolver(context.clone());
+        let res = resolver.resolve("printer.lan", QueryType::A, true).unwrap();
+        assert_eq!(ResultCode::REFUSED, res.header.res_code);
+    }
+
     #[test]
     fn test_recursive_resolver_with_no_nameserver() {
         let context = create_test_context(
@@ -343,6 +585,7 @@ mod tests {
         let mut nameservers = Vec::new();
         nameservers.push(DnsRecord::NS {
             domain: "".to_string(),
+            class: QueryClass::IN,
             host: "a.myroot.net".to_string(),
             ttl: TransientTtl(3600)
         });
@@ -365,6 +608,7 @@ mod tests {
 
                     packet.answers.push(DnsRecord::A {
                         domain: "a.google.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse().unwrap(),
                         ttl: TransientTtl(3600)
                     });
@@ -376,6 +620,7 @@ mod tests {
 
                     packet.answers.push(DnsRecord::A {
                         domain: "b.google.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse().unwrap(),
                         ttl: TransientTtl(3600)
                     });
@@ -387,6 +632,7 @@ mod tests {
 
                     packet.answers.push(DnsRecord::A {
                         domain: "c.google.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse().unwrap(),
                         ttl: TransientTtl(3600)
                     });
@@ -411,11 +657,13 @@ mod tests {
             let mut nameservers = Vec::new();
             nameservers.push(DnsRecord::NS {
                 domain: "".to_string(),
+                class: QueryClass::IN,
                 host: "a.myroot.net".to_string(),
                 ttl: TransientTtl(3600)
             });
             nameservers.push(DnsRecord::A {
                 domain: "a.myroot.net".to_string(),
+                class: QueryClass::IN,
                 addr: "127.0.0.1".parse().unwrap(),
                 ttl: TransientTtl(3600)
             });
@@ -435,11 +683,13 @@ mod tests {
             let mut nameservers = Vec::new();
             nameservers.push(DnsRecord::NS {
                 domain: "com".to_string(),
+                class: QueryClass::IN,
                 host: "a.mytld.net".to_string(),
                 ttl: TransientTtl(3600)
             });
             nameservers.push(DnsRecord::A {
                 domain: "a.mytld.net".to_string(),
+                class: QueryClass::IN,
                 addr: "127.0.0.2".parse().unwrap(),
                 ttl: TransientTtl(3600)
             });
@@ -459,11 +709,13 @@ mod tests {
             let mut nameservers = Vec::new();
             nameservers.push(DnsRecord::NS {
                 domain: "google.com".to_string(),
+                class: QueryClass::IN,
                 host: "ns1.google.com".to_string(),
                 ttl: TransientTtl(3600)
             });
             nameservers.push(DnsRecord::A {
                 domain: "ns1.google.com".to_string(),
+                class: QueryClass::IN,
                 addr: "127.0.0.3".parse().unwrap(),
                 ttl: TransientTtl(3600)
             });
@@ -488,6 +740,7 @@ mod tests {
                 if qname == "google.com" {
                     packet.answers.push(DnsRecord::A {
                         domain: "google.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse().unwrap(),
                         ttl: TransientTtl(3600)
                     });
@@ -496,6 +749,7 @@ mod tests {
 
                     packet.authorities.push(DnsRecord::SOA {
                         domain: "google.com".to_string(),
+                        class: QueryClass::IN,
                         r_name: "google.com".to_string(),
                         m_name: "google.com".to_string(),
                         serial: 0,
@@ -516,11 +770,13 @@ mod tests {
         let mut nameservers = Vec::new();
         nameservers.push(DnsRecord::NS {
             domain: "google.com".to_string(),
+            class: QueryClass::IN,
             host: "ns1.google.com".to_string(),
             ttl: TransientTtl(3600)
         });
         nameservers.push(DnsRecord::A {
             domain: "ns1.google.com".to_string(),
+            class: QueryClass::IN,
             addr: "127.0.0.1".parse().unwrap(),
             ttl: TransientTtl(3600)
         });
@@ -588,12 +844,299 @@ mod tests {
 
             // Should have been hit two times for NS google.com and once for
             // A google.com
-            assert_eq!(3, list[1].hits);
+            assert_eq!(3, list[1].get_hits());
 
             assert_eq!("ns1.google.com", list[2].domain);
             assert_eq!(1, list[2].record_types.len());
-            assert_eq!(2, list[2].hits);
+            assert_eq!(2, list[2].get_hits());
         };
     }
-}
 
+    #[test]
+    fn test_in_bailiwick() {
+        assert!(in_bailiwick("google.com", "google.com"));
+        assert!(in_bailiwick("ns1.Google.com", "google.com."));
+        assert!(in_bailiwick("google.com", "com"));
+        assert!(in_bailiwick("google.com", "."));
+        assert!(in_bailiwick("google.com", ""));
+        assert!(!in_bailiwick("evilgoogle.com", "google.com"));
+        assert!(!in_bailiwick("com", "google.com"));
+        assert!(!in_bailiwick("bank.com", "evil.com"));
+    }
+
+    #[test]
+    fn test_recursive_resolver_bailiwick() {
+        let context = create_test_context(
+            Box::new(|qname, _, (server, _), _| {
+                let mut packet = DnsPacket::new();
+
+                // The server for com refers to the servers of evil.com
+                if server == "127.0.0.1" {
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "evil.com".to_string(),
+                        class: QueryClass::IN,
+                        host: "ns1.evil.com".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                    packet.resources.push(DnsRecord::A {
+                        domain: "ns1.evil.com".to_string(),
+                        class: QueryClass::IN,
+                        addr: "127.0.0.2".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+
+                    return Ok(packet);
+                }
+
+                // The servers of evil.com answer, adding bogus records for
+                // bank.com along with their own
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.3".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                packet.authorities.push(DnsRecord::NS {
+                    domain: "bank.com".to_string(),
+                    class: QueryClass::IN,
+                    host: "ns1.evil.com".to_string(),
+                    ttl: TransientTtl(3600)
+                });
+                packet.resources.push(DnsRecord::A {
+                    domain: "www.bank.com".to_string(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.66".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                packet.resources.push(DnsRecord::A {
+                    domain: "www.evil.com".to_string(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.4".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        let _ = context.cache.store(&[
+            DnsRecord::NS {
+                domain: "com".to_string(),
+                class: QueryClass::IN,
+                host: "a.gtld-servers.net".to_string(),
+                ttl: TransientTtl(3600)
+            },
+            DnsRecord::A {
+                domain: "a.gtld-servers.net".to_string(),
+                class: QueryClass::IN,
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600)
+            }
+        ]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        let res = resolver.resolve("evil.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert!(res.resources.iter().all(|rec| rec.get_domain() != Some("www.bank.com".to_string())));
+
+        // Only what each server was authoritative for made it into the cache
+        assert!(context.cache.lookup("evil.com", QueryType::A).is_some());
+        assert!(context.cache.lookup("evil.com", QueryType::NS).is_some());
+        assert!(context.cache.lookup("ns1.evil.com", QueryType::A).is_some());
+        assert!(context.cache.lookup("www.evil.com", QueryType::A).is_some());
+        assert!(context.cache.lookup("bank.com", QueryType::NS).is_none());
+        assert!(context.cache.lookup("www.bank.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn test_recursive_resolver_negative_caching() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let queries_clone = queries.clone();
+
+        let context = create_test_context(
+            Box::new(move |qname, qtype, _, _| {
+                queries_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+
+                if qname == "google.com" && qtype == QueryType::A {
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        class: QueryClass::IN,
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                    return Ok(packet);
+                }
+
+                if qname != "google.com" {
+                    packet.header.res_code = ResultCode::NXDOMAIN;
+                }
+
+                packet.authorities.push(DnsRecord::SOA {
+                    domain: "google.com".to_string(),
+                    class: QueryClass::IN,
+                    r_name: "google.com".to_string(),
+                    m_name: "google.com".to_string(),
+                    serial: 0,
+                    refresh: 3600,
+                    retry: 3600,
+                    expire: 3600,
+                    minimum: 300,
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        let _ = context.cache.store(&[
+            DnsRecord::NS {
+                domain: "google.com".to_string(),
+                class: QueryClass::IN,
+                host: "ns1.google.com".to_string(),
+                ttl: TransientTtl(3600)
+            },
+            DnsRecord::A {
+                domain: "ns1.google.com".to_string(),
+                class: QueryClass::IN,
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600)
+            }
+        ]);
+
+        // A name without records of the type is NODATA, which is cached for
+        // that type only, along with the SOA
+        for _ in 0..2 {
+            let res = resolver.resolve("google.com", QueryType::AAAA, true).unwrap();
+            assert_eq!(ResultCode::NOERROR, res.header.res_code);
+            assert_eq!(0, res.answers.len());
+            assert_eq!(Some(QueryType::SOA), res.get_soa().map(|x| x.get_querytype()));
+        }
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert_eq!(2, queries.load(Ordering::SeqCst));
+
+        // An NXDOMAIN covers every type of the name, and its TTL is the
+        // minimum field of the SOA
+        let res = resolver.resolve("foobar.google.com", QueryType::A, true).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, res.header.res_code);
+        assert_eq!(3, queries.load(Ordering::SeqCst));
+
+        let res = resolver.resolve("foobar.google.com", QueryType::MX, true).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, res.header.res_code);
+        let ttl = res.get_soa().unwrap().get_ttl();
+        assert!(ttl <= 300 && ttl >= 299);
+        assert_eq!(3, queries.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_recursive_resolver_after_flush() {
+        let context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: ".".to_string(),
+                class: QueryClass::IN,
+                host: "a.myroot.net".to_string(),
+                ttl: TransientTtl(3600)
+            },
+            DnsRecord::A {
+                domain: "a.myroot.net".to_string(),
+                class: QueryClass::IN,
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600)
+            }
+        ]).unwrap();
+
+        let mut resolver = context.create_resolver(context.clone());
+        assert_eq!(1, resolver.resolve("google.com", QueryType::A, true).unwrap().answers.len());
+
+        // Flushing the cache keeps the root hints, so the resolver still has
+        // somewhere to start from
+        context.cache.flush().unwrap();
+        assert!(context.cache.lookup("google.com", QueryType::A).is_none());
+        assert_eq!(1, resolver.resolve("google.com", QueryType::A, true).unwrap().answers.len());
+    }
+
+    #[test]
+    fn test_recursive_resolver_mixed_case_referral() {
+        let context = create_test_context(
+            Box::new(|qname, _, (server, _), _| {
+                let mut packet = DnsPacket::new();
+
+                // The root refers to the TLD servers with the owner name in
+                // the case of the randomized question, as it would when
+                // compressing it as a pointer into the question
+                if server == "127.0.0.1" {
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "cOm".to_string(),
+                        class: QueryClass::IN,
+                        host: "A.gtld-SERVERS.net".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                    packet.resources.push(DnsRecord::A {
+                        domain: "a.GTLD-servers.net".to_string(),
+                        class: QueryClass::IN,
+                        addr: "127.0.0.2".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+
+                    return Ok(packet);
+                }
+
+                if server == "127.0.0.2" {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        class: QueryClass::IN,
+                        addr: "127.0.0.3".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+
+                    return Ok(packet);
+                }
+
+                packet.header.res_code = ResultCode::SERVFAIL;
+                Ok(packet)
+            }));
+
+        context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: ".".to_string(),
+                class: QueryClass::IN,
+                host: "a.myroot.net".to_string(),
+                ttl: TransientTtl(3600)
+            },
+            DnsRecord::A {
+                domain: "a.myroot.net".to_string(),
+                class: QueryClass::IN,
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600)
+            }
+        ]).unwrap();
+
+        let mut resolver = context.create_resolver(context.clone());
+        let res = resolver.resolve("www.google.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert_eq!(DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.3".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }, res.answers[0]);
+    }
+}
@@ -1,22 +1,27 @@
-//! UDP and TCP server implementations for DNS
+//! UDP, TCP and TLS server implementations for DNS
 
-use std::io::{Result,Write};
+use std::io::{Error,Read,Result,Write};
 use std::net::{UdpSocket, TcpListener, TcpStream, Shutdown};
 use std::sync::{Arc,Mutex,Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::thread::{Builder,sleep};
 use std::net::SocketAddr;
 use std::collections::VecDeque;
 
 use rand::random;
+use rustls::{ServerConfig, ServerConnection};
 
 use dns::resolve::DnsResolver;
-use dns::protocol::{DnsPacket, QueryType, DnsRecord, ResultCode};
-use dns::buffer::{PacketBuffer, BytePacketBuffer, VectorPacketBuffer, StreamPacketBuffer};
+use dns::protocol::{DnsPacket, DnsQuestion, Opcode, QueryClass, QueryType, DnsRecord, ResultCode, TransientTtl};
+use dns::buffer::{PacketBuffer, BytePacketBuffer, SlicePacketBuffer, VectorPacketBuffer, StreamPacketBuffer};
 use dns::context::ServerContext;
 use dns::netutil::{read_packet_length, write_packet_length};
 use dns::filter::DnsFilter;
+use dns::prefetch::prefetch_if_needed;
 use dns::utils::current_time_millis;
+use dns::tls::{ServerCertificate, server_config};
+use dns::view::DnsPacketRef;
 use std::time::Duration;
 use std::io::ErrorKind;
 
@@ -78,12 +83,197 @@ fn resolve_cnames(lookup_list: &[DnsRecord], results: &mut Vec<DnsPacket>, resol
     }
 }
 
-/// Perform the actual work for a query
+/// Answer a CHAOS class query for one of the server identification names
 ///
-/// Incoming requests are validated to make sure they are well formed and adhere
-/// to the server configuration. If so, the request will be passed on to the
-/// active resolver and a query will be performed. It will also resolve some
-/// possible references within the query, such as CNAME hosts.
+/// `version.bind`, `hostname.bind` and `id.server` are answered with the TXT
+/// values configured in the context. Names without a configured value, and
+/// any other CHAOS query, are refused.
+fn answer_chaos(context: &ServerContext, question: &DnsQuestion, packet: &mut DnsPacket)
+{
+    let value = match question.name.to_lowercase().as_str() {
+        "version.bind" => context.chaos_version.as_ref(),
+        "hostname.bind" => context.chaos_hostname.as_ref(),
+        "id.server" => context.chaos_id.as_ref(),
+        _ => None
+    };
+
+    match value {
+        Some(value) if question.qtype == QueryType::TXT => {
+            packet.header.authoritative_answer = true;
+            packet.answers.push(DnsRecord::TXT {
+                domain: question.name.clone(),
+                class: QueryClass::CH,
+                data: vec![value.as_bytes().to_vec()],
+                ttl: TransientTtl(0)
+            });
+        },
+        _ => {
+            packet.header.res_code = ResultCode::REFUSED;
+        }
+    }
+}
+
+/// Answer from expired cache entries when resolution has failed (RFC 8767)
+///
+/// Records that expired within the stale window of the context are returned
+/// with a short TTL. A refresh of the name is queued at the same time, so that
+/// the cache is brought up to date as soon as the upstream can be reached
+/// again.
+fn serve_stale(context: &Arc<ServerContext>, question: &DnsQuestion) -> Option<DnsPacket>
+{
+    if context.stale_window == 0 {
+        return None;
+    }
+
+    let mut result = context.cache.lookup_stale(&question.name, question.qtype,
+                                                context.stale_window, context.stale_ttl);
+    if result.is_none() && (question.qtype == QueryType::A || question.qtype == QueryType::AAAA) {
+        result = context.cache.lookup_stale(&question.name, QueryType::CNAME,
+                                            context.stale_window, context.stale_ttl);
+    }
+    let result = result?;
+
+    println!("Serving stale data for {:?} {}", question.qtype, question.name);
+    context.refresh_queue.enqueue(&question.name, question.qtype);
+
+    Some(result)
+}
+
+/// Resolve a standard query
+///
+/// Incoming queries are validated to make sure they adhere to the server
+/// configuration. If so, the query will be passed on to the active resolver.
+/// It will also resolve some possible references within the result, such as
+/// CNAME hosts.
+fn handle_query(context: &Arc<ServerContext>, request: &DnsPacket, packet: &mut DnsPacket)
+{
+    let question = &request.questions[0];
+
+    if question.qclass == QueryClass::CH {
+        answer_chaos(context, question, packet);
+        return;
+    }
+
+    if request.header.recursion_desired && !context.allow_recursive {
+        packet.header.res_code = ResultCode::REFUSED;
+        return;
+    }
+
+    if question.qclass != QueryClass::IN {
+        packet.header.res_code = ResultCode::NOTIMP;
+        return;
+    }
+
+    if context.filter.contains(&question.name) {
+        println!("Blocking domain {}, record {:?}", question.name, question.qtype);
+        DnsFilter::fill_blocked_response(packet);
+        return;
+    }
+
+    let mut results = Vec::new();
+
+    // The resolver, and the coalescing of inflight queries in particular,
+    // doesn't tell classes apart
+    debug_assert!(question.qclass == QueryClass::IN);
+
+    let mut resolver = context.create_resolver(context.clone());
+    let result = match resolver.resolve(&question.name,
+                                        question.qtype,
+                                        request.header.recursion_desired) {
+
+        Ok(result) => {
+            if result.header.res_code == ResultCode::SERVFAIL {
+                serve_stale(context, question).unwrap_or(result)
+            } else {
+                result
+            }
+        },
+        Err(err) => {
+            println!("Failed to resolve {:?} {}: {:?}", question.qtype, question.name, err);
+            match serve_stale(context, question) {
+                Some(result) => result,
+                None => {
+                    packet.header.res_code = ResultCode::SERVFAIL;
+                    return;
+                }
+            }
+        }
+    };
+
+    packet.header.res_code = result.header.res_code;
+
+    let unmatched = result.get_unresolved_cnames();
+    results.push(result);
+
+    resolve_cnames(&unmatched, &mut results, &mut resolver, 0);
+
+    for result in results {
+        for rec in result.answers {
+            packet.answers.push(rec);
+        }
+        for rec in result.authorities {
+            packet.authorities.push(rec);
+        }
+        for rec in result.resources {
+            packet.resources.push(rec);
+        }
+    }
+}
+
+/// Check whether the zone named in a NOTIFY or UPDATE is one we serve
+fn is_local_zone(context: &ServerContext, zone: &str) -> bool
+{
+    match context.authority.read() {
+        Ok(zones) => zones.get_zone(zone).is_some(),
+        Err(_) => false
+    }
+}
+
+/// Handle a zone change notification (RFC 1996)
+///
+/// We never act as a secondary, so there is no zone transfer to trigger. A
+/// notification for a zone we serve is still acknowledged, so that the primary
+/// stops retransmitting it, while other zones get NOTAUTH.
+fn handle_notify(context: &Arc<ServerContext>, request: &DnsPacket, packet: &mut DnsPacket)
+{
+    let question = &request.questions[0];
+
+    if !is_local_zone(context, &question.name) {
+        packet.header.res_code = ResultCode::NOTAUTH;
+        return;
+    }
+
+    println!("Received NOTIFY for zone {}", question.name);
+    packet.header.authoritative_answer = true;
+}
+
+/// Handle a dynamic update (RFC 2136)
+///
+/// There is no way to authenticate updates, so they are never applied. The
+/// zone section is still validated, so that a client can tell a zone we don't
+/// serve (NOTAUTH) from an update that was refused.
+fn handle_update(context: &Arc<ServerContext>, request: &DnsPacket, packet: &mut DnsPacket)
+{
+    let zone = &request.questions[0];
+
+    if zone.qtype != QueryType::SOA {
+        packet.header.res_code = ResultCode::FORMERR;
+    }
+    else if !is_local_zone(context, &zone.name) {
+        packet.header.res_code = ResultCode::NOTAUTH;
+    }
+    else {
+        println!("Refusing UPDATE for zone {}", zone.name);
+        packet.header.res_code = ResultCode::REFUSED;
+    }
+}
+
+/// Perform the actual work for a request
+///
+/// Requests are dispatched on their opcode. Standard queries are passed on to
+/// the resolver, NOTIFY and UPDATE have handlers of their own, and any other
+/// opcode is answered with NOTIMP. Each of the supported opcodes requires
+/// exactly one question, and requests without it get FORMERR.
 ///
 /// This function will always return a valid packet, even if the request could not
 /// be performed, since we still want to send something back to the client.
@@ -91,66 +281,99 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
 {
     let mut packet = DnsPacket::new();
     packet.header.id = request.header.id;
+    packet.header.opcode = request.header.opcode;
+    packet.header.recursion_desired = request.header.recursion_desired;
     packet.header.recursion_available = context.allow_recursive;
     packet.header.response = true;
+    packet.questions = request.questions.clone();
+
+    match request.header.opcode {
+        Opcode::QUERY | Opcode::NOTIFY | Opcode::UPDATE if request.questions.len() != 1 => {
+            packet.header.res_code = ResultCode::FORMERR;
+        },
+        Opcode::QUERY => handle_query(&context, request, &mut packet),
+        Opcode::NOTIFY => handle_notify(&context, request, &mut packet),
+        Opcode::UPDATE => handle_update(&context, request, &mut packet),
+        _ => {
+            packet.header.res_code = ResultCode::NOTIMP;
+        }
+    }
 
-    if request.header.recursion_desired && !context.allow_recursive {
-        packet.header.res_code = ResultCode::REFUSED;
+    packet
+}
+
+/// Answer a query without parsing the request into a `DnsPacket`
+///
+/// Blocked names and cache hits make up most of the traffic, and only need
+/// the question of the request. Anything else, including names in local
+/// zones, CNAME chains which need following and queries that would be
+/// refused, returns `None` and should go through `execute_query` instead.
+pub fn execute_fast_query(context: &ServerContext, request: &DnsPacketRef) -> Option<DnsPacket>
+{
+    if request.header.opcode != Opcode::QUERY || request.header.questions != 1 ||
+        !request.header.recursion_desired || !context.allow_recursive {
+        return None;
     }
-    else if request.questions.is_empty() {
-        packet.header.res_code = ResultCode::FORMERR;
+
+    let question = request.questions().next()?;
+    if question.qclass != QueryClass::IN {
+        return None;
     }
-    else {
-        let mut results = Vec::new();
 
-        let question = &request.questions[0];
-        packet.questions.push(question.clone());
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.opcode = request.header.opcode;
+    packet.header.recursion_desired = true;
+    packet.header.recursion_available = true;
+    packet.header.response = true;
+    packet.questions.push(question.to_question());
 
-        if context.filter.contains(&question.name) {
-            println!("Blocking domain {}, record {:?}", question.name, question.qtype);
-            DnsFilter::fill_blocked_response(&mut packet);
-            return packet;
-        }
+    let qname = &packet.questions[0].name;
 
-        let mut resolver = context.create_resolver(context.clone());
-        let res_code = match resolver.resolve(&question.name,
-                                             question.qtype,
-                                             request.header.recursion_desired) {
+    if context.filter.contains(qname) {
+        println!("Blocking domain {}, record {:?}", qname, question.qtype);
+        DnsFilter::fill_blocked_response(&mut packet);
+        return Some(packet);
+    }
 
-            Ok(result) => {
-                let res_code = result.header.res_code;
+    if context.authority.query(qname, question.qtype).is_some() {
+        return None;
+    }
 
-                let unmatched = result.get_unresolved_cnames();
-                results.push(result);
+    // Hosts referred to by CNAME and SRV records are resolved by
+    // `resolve_cnames` on the regular path
+    let result = context.cache.lookup(qname, question.qtype)?;
+    prefetch_if_needed(context, qname, question.qtype);
 
-                resolve_cnames(&unmatched, &mut results, &mut resolver, 0);
+    let needs_resolving = result.get_unresolved_cnames().iter()
+        .any(|rec| matches!(*rec, DnsRecord::CNAME { .. } | DnsRecord::SRV { .. }));
+    if needs_resolving {
+        return None;
+    }
 
-                res_code
-            },
-            Err(err) => {
-                println!("Failed to resolve {:?} {}: {:?}", question.qtype, question.name, err);
-                ResultCode::SERVFAIL
-            }
-        };
+    packet.header.res_code = result.header.res_code;
+    packet.answers = result.answers;
+    packet.authorities = result.authorities;
+    packet.resources = result.resources;
 
-        packet.header.res_code = res_code;
+    Some(packet)
+}
 
-        for result in results {
-            for rec in result.answers {
-                packet.answers.push(rec);
-            }
-            for rec in result.authorities {
-                packet.authorities.push(rec);
-            }
-            for rec in result.resources {
-                packet.resources.push(rec);
-            }
-        }
-    }
+/// Write a response of at most `size_limit` bytes, and send it to `src`
+fn send_udp_response(socket: &UdpSocket, src: SocketAddr, packet: &mut DnsPacket, size_limit: usize)
+{
+    let mut res_buffer = VectorPacketBuffer::new();
+    let _ = packet.write(&mut res_buffer, size_limit);
 
-    packet
+    let len = res_buffer.pos();
+    let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get buffer data");
+    ignore_or_report!(socket.send_to(data, src), "Failed to send response packet");
 }
 
+/// A query received over UDP, waiting for a worker thread: the client, the
+/// raw query and the time it was received
+type UdpRequest = (SocketAddr, Vec<u8>, u64);
+
 /// The UDP server
 ///
 /// Accepts DNS queries through UDP, and uses the `ServerContext` to determine
@@ -158,7 +381,7 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
 /// a new thread is spawned to service the request asynchronously.
 pub struct DnsUdpServer {
     context: Arc<ServerContext>,
-    request_queue: Arc<Mutex<VecDeque<(SocketAddr, DnsPacket)>>>,
+    request_queue: Arc<Mutex<VecDeque<UdpRequest>>>,
     request_cond: Arc<Condvar>,
     thread_count: usize
 }
@@ -205,7 +428,7 @@ impl DnsServer for DnsUdpServer {
                 loop {
                     // Acquire lock, and wait on the condition until data is available.
                     // Then proceed with popping an entry of the queue.
-                    let (src, request) = match request_queue.lock().ok()
+                    let (src, data, start_time) = match request_queue.lock().ok()
                         .and_then(|x| request_cond.wait(x).ok())
                         .and_then(|mut x| x.pop_front()) {
                         Some(x) => x,
@@ -215,27 +438,42 @@ impl DnsServer for DnsUdpServer {
                         }
                     };
 
-                    let mut size_limit = 512;
+                    // Blocked names and cache hits are answered without
+                    // parsing the whole packet
+                    let fast_response = DnsPacketRef::parse(&data).ok().and_then(|request_ref| {
+                        let size_limit = request_ref.edns_payload_size().unwrap_or(512) as usize;
+                        execute_fast_query(&context, &request_ref).map(|packet| (packet, size_limit))
+                    });
 
-                    // Check for EDNS
-                    if request.resources.len() == 1 {
-                        if let DnsRecord::OPT { packet_len, .. } = request.resources[0] {
-                            size_limit = packet_len as usize;
+                    let (mut packet, size_limit) = match fast_response {
+                        Some(x) => x,
+                        None => {
+                            let request = match DnsPacket::from_buffer(&mut SlicePacketBuffer::new(&data)) {
+                                Ok(x) => x,
+                                Err(e) => {
+                                    println!("Failed to parse UDP query packet: {:?}", e);
+                                    continue;
+                                }
+                            };
+
+                            let mut size_limit = 512;
+
+                            // Check for EDNS
+                            if request.resources.len() == 1 {
+                                if let DnsRecord::OPT { packet_len, .. } = request.resources[0] {
+                                    size_limit = packet_len as usize;
+                                }
+                            }
+
+                            // Ask the context for an appropriate resolver, and fire off the response
+                            (execute_query(context.clone(), &request), size_limit)
                         }
-                    }
-
-                    // Create a response buffer, and ask the context for an appropriate resolver
-                    let mut res_buffer = VectorPacketBuffer::new();
+                    };
 
-                    let mut packet = execute_query(context.clone(), &request);
-                    let _ = packet.write(&mut res_buffer, size_limit);
+                    send_udp_response(&socket_clone, src, &mut packet, size_limit);
 
-                    // Fire off the response
-                    let len = res_buffer.pos();
-                    let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get buffer data");
-                    ignore_or_report!(socket_clone.send_to(data, src), "Failed to send response packet");
                     // Incrementing and printing statistics
-                    let request_time = current_time_millis() - request.get_start_time();
+                    let request_time = current_time_millis() - start_time;
                     let mut lock = context.statistics.lock().unwrap();
                     lock.add_request_time(request_time, true);
                 }
@@ -265,7 +503,7 @@ impl DnsServer for DnsUdpServer {
 
                 // Read a query packet
                 let mut req_buffer = BytePacketBuffer::new();
-                let (_, src) = match socket.recv_from(&mut req_buffer.buf) {
+                let (len, src) = match socket.recv_from(&mut req_buffer.buf) {
                     Ok(x) => x,
                     Err(e) => {
                         if e.kind() != ErrorKind::TimedOut && e.raw_os_error() != Some(11) {
@@ -277,21 +515,10 @@ impl DnsServer for DnsUdpServer {
 
                 let start_time = current_time_millis();
 
-                // Parse it
-                let mut request = match DnsPacket::from_buffer(&mut req_buffer) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        println!("Failed to parse UDP query packet: {:?}", e);
-                        continue;
-                    }
-                };
-
-                request.set_start_time(start_time);
-
                 // Acquire lock, add request to queue, and notify waiting threads using the condition.
                 match self.request_queue.lock() {
                     Ok(mut queue) => {
-                        queue.push_back((src, request));
+                        queue.push_back((src, req_buffer.buf[..len].to_vec(), start_time));
                         self.request_cond.notify_one();
                         queue_len = queue.len();
                     },
@@ -368,7 +595,7 @@ impl DnsServer for DnsTcpServer {
                     // Now we can go ahead and write the actual packet
                     let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get packet data");
 
-                    ignore_or_report!(stream.write(data), "Failed to write response packet");
+                    ignore_or_report!(stream.write_all(data), "Failed to write response packet");
                     ignore_or_report!(stream.shutdown(Shutdown::Both), "Failed to shutdown socket");
                     // Incrementing and printing statistics
                     let request_time = current_time_millis() - request.get_start_time();
@@ -403,6 +630,281 @@ impl DnsServer for DnsTcpServer {
     }
 }
 
+/// Connections served at the same time by the DNS over TLS server, beyond
+/// which new ones are closed right away
+const MAX_TLS_CONNECTIONS: usize = 256;
+
+/// A connection to the DNS over TLS server
+///
+/// Queries are read by the thread serving the connection, and answered by
+/// the workers of the server, which write the responses as they're done.
+/// The TLS session is only locked while it processes what's been received or
+/// what's to be sent, so that responses can be written while waiting for the
+/// next query.
+struct TlsServerConnection {
+    conn: Mutex<ServerConnection>,
+    socket: TcpStream
+}
+
+impl TlsServerConnection {
+    fn new(config: Arc<ServerConfig>, socket: TcpStream, idle_timeout: Duration) -> Result<TlsServerConnection> {
+        socket.set_read_timeout(Some(idle_timeout))?;
+        socket.set_write_timeout(Some(idle_timeout))?;
+        socket.set_nodelay(true)?;
+
+        let conn = ServerConnection::new(config)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        Ok(TlsServerConnection {
+            conn: Mutex::new(conn),
+            socket
+        })
+    }
+
+    /// Write a message, prefixed with its length
+    fn write(&self, message: &[u8]) -> Result<()> {
+        let mut conn = match self.conn.lock() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        // The length goes in front of the message, so that both are sent in
+        // a single record
+        let mut data = Vec::with_capacity(message.len() + 2);
+        data.push((message.len() >> 8) as u8);
+        data.push((message.len() & 0xFF) as u8);
+        data.extend_from_slice(message);
+
+        conn.writer().write_all(&data)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(())
+    }
+
+    /// Let the client know that the connection is closed on purpose
+    fn close(&self) {
+        if let Ok(mut conn) = self.conn.lock() {
+            conn.send_close_notify();
+            while conn.wants_write() {
+                if conn.write_tls(&mut &self.socket).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Read queries until the client closes the connection or it's been
+    /// idle for too long, handing each to `dispatch`
+    fn read_queries<F>(&self, mut dispatch: F) -> Result<()> where F: FnMut(Vec<u8>) {
+        let mut buf = [0; 4096];
+        let mut chunk = [0; 4096];
+        let mut plaintext = Vec::new();
+
+        loop {
+            let size = match (&self.socket).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(x) => x,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    self.close();
+                    return Ok(());
+                },
+                Err(e) => return Err(e)
+            };
+
+            {
+                let mut conn = match self.conn.lock() {
+                    Ok(x) => x,
+                    Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+                };
+
+                let mut input = &buf[0..size];
+                while !input.is_empty() {
+                    conn.read_tls(&mut input)?;
+                    if let Err(err) = conn.process_new_packets() {
+                        // Let the client know why the connection is closed
+                        let _ = conn.write_tls(&mut &self.socket);
+                        return Err(Error::new(ErrorKind::InvalidData, err.to_string()));
+                    }
+
+                    loop {
+                        match conn.reader().read(&mut chunk) {
+                            Ok(0) => return Ok(()),
+                            Ok(n) => plaintext.extend_from_slice(&chunk[0..n]),
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(e)
+                        }
+                    }
+                }
+
+                while conn.wants_write() {
+                    conn.write_tls(&mut &self.socket)?;
+                }
+            }
+
+            // Several queries can be sent on a connection, so exactly the
+            // length of each is taken before handing it on
+            while plaintext.len() >= 2 {
+                let len = (plaintext[0] as usize) << 8 | plaintext[1] as usize;
+                if plaintext.len() < len + 2 {
+                    break;
+                }
+
+                dispatch(plaintext[2..len + 2].to_vec());
+                plaintext.drain(0..len + 2);
+            }
+        }
+    }
+}
+
+/// Answer a query received over a DNS over TLS connection
+fn answer_tls_query(context: &Arc<ServerContext>, connection: &TlsServerConnection, query: Vec<u8>) -> Result<()> {
+    let mut req_buffer = VectorPacketBuffer::new();
+    req_buffer.buffer = query;
+
+    let mut request = DnsPacket::from_buffer(&mut req_buffer)?;
+    request.set_start_time(current_time_millis());
+
+    let mut res_buffer = VectorPacketBuffer::new();
+    let mut packet = execute_query(context.clone(), &request);
+    packet.write(&mut res_buffer, 0xFFFF)?;
+
+    connection.write(&res_buffer.buffer[0..res_buffer.pos()])?;
+
+    let request_time = current_time_millis() - request.get_start_time();
+    if let Ok(mut statistics) = context.statistics.lock() {
+        statistics.add_request_time(request_time, false);
+    }
+
+    Ok(())
+}
+
+/// DNS over TLS server (RFC 7858)
+///
+/// Clients keep their connection open for the queries that follow, so rather
+/// than handing connections to a fixed pool of threads, each is read by a
+/// thread of its own until it's closed or times out. The queries read are
+/// handed to a pool of worker threads, so that the queries sent on a
+/// connection are answered at the same time, and a slow one doesn't hold up
+/// the rest. Responses are sent in whatever order they're done, which clients
+/// tell apart by their id.
+pub struct DnsTlsServer {
+    context: Arc<ServerContext>,
+    certificate: Arc<ServerCertificate>,
+    thread_count: usize,
+    listener: Option<TcpListener>
+}
+
+impl DnsTlsServer {
+    pub fn new(context: Arc<ServerContext>, certificate: Arc<ServerCertificate>, thread_count: usize) -> DnsTlsServer {
+        DnsTlsServer {
+            context,
+            certificate,
+            thread_count,
+            listener: None
+        }
+    }
+
+    /// Bind the listener ahead of `run_server`, returning the address it's
+    /// bound to, which tells the port picked when `tls_port` is 0
+    pub fn bind(&mut self) -> Result<SocketAddr> {
+        let listener = TcpListener::bind((self.context.dns_bind_ip.as_ref(), self.context.tls_port))?;
+        let addr = listener.local_addr()?;
+        self.listener = Some(listener);
+
+        Ok(addr)
+    }
+}
+
+impl DnsServer for DnsTlsServer {
+    fn run_server(mut self) -> Result<()> {
+        if self.listener.is_none() {
+            self.bind()?;
+        }
+        let socket = match self.listener.take() {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::Other, "TLS listener not bound"))
+        };
+
+        let config = server_config(self.certificate.clone());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        // Spawn threads for answering queries, which share a single queue so
+        // that a query is picked up by whichever thread is free
+        let (sender, receiver) = channel::<(Arc<TlsServerConnection>, Vec<u8>)>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for thread_id in 0..self.thread_count.max(1) {
+            let context = self.context.clone();
+            let receiver = receiver.clone();
+
+            let name = "DnsTlsServer-request-".to_string() + &thread_id.to_string();
+            Builder::new().name(name).spawn(move || {
+                loop {
+                    let (connection, query) = match receiver.lock().map(|x| x.recv()) {
+                        Ok(Ok(x)) => x,
+                        _ => break
+                    };
+
+                    if let Err(e) = answer_tls_query(&context, &connection, query) {
+                        println!("Failed to answer TLS query: {:?}", e);
+                    }
+                }
+            })?;
+        }
+
+        Builder::new().name("DnsTlsServer-incoming".into()).spawn(move || {
+            for (connection_id, wrap_stream) in socket.incoming().enumerate() {
+                let stream = match wrap_stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        println!("Failed to accept TLS connection: {:?}", err);
+                        continue;
+                    }
+                };
+
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_TLS_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    println!("Too many TLS connections, closing the one from {:?}", stream.peer_addr());
+                    continue;
+                }
+
+                let idle_timeout = Duration::from_secs(self.context.tls_idle_timeout);
+                let config = config.clone();
+                let sender = sender.clone();
+                let open_connections = connections.clone();
+
+                let name = "DnsTlsServer-connection-".to_string() + &connection_id.to_string();
+                let spawned = Builder::new().name(name).spawn(move || {
+                    let result = TlsServerConnection::new(config, stream, idle_timeout).and_then(|connection| {
+                        let connection = Arc::new(connection);
+
+                        // Hand each query off to the worker threads
+                        connection.read_queries(|query| {
+                            if let Err(e) = sender.send((connection.clone(), query)) {
+                                println!("Failed to send TLS query for processing: {}", e);
+                            }
+                        })
+                    });
+
+                    if let Err(e) = result {
+                        println!("Failed to serve TLS connection: {:?}", e);
+                    }
+
+                    open_connections.fetch_sub(1, Ordering::SeqCst);
+                });
+
+                if let Err(e) = spawned {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    println!("Failed to spawn thread for TLS connection: {}", e);
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -410,11 +912,13 @@ mod tests {
     use std::net::Ipv4Addr;
     use std::io::{Error, ErrorKind};
 
-    use dns::protocol::{DnsPacket, DnsQuestion, QueryType, DnsRecord, ResultCode, TransientTtl};
+    use dns::protocol::{DnsPacket, DnsQuestion, Opcode, QueryClass, QueryType, DnsRecord, ResultCode, TransientTtl};
 
     use super::*;
 
+    use dns::authority::Zone;
     use dns::context::ResolveStrategy;
+    use dns::upstream::UpstreamPool;
     use dns::context::tests::create_test_context;
 
     fn build_query(qname: &str, qtype: QueryType) -> DnsPacket {
@@ -437,29 +941,34 @@ mod tests {
                 if qname == "google.com" {
                     packet.answers.push(DnsRecord::A {
                         domain: "google.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
                         ttl: TransientTtl(3600)
                     });
                 } else if qname == "www.facebook.com" && qtype == QueryType::CNAME {
                     packet.answers.push(DnsRecord::CNAME {
                         domain: "www.facebook.com".to_string(),
+                        class: QueryClass::IN,
                         host: "cdn.facebook.com".to_string(),
                         ttl: TransientTtl(3600)
                     });
                     packet.answers.push(DnsRecord::A {
                         domain: "cdn.facebook.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
                         ttl: TransientTtl(3600)
                     });
                 } else if qname == "www.microsoft.com" && qtype == QueryType::CNAME {
                     packet.answers.push(DnsRecord::CNAME {
                         domain: "www.microsoft.com".to_string(),
+                        class: QueryClass::IN,
                         host: "cdn.microsoft.com".to_string(),
                         ttl: TransientTtl(3600)
                     });
                 } else if qname == "cdn.microsoft.com" && qtype == QueryType::A {
                     packet.answers.push(DnsRecord::A {
                         domain: "cdn.microsoft.com".to_string(),
+                        class: QueryClass::IN,
                         addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
                         ttl: TransientTtl(3600)
                     });
@@ -473,8 +982,7 @@ mod tests {
         match Arc::get_mut(&mut context) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                        host: "127.0.0.1".to_string(),
-                        port: 53
+                        upstreams: UpstreamPool::single("127.0.0.1", 53)
                     };
             },
             None => panic!()
@@ -494,6 +1002,15 @@ mod tests {
             }
         };
 
+        // The question is echoed in its original case, while the lookup
+        // itself is case insensitive
+        {
+            let res = execute_query(context.clone(),
+                                    &build_query("GooGle.COM", QueryType::A));
+            assert_eq!("GooGle.COM", res.questions[0].name);
+            assert_eq!(1, res.answers.len());
+        };
+
         // A successful resolve, that also resolves a CNAME without recursive lookup
         {
             let res = execute_query(context.clone(),
@@ -579,8 +1096,7 @@ mod tests {
         match Arc::get_mut(&mut context2) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                        host: "127.0.0.1".to_string(),
-                        port: 53
+                        upstreams: UpstreamPool::single("127.0.0.1", 53)
                     };
             },
             None => panic!()
@@ -595,5 +1111,418 @@ mod tests {
         };
 
     }
-}
 
+    #[test]
+    fn test_serve_stale() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        upstreams: UpstreamPool::single("127.0.0.1", 53)
+                    };
+            },
+            None => panic!()
+        }
+
+        // Store a record which has already expired
+        context.cache.store(&[DnsRecord::A {
+            domain: "google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+            ttl: TransientTtl(0)
+        }]).unwrap();
+
+        // Since the upstream fails, the expired record is served with the
+        // stale TTL rather than failing the query
+        {
+            let res = execute_query(context.clone(),
+                                    &build_query("google.com", QueryType::A));
+            assert_eq!(ResultCode::NOERROR, res.header.res_code);
+            assert_eq!(1, res.answers.len());
+            assert_eq!(context.stale_ttl, res.answers[0].get_ttl());
+        };
+
+        // Names without any cached data still fail
+        {
+            let res = execute_query(context.clone(),
+                                    &build_query("yahoo.com", QueryType::A));
+            assert_eq!(ResultCode::SERVFAIL, res.header.res_code);
+            assert_eq!(0, res.answers.len());
+        };
+    }
+
+    #[test]
+    fn test_serve_stale_disabled() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        upstreams: UpstreamPool::single("127.0.0.1", 53)
+                    };
+                ctx.stale_window = 0;
+            },
+            None => panic!()
+        }
+
+        context.cache.store(&[DnsRecord::A {
+            domain: "google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+            ttl: TransientTtl(0)
+        }]).unwrap();
+
+        let res = execute_query(context.clone(),
+                                &build_query("google.com", QueryType::A));
+        assert_eq!(ResultCode::SERVFAIL, res.header.res_code);
+        assert_eq!(0, res.answers.len());
+    }
+
+    #[test]
+    fn test_chaos_query() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.chaos_version = Some("hermes test".to_string());
+                ctx.chaos_id = Some("node-1".to_string());
+                ctx.allow_recursive = false;
+            },
+            None => panic!()
+        }
+
+        let chaos_query = |qname: &str, qtype: QueryType| {
+            let mut query_packet = build_query(qname, qtype);
+            query_packet.questions[0].qclass = QueryClass::CH;
+            query_packet
+        };
+
+        // Configured values are answered, even with recursion disabled
+        {
+            let res = execute_query(context.clone(), &chaos_query("version.bind", QueryType::TXT));
+            assert_eq!(ResultCode::NOERROR, res.header.res_code);
+            assert!(res.header.authoritative_answer);
+            assert_eq!(QueryClass::CH, res.questions[0].qclass);
+            assert_eq!(1, res.answers.len());
+
+            match res.answers[0] {
+                DnsRecord::TXT { ref domain, class, ref data, .. } => {
+                    assert_eq!("version.bind", domain);
+                    assert_eq!(QueryClass::CH, class);
+                    assert_eq!(vec![b"hermes test".to_vec()], *data);
+                },
+                _ => panic!()
+            }
+        };
+
+        {
+            let res = execute_query(context.clone(), &chaos_query("id.server", QueryType::TXT));
+            assert_eq!(ResultCode::NOERROR, res.header.res_code);
+            assert_eq!(1, res.answers.len());
+        };
+
+        // Values that aren't configured are refused
+        {
+            let res = execute_query(context.clone(), &chaos_query("hostname.bind", QueryType::TXT));
+            assert_eq!(ResultCode::REFUSED, res.header.res_code);
+            assert_eq!(0, res.answers.len());
+        };
+
+        // As are other names and types
+        {
+            let res = execute_query(context.clone(), &chaos_query("version.server", QueryType::TXT));
+            assert_eq!(ResultCode::REFUSED, res.header.res_code);
+
+            let res = execute_query(context.clone(), &chaos_query("version.bind", QueryType::A));
+            assert_eq!(ResultCode::REFUSED, res.header.res_code);
+        };
+
+        // Other classes than IN and CH aren't implemented
+        {
+            let mut query_packet = build_query("version.bind", QueryType::TXT);
+            query_packet.header.recursion_desired = false;
+            query_packet.questions[0].qclass = QueryClass::HS;
+
+            let res = execute_query(context.clone(), &query_packet);
+            assert_eq!(ResultCode::NOTIMP, res.header.res_code);
+        };
+    }
+
+    #[test]
+    fn test_opcodes() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                let mut zones = ctx.authority.write().unwrap();
+                zones.add_zone(Zone::new("example.com".to_string(),
+                                         "ns1.example.com".to_string(),
+                                         "admin.example.com".to_string()));
+            },
+            None => panic!()
+        }
+
+        let request = |opcode: Opcode, qname: &str, qtype: QueryType| {
+            let mut query_packet = build_query(qname, qtype);
+            query_packet.header.id = 4711;
+            query_packet.header.opcode = opcode;
+            query_packet
+        };
+
+        // Unsupported opcodes aren't implemented, but the opcode and the
+        // question are echoed
+        {
+            let res = execute_query(context.clone(), &request(Opcode::IQUERY, "example.com", QueryType::A));
+            assert_eq!(ResultCode::NOTIMP, res.header.res_code);
+            assert_eq!(Opcode::IQUERY, res.header.opcode);
+            assert_eq!(4711, res.header.id);
+            assert!(res.header.response);
+            assert!(res.header.recursion_desired);
+            assert_eq!(1, res.questions.len());
+            assert_eq!("example.com", res.questions[0].name);
+
+            let res = execute_query(context.clone(), &request(Opcode::UNKNOWN(15), "example.com", QueryType::A));
+            assert_eq!(ResultCode::NOTIMP, res.header.res_code);
+            assert_eq!(Opcode::UNKNOWN(15), res.header.opcode);
+        };
+
+        // More than one question is a format error
+        {
+            let mut query_packet = request(Opcode::QUERY, "example.com", QueryType::A);
+            query_packet.questions.push(DnsQuestion::new("example.org".to_string(), QueryType::A));
+
+            let res = execute_query(context.clone(), &query_packet);
+            assert_eq!(ResultCode::FORMERR, res.header.res_code);
+            assert_eq!(2, res.questions.len());
+            assert_eq!(0, res.answers.len());
+        };
+
+        // NOTIFY is acknowledged for local zones only
+        {
+            let res = execute_query(context.clone(), &request(Opcode::NOTIFY, "example.com", QueryType::SOA));
+            assert_eq!(ResultCode::NOERROR, res.header.res_code);
+            assert_eq!(Opcode::NOTIFY, res.header.opcode);
+            assert!(res.header.authoritative_answer);
+
+            let res = execute_query(context.clone(), &request(Opcode::NOTIFY, "example.org", QueryType::SOA));
+            assert_eq!(ResultCode::NOTAUTH, res.header.res_code);
+        };
+
+        // UPDATE is never applied
+        {
+            let res = execute_query(context.clone(), &request(Opcode::UPDATE, "example.com", QueryType::SOA));
+            assert_eq!(ResultCode::REFUSED, res.header.res_code);
+            assert_eq!(Opcode::UPDATE, res.header.opcode);
+
+            let res = execute_query(context.clone(), &request(Opcode::UPDATE, "example.org", QueryType::SOA));
+            assert_eq!(ResultCode::NOTAUTH, res.header.res_code);
+
+            let res = execute_query(context.clone(), &request(Opcode::UPDATE, "example.com", QueryType::A));
+            assert_eq!(ResultCode::FORMERR, res.header.res_code);
+        };
+    }
+
+    #[test]
+    fn test_fast_query() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string()));
+        }
+
+        context.cache.store(&[
+            DnsRecord::A {
+                domain: "google.com".to_string(),
+                class: QueryClass::IN,
+                addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                ttl: TransientTtl(3600)
+            },
+            DnsRecord::CNAME {
+                domain: "www.google.com".to_string(),
+                class: QueryClass::IN,
+                host: "cdn.google.com".to_string(),
+                ttl: TransientTtl(3600)
+            }
+        ]).unwrap();
+
+        let fast_query = |mut request: DnsPacket| {
+            request.header.id = 4711;
+
+            let mut buffer = VectorPacketBuffer::new();
+            request.write(&mut buffer, 512).unwrap();
+
+            let request_ref = DnsPacketRef::parse(&buffer.buffer).unwrap();
+            execute_fast_query(&context, &request_ref)
+        };
+
+        // A cache hit is answered, with the question as it was asked
+        {
+            let res = fast_query(build_query("GOOGLE.com", QueryType::A)).unwrap();
+            assert_eq!(4711, res.header.id);
+            assert!(res.header.response);
+            assert_eq!(ResultCode::NOERROR, res.header.res_code);
+            assert_eq!("GOOGLE.com", res.questions[0].name);
+            assert_eq!(1, res.answers.len());
+        };
+
+        // Cache misses, CNAMEs that need resolving and local zones all take
+        // the regular path
+        assert!(fast_query(build_query("yahoo.com", QueryType::A)).is_none());
+        assert!(fast_query(build_query("www.google.com", QueryType::CNAME)).is_none());
+        assert!(fast_query(build_query("example.com", QueryType::A)).is_none());
+
+        // As do requests which aren't plain recursive queries
+        let mut request = build_query("google.com", QueryType::A);
+        request.header.recursion_desired = false;
+        assert!(fast_query(request).is_none());
+
+        let mut request = build_query("google.com", QueryType::A);
+        request.questions[0].qclass = QueryClass::CH;
+        assert!(fast_query(request).is_none());
+
+        let mut request = build_query("google.com", QueryType::A);
+        request.header.opcode = Opcode::NOTIFY;
+        assert!(fast_query(request).is_none());
+    }
+
+    #[test]
+    fn test_tls_server() {
+        use std::convert::TryFrom;
+        use std::fs;
+        use std::path::Path;
+        use std::thread::sleep;
+
+        use rustls::{ClientConnection, StreamOwned};
+        use rustls::pki_types::ServerName;
+
+        use dns::tls::{SpkiPin, client_config, load_roots, pinned_client_config};
+
+        let mut context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                if qname == "slow.com" {
+                    sleep(Duration::from_millis(500));
+                }
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    class: QueryClass::IN,
+                    addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    upstreams: UpstreamPool::single("127.0.0.1", 53)
+                };
+                ctx.dns_bind_ip = "127.0.0.1".to_string();
+                ctx.tls_port = 0;
+                ctx.tls_idle_timeout = 1;
+            },
+            None => panic!()
+        }
+
+        // Serve a copy of the certificate, so that it can be replaced
+        let dir = ::std::env::temp_dir().join(format!("hermes-tls-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("server.crt");
+        let key_path = dir.join("server.key");
+        fs::copy("testdata/localhost.crt", &cert_path).unwrap();
+        fs::copy("testdata/localhost.key", &key_path).unwrap();
+
+        let certificate = Arc::new(ServerCertificate::load(&cert_path, &key_path).unwrap());
+        let mut server = DnsTlsServer::new(context.clone(), certificate, 4);
+        let addr = server.bind().unwrap();
+        server.run_server().unwrap();
+
+        let connect = |config| {
+            let conn = ClientConnection::new(config, ServerName::try_from("localhost").unwrap()).unwrap();
+            StreamOwned::new(conn, TcpStream::connect(addr).unwrap())
+        };
+
+        let query = |qname: &str, id: u16| {
+            let mut request = build_query(qname, QueryType::A);
+            request.header.id = id;
+
+            let mut req_buffer = VectorPacketBuffer::new();
+            request.write(&mut req_buffer, 0xFFFF).unwrap();
+
+            let len = req_buffer.pos();
+            let mut message = vec![(len >> 8) as u8, (len & 0xFF) as u8];
+            message.extend_from_slice(&req_buffer.buffer[0..req_buffer.pos()]);
+            message
+        };
+
+        let read_response = |stream: &mut StreamOwned<ClientConnection, TcpStream>| {
+            let len = read_packet_length(stream).unwrap();
+            let mut res_buffer = VectorPacketBuffer::new();
+            res_buffer.buffer = vec![0; len as usize];
+            stream.read_exact(&mut res_buffer.buffer).unwrap();
+            DnsPacket::from_buffer(&mut res_buffer).unwrap()
+        };
+
+        let roots = load_roots(Path::new("testdata/localhost.crt")).unwrap();
+        let mut stream = connect(client_config(Some(roots)));
+
+        // Several queries can be sent on a connection, without waiting for
+        // the responses, which are sent as soon as they're ready rather than
+        // in the order asked
+        stream.write_all(&query("slow.com", 1)).unwrap();
+        stream.write_all(&query("google.com", 2)).unwrap();
+        stream.flush().unwrap();
+
+        let response = read_response(&mut stream);
+        assert_eq!(2, response.header.id);
+        assert_eq!(DnsRecord::A {
+            domain: "google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+            ttl: TransientTtl(3600)
+        }, response.answers[0]);
+
+        let response = read_response(&mut stream);
+        assert_eq!(1, response.header.id);
+        assert_eq!(Some("slow.com".to_string()), response.answers[0].get_domain());
+
+        // After being idle for too long, the connection is closed
+        sleep(Duration::from_millis(1500));
+        let mut buf = [0; 1];
+        assert_eq!(0, stream.read(&mut buf).unwrap());
+
+        // When the certificate is replaced, new connections are served the
+        // new one
+        fs::copy("testdata/renewed.crt", &cert_path).unwrap();
+        fs::copy("testdata/renewed.key", &key_path).unwrap();
+
+        let renewed = SpkiPin::parse("CyFf4YQSpExhqmzQGXC/SKj/oeMjPjh74kuY10hZFsY=").unwrap();
+        let mut stream = connect(pinned_client_config(&[renewed]));
+        stream.write_all(&query("google.com", 3)).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(3, read_response(&mut stream).header.id);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
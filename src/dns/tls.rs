@@ -0,0 +1,744 @@
+//! DNS over TLS (RFC 7858)
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::Builder;
+use std::time::{Duration, SystemTime};
+
+use rustls::{CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::pki_types::pem::PemObject;
+
+/// Timeout for connecting to a server, for writing to it, and for waiting
+/// for the response to a query
+const TLS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a connection is kept open without any traffic
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Create a client configuration trusting `roots`, or the Mozilla root
+/// certificates bundled with webpki-roots when not given
+pub fn client_config(roots: Option<RootCertStore>) -> Arc<ClientConfig> {
+    let roots = roots.unwrap_or_else(|| RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()
+    });
+
+    Arc::new(ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .expect("TLS protocol versions are supported")
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Create a client configuration accepting any server presenting a
+/// certificate for one of the public keys in `pins`
+pub fn pinned_client_config(pins: &[SpkiPin]) -> Arc<ClientConfig> {
+    let provider = crypto_provider();
+    let verifier = SpkiPinVerifier {
+        pins: pins.to_vec(),
+        algorithms: provider.signature_verification_algorithms
+    };
+
+    Arc::new(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("TLS protocol versions are supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Load the certificates of a PEM file into a store of trusted roots
+pub fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    for cert in certs {
+        let cert = cert.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        roots.add(cert).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    }
+
+    Ok(roots)
+}
+
+/// The SHA-256 digest of the SubjectPublicKeyInfo of a certificate, as used
+/// by the key-pinned privacy profile of RFC 7858
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct SpkiPin(Vec<u8>);
+
+impl SpkiPin {
+    /// Parse a base64 encoded digest, as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    pub fn parse(pin: &str) -> Option<SpkiPin> {
+        match base64::decode(pin) {
+            Ok(ref digest) if digest.len() == 32 => Some(SpkiPin(digest.clone())),
+            _ => None
+        }
+    }
+
+    /// Compute the pin of a DER encoded certificate
+    pub fn of_certificate(cert: &CertificateDer) -> Option<SpkiPin> {
+        let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+        let spki = cert.subject_public_key_info();
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki.as_ref());
+
+        Some(SpkiPin(digest.as_ref().to_vec()))
+    }
+}
+
+impl fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode(&self.0))
+    }
+}
+
+/// Verifies servers by the public key of their certificate alone
+///
+/// When pins are configured, the certificate doesn't have to chain to a
+/// trusted root, nor be valid for the server name. Matching a pin is what
+/// authenticates the server.
+#[derive(Debug)]
+struct SpkiPinVerifier {
+    pins: Vec<SpkiPin>,
+    algorithms: WebPkiSupportedAlgorithms
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(&self,
+                          end_entity: &CertificateDer,
+                          _: &[CertificateDer],
+                          _: &ServerName,
+                          _: &[u8],
+                          _: UnixTime) -> ::std::result::Result<ServerCertVerified, rustls::Error> {
+
+        match SpkiPin::of_certificate(end_entity) {
+            Some(ref pin) if self.pins.contains(pin) => Ok(ServerCertVerified::assertion()),
+            Some(_) => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+            None => Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))
+        }
+    }
+
+    fn verify_tls12_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct) -> ::std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct) -> ::std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// How a server speaking DNS over TLS is authenticated
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct TlsAuth {
+    /// The name sent in the SNI extension, which the certificate has to be
+    /// valid for unless pins are given
+    pub server_name: String,
+    /// Public keys the certificate has to match, instead of chaining to a
+    /// trusted root
+    pub pins: Vec<SpkiPin>
+}
+
+impl TlsAuth {
+    pub fn new(server_name: &str) -> TlsAuth {
+        TlsAuth {
+            server_name: server_name.to_string(),
+            pins: Vec::new()
+        }
+    }
+}
+
+/// A connection to a server, shared by the queries sent to it at the same time
+///
+/// Queries are written as soon as they're sent, without waiting for the
+/// responses to earlier ones. A reader thread matches the responses to the
+/// queries by id, in whatever order the server answers them.
+struct TlsConnection {
+    conn: Mutex<ClientConnection>,
+    socket: TcpStream,
+    pending: Mutex<HashMap<u16, Sender<Vec<u8>>>>,
+    closed: AtomicBool,
+    error: Mutex<Option<String>>
+}
+
+impl TlsConnection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Close the connection, failing the queries waiting for a response
+    fn close(&self, error: Option<String>) {
+        if let Some(error) = error {
+            if let Ok(mut x) = self.error.lock() {
+                x.get_or_insert(error);
+            }
+        }
+
+        self.closed.store(true, Ordering::Release);
+        let _ = self.socket.shutdown(Shutdown::Both);
+
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.clear();
+        }
+    }
+
+    /// Write a DNS message to the server, and wait for the response with the
+    /// same id
+    fn query(&self, request: &[u8]) -> Result<Vec<u8>> {
+        if request.len() < 2 || request.len() > 0xFFFF {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid DNS message"));
+        }
+        let id = (request[0] as u16) << 8 | request[1] as u16;
+
+        let (tx, rx) = channel();
+        {
+            let mut pending = match self.pending.lock() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            if self.is_closed() {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "TLS connection closed"));
+            }
+            if pending.contains_key(&id) {
+                return Err(Error::new(ErrorKind::AlreadyExists, "Query id already in use"));
+            }
+
+            pending.insert(id, tx);
+        }
+
+        if let Err(err) = self.write(request) {
+            self.close(Some(err.to_string()));
+            return Err(Error::new(ErrorKind::ConnectionAborted, err.to_string()));
+        }
+
+        match rx.recv_timeout(TLS_TIMEOUT) {
+            Ok(response) => Ok(response),
+            Err(RecvTimeoutError::Timeout) => {
+                // Only this query fails, since the server may still be
+                // answering the others, and a response arriving late is
+                // dropped along with its id
+                if let Ok(mut pending) = self.pending.lock() {
+                    pending.remove(&id);
+                }
+
+                Err(Error::new(ErrorKind::TimedOut, "Request timed out"))
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                let error = match self.error.lock() {
+                    Ok(x) => x.clone(),
+                    Err(_) => None
+                };
+
+                Err(Error::new(ErrorKind::ConnectionAborted, error.unwrap_or_else(|| "TLS connection closed".to_string())))
+            }
+        }
+    }
+
+    fn write(&self, request: &[u8]) -> Result<()> {
+        let mut message = Vec::with_capacity(request.len() + 2);
+        message.push((request.len() >> 8) as u8);
+        message.push((request.len() & 0xFF) as u8);
+        message.extend_from_slice(request);
+
+        let mut conn = match self.conn.lock() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        // Before the handshake has completed, the message is buffered by
+        // rustls, and written by the reader thread once it's done
+        conn.writer().write_all(&message)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read from the server until the connection is closed, handing the
+    /// responses to the queries waiting for them
+    fn read_responses(&self) -> Result<()> {
+        let mut buf = [0; 4096];
+        let mut chunk = [0; 4096];
+        let mut plaintext = Vec::new();
+
+        loop {
+            // The lock is only held while processing what's been received,
+            // so that queries can be written while waiting for a response
+            let size = match (&self.socket).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(x) => x,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    // Idle for too long
+                    return Ok(());
+                },
+                Err(e) => return Err(e)
+            };
+
+            let mut conn = match self.conn.lock() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            let mut input = &buf[0..size];
+            while !input.is_empty() {
+                conn.read_tls(&mut input)?;
+                if let Err(err) = conn.process_new_packets() {
+                    // Let the server know why the connection is closed
+                    let _ = conn.write_tls(&mut &self.socket);
+                    return Err(Error::new(ErrorKind::InvalidData, err.to_string()));
+                }
+
+                loop {
+                    match conn.reader().read(&mut chunk) {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => plaintext.extend_from_slice(&chunk[0..n]),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e)
+                    }
+                }
+            }
+
+            while conn.wants_write() {
+                conn.write_tls(&mut &self.socket)?;
+            }
+
+            drop(conn);
+
+            self.dispatch(&mut plaintext);
+        }
+    }
+
+    /// Hand the complete messages received so far to the queries waiting
+    /// for them
+    fn dispatch(&self, plaintext: &mut Vec<u8>) {
+        let mut pos = 0;
+        while plaintext.len() >= pos + 2 {
+            let len = (plaintext[pos] as usize) << 8 | plaintext[pos + 1] as usize;
+            if plaintext.len() < pos + 2 + len {
+                break;
+            }
+
+            let message = plaintext[pos + 2..pos + 2 + len].to_vec();
+            pos += 2 + len;
+
+            if message.len() < 2 {
+                continue;
+            }
+
+            let id = (message[0] as u16) << 8 | message[1] as u16;
+            let tx = match self.pending.lock() {
+                Ok(mut pending) => pending.remove(&id),
+                Err(_) => None
+            };
+
+            if let Some(tx) = tx {
+                let _ = tx.send(message);
+            }
+        }
+
+        plaintext.drain(0..pos);
+    }
+}
+
+/// Connections to servers speaking DNS over TLS
+///
+/// Setting up a TLS session takes a couple of round trips, so a connection
+/// is kept open after a query and reused by the next ones, as recommended by
+/// RFC 7858. Queries sent to a server at the same time share the connection,
+/// rather than each waiting for their turn. A server is free to close an
+/// idle connection at any time, so a query failing on a reused connection is
+/// retried on a new one.
+pub struct TlsConnections {
+    config: Arc<ClientConfig>,
+    connections: Mutex<HashMap<(String, u16, TlsAuth), Arc<TlsConnection>>>
+}
+
+impl TlsConnections {
+    pub fn new(config: Arc<ClientConfig>) -> TlsConnections {
+        TlsConnections {
+            config,
+            connections: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn connect(&self, server: (&str, u16), auth: &TlsAuth) -> Result<TlsConnection> {
+        let addr = match server.to_socket_addrs()?.next() {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid server address"))
+        };
+
+        let config = if auth.pins.is_empty() {
+            self.config.clone()
+        } else {
+            pinned_client_config(&auth.pins)
+        };
+
+        let name = ServerName::try_from(auth.server_name.clone())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid server name"))?;
+        let conn = ClientConnection::new(config, name)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        let socket = TcpStream::connect_timeout(&addr, TLS_TIMEOUT)?;
+        socket.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        socket.set_write_timeout(Some(TLS_TIMEOUT))?;
+        socket.set_nodelay(true)?;
+
+        Ok(TlsConnection {
+            conn: Mutex::new(conn),
+            socket,
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            error: Mutex::new(None)
+        })
+    }
+
+    /// Get the open connection to a server, or open a new one, returning
+    /// whether it was reused
+    fn get_connection(&self, server: (&str, u16), auth: &TlsAuth) -> Result<(Arc<TlsConnection>, bool)> {
+        let key = (server.0.to_string(), server.1, auth.clone());
+
+        if let Some(conn) = self.get_open(&key) {
+            return Ok((conn, true));
+        }
+
+        let conn = self.connect(server, auth)?;
+
+        let mut connections = match self.connections.lock() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        // Another query may have opened a connection in the meantime
+        if let Some(existing) = connections.get(&key) {
+            if !existing.is_closed() {
+                return Ok((existing.clone(), true));
+            }
+        }
+
+        let conn = Arc::new(conn);
+        connections.insert(key.clone(), conn.clone());
+
+        let reader = conn.clone();
+        let name = format!("TlsConnection-{}:{}", server.0, server.1);
+        Builder::new().name(name).spawn(move || {
+            let result = reader.read_responses();
+            if let Err(ref err) = result {
+                println!("TLS connection to {}:{} failed: {}", key.0, key.1, err);
+            }
+
+            reader.close(result.err().map(|e| e.to_string()));
+        })?;
+
+        Ok((conn, false))
+    }
+
+    fn get_open(&self, key: &(String, u16, TlsAuth)) -> Option<Arc<TlsConnection>> {
+        let connections = self.connections.lock().ok()?;
+        connections.get(key).filter(|x| !x.is_closed()).cloned()
+    }
+
+    /// Send a DNS message to a server, authenticated according to `auth`,
+    /// and return the response
+    pub fn send(&self, server: (&str, u16), auth: &TlsAuth, request: &[u8]) -> Result<Vec<u8>> {
+        let (conn, reused) = self.get_connection(server, auth)?;
+
+        match conn.query(request) {
+            Err(ref err) if reused && err.kind() == ErrorKind::ConnectionAborted => {
+                let (conn, _) = self.get_connection(server, auth)?;
+                conn.query(request)
+            },
+            result => result
+        }
+    }
+
+    /// Check whether a connection to a server is open
+    pub fn is_connected(&self, server: (&str, u16), auth: &TlsAuth) -> bool {
+        self.get_open(&(server.0.to_string(), server.1, auth.clone())).is_some()
+    }
+}
+
+#[derive(Debug)]
+struct LoadedCertificate {
+    modified: (SystemTime, SystemTime),
+    key: Arc<CertifiedKey>
+}
+
+/// A certificate and private key served from PEM files, which are reloaded
+/// when either of them changes
+///
+/// The files are checked on every handshake, so a renewed certificate is
+/// picked up by the next connection without restarting the server. If they
+/// fail to load, for instance because they're halfway through being replaced,
+/// the previous certificate keeps being served until they do.
+#[derive(Debug)]
+pub struct ServerCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: Mutex<LoadedCertificate>
+}
+
+impl ServerCertificate {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<ServerCertificate> {
+        let modified = ServerCertificate::modified(cert_path, key_path)?;
+        let key = ServerCertificate::read(cert_path, key_path)?;
+
+        Ok(ServerCertificate {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            loaded: Mutex::new(LoadedCertificate {
+                modified,
+                key
+            })
+        })
+    }
+
+    fn modified(cert_path: &Path, key_path: &Path) -> Result<(SystemTime, SystemTime)> {
+        Ok((cert_path.metadata()?.modified()?, key_path.metadata()?.modified()?))
+    }
+
+    fn read(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>> {
+        let pem_error = |e: rustls::pki_types::pem::Error| Error::new(ErrorKind::InvalidData, e.to_string());
+
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .map_err(pem_error)?
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .map_err(pem_error)?;
+        if certs.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "No certificates found"));
+        }
+
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(pem_error)?;
+
+        CertifiedKey::from_der(certs, key, &crypto_provider())
+            .map(Arc::new)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// The certificate to present, reloaded first if the files have changed
+    pub fn current(&self) -> Option<Arc<CertifiedKey>> {
+        let mut loaded = self.loaded.lock().ok()?;
+
+        if let Ok(modified) = ServerCertificate::modified(&self.cert_path, &self.key_path) {
+            if modified != loaded.modified {
+                match ServerCertificate::read(&self.cert_path, &self.key_path) {
+                    Ok(key) => {
+                        println!("Reloaded TLS certificate {}", self.cert_path.display());
+                        loaded.modified = modified;
+                        loaded.key = key;
+                    },
+                    Err(e) => println!("Failed to reload TLS certificate {}: {}", self.cert_path.display(), e)
+                }
+            }
+        }
+
+        Some(loaded.key.clone())
+    }
+}
+
+impl ResolvesServerCert for ServerCertificate {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+}
+
+/// Create a server configuration presenting `certificate`
+pub fn server_config(certificate: Arc<ServerCertificate>) -> Arc<ServerConfig> {
+    Arc::new(ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .expect("TLS protocol versions are supported")
+        .with_no_client_auth()
+        .with_cert_resolver(certificate))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use rustls::{ServerConnection, StreamOwned};
+
+    use dns::netutil::{read_packet_length, write_packet_length};
+
+    use super::*;
+
+    /// The pin of the certificate in the testdata directory
+    const TEST_PIN: &str = "6hsq0+LkprAkpdy5e5wb33R5IHryKR8HQIlZA4HZGvE=";
+
+    /// A configuration for a server using the certificate for localhost
+    /// and 127.0.0.1 from the testdata directory
+    pub fn test_server_config() -> Arc<ServerConfig> {
+        let certificate = ServerCertificate::load(Path::new("testdata/localhost.crt"),
+                                                  Path::new("testdata/localhost.key")).unwrap();
+
+        server_config(Arc::new(certificate))
+    }
+
+    /// A client configuration trusting the certificate used by the server
+    pub fn test_client_config() -> Arc<ClientConfig> {
+        client_config(Some(load_roots(Path::new("testdata/localhost.crt")).unwrap()))
+    }
+
+    /// Start a server which reads `batch` messages at a time, and answers
+    /// each with itself in the reverse order, returning its port and a count
+    /// of the connections accepted
+    fn start_echo_server(batch: usize) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let config = test_server_config();
+        let accepted = connections.clone();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+
+                let config = config.clone();
+                thread::spawn(move || {
+                    let conn = ServerConnection::new(config).unwrap();
+                    let mut stream = StreamOwned::new(conn, socket.unwrap());
+
+                    let mut messages = Vec::new();
+                    while let Ok(len) = read_packet_length(&mut stream) {
+                        let mut message = vec![0; len as usize];
+                        if stream.read_exact(&mut message).is_err() {
+                            break;
+                        }
+
+                        messages.push(message);
+                        if messages.len() < batch {
+                            continue;
+                        }
+
+                        while let Some(message) = messages.pop() {
+                            let _ = write_packet_length(&mut stream, message.len());
+                            let _ = stream.write_all(&message);
+                        }
+                        let _ = stream.flush();
+                    }
+                });
+            }
+        });
+
+        (port, connections)
+    }
+
+    #[test]
+    fn test_tls_connections() {
+        let (port, connections) = start_echo_server(1);
+        let tls = TlsConnections::new(test_client_config());
+        let localhost = TlsAuth::new("localhost");
+
+        assert_eq!(b"\x00\x01hello".to_vec(), tls.send(("127.0.0.1", port), &localhost, b"\x00\x01hello").unwrap());
+        assert_eq!(b"\x00\x02world".to_vec(), tls.send(("127.0.0.1", port), &localhost, b"\x00\x02world").unwrap());
+
+        // The connection was kept open and reused
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+        assert!(tls.is_connected(("127.0.0.1", port), &localhost));
+
+        // The certificate is valid for the address as well
+        assert!(tls.send(("127.0.0.1", port), &TlsAuth::new("127.0.0.1"), b"\x00\x01hello").is_ok());
+
+        // But not for any other name
+        let google = TlsAuth::new("dns.google");
+        assert!(tls.send(("127.0.0.1", port), &google, b"\x00\x01hello").is_err());
+        assert!(!tls.is_connected(("127.0.0.1", port), &google));
+
+        // And it's not trusted by default
+        let tls = TlsConnections::new(client_config(None));
+        assert!(tls.send(("127.0.0.1", port), &localhost, b"\x00\x01hello").is_err());
+    }
+
+    #[test]
+    fn test_tls_pipelining() {
+        let (port, connections) = start_echo_server(2);
+        let tls = Arc::new(TlsConnections::new(test_client_config()));
+        let localhost = TlsAuth::new("localhost");
+
+        // Open the connection up front, so that both queries share it
+        let _ = tls.get_connection(("127.0.0.1", port), &localhost).unwrap();
+
+        // The server only answers once it has received both queries, so they
+        // have to be written without waiting for each other's response
+        let threads = vec![b"\x00\x01hello".to_vec(), b"\x00\x02world".to_vec()].into_iter().map(|request| {
+            let tls = tls.clone();
+            let localhost = localhost.clone();
+            thread::spawn(move || {
+                let response = tls.send(("127.0.0.1", port), &localhost, &request).unwrap();
+                assert_eq!(request, response);
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_tls_timeout() {
+        let (port, connections) = start_echo_server(2);
+        let tls = TlsConnections::new(test_client_config());
+        let localhost = TlsAuth::new("localhost");
+
+        // The server holds on to the first query until a second arrives, so
+        // it times out, without taking the connection down with it
+        let err = tls.send(("127.0.0.1", port), &localhost, b"\x00\x01hello").unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(tls.is_connected(("127.0.0.1", port), &localhost));
+
+        // The late response to the first query is dropped
+        assert_eq!(b"\x00\x02world".to_vec(), tls.send(("127.0.0.1", port), &localhost, b"\x00\x02world").unwrap());
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_tls_pins() {
+        let cert = CertificateDer::from_pem_file("testdata/localhost.crt").unwrap();
+        assert_eq!(SpkiPin::parse(TEST_PIN), SpkiPin::of_certificate(&cert));
+        assert_eq!(TEST_PIN, SpkiPin::parse(TEST_PIN).unwrap().to_string());
+        assert_eq!(None, SpkiPin::parse("aGVsbG8="));
+        assert_eq!(None, SpkiPin::parse("not base64"));
+
+        let (port, _) = start_echo_server(1);
+
+        // With a matching pin, the certificate doesn't have to be trusted
+        let tls = TlsConnections::new(client_config(None));
+        let mut auth = TlsAuth::new("dns.google");
+        auth.pins.push(SpkiPin::parse("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap());
+        assert!(tls.send(("127.0.0.1", port), &auth, b"\x00\x01hello").is_err());
+
+        auth.pins.push(SpkiPin::parse(TEST_PIN).unwrap());
+        assert_eq!(b"\x00\x01hello".to_vec(), tls.send(("127.0.0.1", port), &auth, b"\x00\x01hello").unwrap());
+    }
+}
@@ -0,0 +1,580 @@
+//! upstream servers for the forwarding resolver, and the choice between them
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+use dns::protocol::{DnsPacket, ResultCode};
+use dns::https::{HttpsEndpoint, HttpsMethod};
+use dns::tls::{SpkiPin, TlsAuth};
+
+/// Consecutive failures after which an upstream is considered down
+const MAX_FAILURES: u32 = 3;
+
+/// How long an upstream which is down is left alone, before it's tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How queries are sent to an upstream
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum UpstreamProtocol {
+    /// Plain DNS over UDP, retried over TCP when the response is truncated
+    Udp,
+    /// DNS over TLS (RFC 7858), to a server authenticated according to
+    /// `auth`
+    Tls {
+        auth: TlsAuth
+    },
+    /// DNS over HTTPS (RFC 8484), to an endpoint on the server, which is
+    /// reached at the first of `addrs` that answers
+    Https {
+        endpoint: HttpsEndpoint,
+        addrs: Vec<String>
+    }
+}
+
+/// A server queries are forwarded to
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Upstream {
+    /// The address of the server, or the first of them for DNS over HTTPS
+    pub host: String,
+    pub port: u16,
+    pub protocol: UpstreamProtocol
+}
+
+/// Parse an IPv4 address, optionally followed by a port
+fn parse_addr(addr: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Ok(ip) = addr.parse::<Ipv4Addr>() {
+        return Some((ip.to_string(), default_port));
+    }
+
+    addr.parse::<SocketAddrV4>().ok()
+        .map(|addr| (addr.ip().to_string(), addr.port()))
+}
+
+impl Upstream {
+    pub fn new(host: &str, port: u16) -> Upstream {
+        Upstream {
+            host: host.to_string(),
+            port,
+            protocol: UpstreamProtocol::Udp
+        }
+    }
+
+    pub fn tls(host: &str, port: u16, auth: TlsAuth) -> Upstream {
+        Upstream {
+            host: host.to_string(),
+            port,
+            protocol: UpstreamProtocol::Tls {
+                auth
+            }
+        }
+    }
+
+    pub fn https(addrs: Vec<String>, endpoint: HttpsEndpoint) -> Upstream {
+        Upstream {
+            host: addrs.first().cloned().unwrap_or_default(),
+            port: endpoint.port,
+            protocol: UpstreamProtocol::Https {
+                endpoint,
+                addrs
+            }
+        }
+    }
+
+    /// Parse an IPv4 address, optionally followed by a port, such as
+    /// `8.8.8.8` or `127.0.0.1:5353`
+    ///
+    /// Servers speaking DNS over TLS are prefixed with `tls://`, and may be
+    /// followed by the name their certificate is verified against, such as
+    /// `tls://1.1.1.1#cloudflare-dns.com`. Without a name, the certificate
+    /// has to be valid for the address. The port defaults to 853. The public
+    /// key of the server can be pinned by appending the base64 encoded SHA-256
+    /// digest of its SubjectPublicKeyInfo, as in `?pin=DIGEST`, repeated with
+    /// `&pin=DIGEST` for backup keys.
+    ///
+    /// Servers speaking DNS over HTTPS are given by the URL of their endpoint,
+    /// see `HttpsEndpoint::parse`. Unless the host of the URL is an address,
+    /// the address to connect to has to be given as well, as in
+    /// `https://dns.google/dns-query#addr=8.8.8.8`, so that reaching the
+    /// server doesn't depend on resolving its name. Both IPv4 and IPv6
+    /// addresses are accepted, and `addr=` can be repeated, in which case the
+    /// addresses are tried in the order given.
+    pub fn parse(addr: &str) -> Option<Upstream> {
+        if addr.starts_with("https://") {
+            let (endpoint, params) = HttpsEndpoint::parse(addr)?;
+
+            let mut addrs = Vec::new();
+            for (key, value) in params {
+                if key != "addr" {
+                    return None;
+                }
+
+                addrs.push(value.parse::<IpAddr>().ok()?.to_string());
+            }
+            if addrs.is_empty() {
+                addrs.push(endpoint.auth.server_name.parse::<IpAddr>().ok()?.to_string());
+            }
+
+            return Some(Upstream::https(addrs, endpoint));
+        }
+
+        if let Some(addr) = addr.strip_prefix("tls://") {
+            let mut parts = addr.splitn(2, '?');
+            let mut server = parts.next()?.splitn(2, '#');
+
+            let (host, port) = parse_addr(server.next()?, 853)?;
+            let mut auth = TlsAuth::new(server.next().unwrap_or(&host));
+            if auth.server_name.is_empty() {
+                return None;
+            }
+
+            if let Some(params) = parts.next() {
+                for param in params.split('&') {
+                    auth.pins.push(SpkiPin::parse(param.strip_prefix("pin=")?)?);
+                }
+            }
+
+            return Some(Upstream::tls(&host, port, auth));
+        }
+
+        parse_addr(addr, 53).map(|(host, port)| Upstream::new(&host, port))
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.protocol {
+            UpstreamProtocol::Udp => write!(f, "{}:{}", self.host, self.port),
+            UpstreamProtocol::Tls { ref auth } => {
+                write!(f, "tls://{}:{}#{}", self.host, self.port, auth.server_name)?;
+                for (i, pin) in auth.pins.iter().enumerate() {
+                    write!(f, "{}pin={}", if i == 0 { "?" } else { "&" }, pin)?;
+                }
+
+                Ok(())
+            },
+            UpstreamProtocol::Https { ref endpoint, ref addrs } => {
+                write!(f, "{}#", endpoint)?;
+                for (i, addr) in addrs.iter().enumerate() {
+                    write!(f, "{}addr={}", if i == 0 { "" } else { "&" }, addr)?;
+                }
+                if endpoint.method == HttpsMethod::Get {
+                    write!(f, "&method=get")?;
+                }
+                for pin in &endpoint.auth.pins {
+                    write!(f, "&pin={}", pin)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The order in which the upstreams of a pool are tried
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum UpstreamStrategy {
+    /// Always in the order given, moving on to the next when one fails
+    Failover,
+    /// Starting with the next upstream for every query
+    RoundRobin,
+    /// In a random order for every query
+    Random,
+    /// The one which has been answering the fastest first
+    Fastest
+}
+
+impl UpstreamStrategy {
+    pub fn parse(name: &str) -> Option<UpstreamStrategy> {
+        match name {
+            "failover" => Some(UpstreamStrategy::Failover),
+            "round-robin" => Some(UpstreamStrategy::RoundRobin),
+            "random" => Some(UpstreamStrategy::Random),
+            "fastest" => Some(UpstreamStrategy::Fastest),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+struct UpstreamHealth {
+    failures: u32,
+    down_until: Option<Instant>,
+    /// Smoothed round trip time of successful queries
+    rtt: Option<Duration>
+}
+
+impl UpstreamHealth {
+    fn is_down(&self, now: Instant) -> bool {
+        self.down_until.is_some_and(|x| now < x)
+    }
+}
+
+/// A set of upstreams, along with what's been learned about their health
+///
+/// A query is sent to one upstream at a time, in an order picked by the
+/// strategy of the pool, until one of them answers. Errors, including
+/// timeouts, and SERVFAIL or REFUSED responses count as failures. After
+/// `MAX_FAILURES` failures in a row an upstream is marked down, and tried
+/// after the others until `RETRY_INTERVAL` has passed, when the next query
+/// probes it again.
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    strategy: UpstreamStrategy,
+    health: Mutex<Vec<UpstreamHealth>>,
+    next: AtomicUsize
+}
+
+impl UpstreamPool {
+    pub fn new(upstreams: Vec<Upstream>, strategy: UpstreamStrategy) -> UpstreamPool {
+        UpstreamPool {
+            health: Mutex::new(vec![UpstreamHealth::default(); upstreams.len()]),
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0)
+        }
+    }
+
+    /// A pool of a single plain DNS upstream, for tests
+    #[cfg(test)]
+    pub fn single(host: &str, port: u16) -> ::std::sync::Arc<UpstreamPool> {
+        ::std::sync::Arc::new(UpstreamPool::new(vec![Upstream::new(host, port)], UpstreamStrategy::Failover))
+    }
+
+    pub fn get_upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    pub fn get_strategy(&self) -> UpstreamStrategy {
+        self.strategy
+    }
+
+    /// Check whether an upstream is marked down
+    pub fn is_down(&self, idx: usize) -> bool {
+        match self.health.lock() {
+            Ok(health) => health[idx].is_down(Instant::now()),
+            Err(_) => false
+        }
+    }
+
+    /// The indices of the upstreams, in the order they're to be tried
+    fn order(&self) -> Vec<usize> {
+        let mut order = (0..self.upstreams.len()).collect::<Vec<usize>>();
+        if order.is_empty() {
+            return order;
+        }
+
+        let health = match self.health.lock() {
+            Ok(x) => x.clone(),
+            Err(_) => return order
+        };
+
+        match self.strategy {
+            UpstreamStrategy::Failover => {},
+            UpstreamStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len();
+                order.rotate_left(start);
+            },
+            UpstreamStrategy::Random => thread_rng().shuffle(&mut order),
+            UpstreamStrategy::Fastest => {
+                // Upstreams which haven't answered yet go first, so that
+                // they get measured
+                order.sort_by_key(|&idx| health[idx].rtt.unwrap_or_default());
+            }
+        }
+
+        // The sort is stable, so the upstreams which are down are moved last
+        // while keeping the order picked by the strategy
+        let now = Instant::now();
+        order.sort_by_key(|&idx| health[idx].is_down(now));
+
+        order
+    }
+
+    fn record_success(&self, idx: usize, rtt: Duration) {
+        if let Ok(mut health) = self.health.lock() {
+            let health = &mut health[idx];
+
+            health.failures = 0;
+            health.down_until = None;
+            health.rtt = Some(match health.rtt {
+                Some(x) => (x * 7 + rtt) / 8,
+                None => rtt
+            });
+        }
+    }
+
+    fn record_failure(&self, idx: usize) {
+        if let Ok(mut health) = self.health.lock() {
+            let health = &mut health[idx];
+
+            health.failures = health.failures.saturating_add(1);
+            if health.failures >= MAX_FAILURES {
+                if !health.is_down(Instant::now()) {
+                    println!("Marking upstream {} as down after {} failures", self.upstreams[idx], health.failures);
+                }
+
+                health.down_until = Some(Instant::now() + RETRY_INTERVAL);
+            }
+        }
+    }
+
+    /// Send a query to the upstreams using `send`, until one of them answers
+    ///
+    /// When every upstream fails, the last failure is returned, which is
+    /// either an error or a SERVFAIL or REFUSED response.
+    pub fn query<F>(&self, mut send: F) -> Result<DnsPacket> where F: FnMut(&Upstream) -> Result<DnsPacket> {
+        let mut result = Err(Error::new(ErrorKind::NotFound, "No upstream servers"));
+
+        for idx in self.order() {
+            let upstream = &self.upstreams[idx];
+
+            let start = Instant::now();
+            result = send(upstream);
+            let rtt = start.elapsed();
+
+            match result {
+                Ok(ref packet) if packet.header.res_code != ResultCode::SERVFAIL &&
+                    packet.header.res_code != ResultCode::REFUSED => {
+                    self.record_success(idx, rtt);
+                    break;
+                },
+                Ok(ref packet) => {
+                    println!("Upstream {} failed with {:?}", upstream, packet.header.res_code);
+                    self.record_failure(idx);
+                },
+                Err(ref err) => {
+                    println!("Upstream {} failed: {:?}", upstream, err);
+                    self.record_failure(idx);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// The smoothed round trip time of an upstream, if it has answered yet
+    pub fn get_rtt(&self, idx: usize) -> Option<Duration> {
+        match self.health.lock() {
+            Ok(health) => health[idx].rtt,
+            Err(_) => None
+        }
+    }
+
+    /// The number of upstreams which are currently up
+    pub fn up_count(&self) -> usize {
+        let now = Instant::now();
+        match self.health.lock() {
+            Ok(health) => health.iter().filter(|x| !x.is_down(now)).count(),
+            Err(_) => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use dns::client::DnsClient;
+    use dns::client::tests::DnsStubClient;
+    use dns::protocol::{DnsPacket, QueryType, ResultCode};
+
+    use super::*;
+
+    fn create_pool(strategy: UpstreamStrategy) -> UpstreamPool {
+        UpstreamPool::new(vec![
+            Upstream::new("127.0.0.1", 53),
+            Upstream::new("127.0.0.2", 53),
+            Upstream::new("127.0.0.3", 53)
+        ], strategy)
+    }
+
+    #[test]
+    fn test_upstream_parse() {
+        assert_eq!(Some(Upstream::new("8.8.8.8", 53)), Upstream::parse("8.8.8.8"));
+        assert_eq!(Some(Upstream::new("127.0.0.1", 5353)), Upstream::parse("127.0.0.1:5353"));
+        assert_eq!(None, Upstream::parse("dns.google"));
+        assert_eq!(None, Upstream::parse("127.0.0.1:dns"));
+
+        assert_eq!(Some(Upstream::tls("1.1.1.1", 853, TlsAuth::new("cloudflare-dns.com"))),
+                   Upstream::parse("tls://1.1.1.1#cloudflare-dns.com"));
+        assert_eq!(Some(Upstream::tls("9.9.9.9", 8853, TlsAuth::new("9.9.9.9"))), Upstream::parse("tls://9.9.9.9:8853"));
+        assert_eq!(None, Upstream::parse("tls://1.1.1.1#"));
+        assert_eq!(None, Upstream::parse("tls://dns.google"));
+
+        let pin = "6hsq0+LkprAkpdy5e5wb33R5IHryKR8HQIlZA4HZGvE=";
+        let upstream = Upstream::parse(&format!("tls://127.0.0.1#localhost?pin={}&pin={}", pin, pin)).unwrap();
+        match upstream.protocol {
+            UpstreamProtocol::Tls { ref auth } => {
+                assert_eq!("localhost", auth.server_name);
+                assert_eq!(vec![SpkiPin::parse(pin).unwrap(); 2], auth.pins);
+            },
+            _ => panic!()
+        }
+        assert_eq!(None, Upstream::parse("tls://127.0.0.1?pin=hello"));
+        assert_eq!(None, Upstream::parse(&format!("tls://127.0.0.1?hash={}", pin)));
+
+        assert_eq!("tls://1.1.1.1:853#cloudflare-dns.com",
+                   Upstream::parse("tls://1.1.1.1#cloudflare-dns.com").unwrap().to_string());
+        assert_eq!(format!("tls://127.0.0.1:853#127.0.0.1?pin={}", pin),
+                   Upstream::parse(&format!("tls://127.0.0.1?pin={}", pin)).unwrap().to_string());
+
+        let upstream = Upstream::parse("https://dns.google/dns-query#addr=8.8.8.8&method=get").unwrap();
+        assert_eq!("8.8.8.8", upstream.host);
+        assert_eq!(443, upstream.port);
+        match upstream.protocol {
+            UpstreamProtocol::Https { ref endpoint, ref addrs } => {
+                assert_eq!("dns.google", endpoint.auth.server_name);
+                assert_eq!(HttpsMethod::Get, endpoint.method);
+                assert_eq!(vec!["8.8.8.8".to_string()], *addrs);
+            },
+            _ => panic!()
+        }
+        assert_eq!("https://dns.google/dns-query#addr=8.8.8.8&method=get", upstream.to_string());
+
+        // IPv6 addresses work as well, and more than one address can be given
+        let upstream = Upstream::parse("https://dns.google/dns-query#addr=2001:4860:4860::8888&addr=8.8.8.8").unwrap();
+        assert_eq!("2001:4860:4860::8888", upstream.host);
+        match upstream.protocol {
+            UpstreamProtocol::Https { ref addrs, .. } => {
+                assert_eq!(vec!["2001:4860:4860::8888".to_string(), "8.8.8.8".to_string()], *addrs);
+            },
+            _ => panic!()
+        }
+        assert_eq!("https://dns.google/dns-query#addr=2001:4860:4860::8888&addr=8.8.8.8", upstream.to_string());
+
+        assert_eq!("https://[2001:db8::1]/dns-query#addr=2001:db8::1",
+                   Upstream::parse("https://[2001:db8::1]/dns-query").unwrap().to_string());
+
+        assert_eq!("https://1.1.1.1:8443/dns-query#addr=1.1.1.1",
+                   Upstream::parse("https://1.1.1.1:8443/dns-query").unwrap().to_string());
+
+        // The address of a server given by name has to be known up front
+        assert_eq!(None, Upstream::parse("https://dns.google/dns-query"));
+        assert_eq!(None, Upstream::parse("https://dns.google/dns-query#addr=dns.google"));
+        assert_eq!(None, Upstream::parse("https://dns.google/dns-query#addr=8.8.8.8&proxy=1"));
+    }
+
+    #[test]
+    fn test_upstream_protocols() {
+        let client = DnsStubClient::new(Box::new(|_, _, server, _| {
+            if server == ("9.9.9.9", 853) {
+                return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+            }
+
+            assert_eq!(("2001:4860:4860::8888", 443), server);
+            Ok(DnsPacket::new())
+        }));
+
+        let pool = UpstreamPool::new(vec![
+            Upstream::parse("tls://9.9.9.9#dns.quad9.net").unwrap(),
+            Upstream::parse("https://dns.google/dns-query#addr=2001:4860:4860::8888").unwrap(),
+            Upstream::new("127.0.0.1", 53)
+        ], UpstreamStrategy::Failover);
+
+        // Every query is sent using the protocol of the upstream it's for
+        assert!(pool.query(|upstream| client.send_query("google.com", QueryType::A, upstream, true)).is_ok());
+
+        let protocols = client.get_protocols();
+        assert_eq!(2, protocols.len());
+        assert_eq!(pool.get_upstreams()[0].protocol, protocols[0]);
+        assert_eq!(pool.get_upstreams()[1].protocol, protocols[1]);
+    }
+
+    #[test]
+    fn test_upstream_strategies() {
+        let pool = create_pool(UpstreamStrategy::Failover);
+        assert_eq!(vec![0, 1, 2], pool.order());
+        assert_eq!(vec![0, 1, 2], pool.order());
+
+        let pool = create_pool(UpstreamStrategy::RoundRobin);
+        assert_eq!(vec![0, 1, 2], pool.order());
+        assert_eq!(vec![1, 2, 0], pool.order());
+        assert_eq!(vec![2, 0, 1], pool.order());
+
+        let pool = create_pool(UpstreamStrategy::Random);
+        let mut order = pool.order();
+        order.sort();
+        assert_eq!(vec![0, 1, 2], order);
+
+        // Unmeasured upstreams are tried first, then the fastest
+        let pool = create_pool(UpstreamStrategy::Fastest);
+        pool.record_success(0, Duration::from_millis(50));
+        pool.record_success(1, Duration::from_millis(10));
+        assert_eq!(vec![2, 1, 0], pool.order());
+        pool.record_success(2, Duration::from_millis(30));
+        assert_eq!(1, pool.order()[0]);
+    }
+
+    #[test]
+    fn test_upstream_failover() {
+        let pool = create_pool(UpstreamStrategy::Failover);
+
+        // The first upstream times out, and the second answers
+        let mut tried = Vec::new();
+        let result = pool.query(|upstream| {
+            tried.push(upstream.host.clone());
+            if upstream.host == "127.0.0.1" {
+                return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+            }
+
+            Ok(DnsPacket::new())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(vec!["127.0.0.1", "127.0.0.2"], tried);
+        assert!(!pool.is_down(0));
+
+        // After failing repeatedly, it's marked down and tried last
+        for _ in 0..2 {
+            let _ = pool.query(|upstream| {
+                if upstream.host == "127.0.0.1" {
+                    return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+                }
+
+                Ok(DnsPacket::new())
+            });
+        }
+
+        assert!(pool.is_down(0));
+        assert_eq!(2, pool.up_count());
+        assert_eq!(vec![1, 2, 0], pool.order());
+
+        // Once it's time to probe it again, it answers and is back up
+        if let Ok(mut health) = pool.health.lock() {
+            health[0].down_until = Some(Instant::now());
+        }
+        assert_eq!(vec![0, 1, 2], pool.order());
+
+        let _ = pool.query(|_| Ok(DnsPacket::new()));
+        assert!(!pool.is_down(0));
+        assert!(pool.get_rtt(0).is_some());
+    }
+
+    #[test]
+    fn test_upstream_servfail() {
+        let pool = create_pool(UpstreamStrategy::Failover);
+
+        // When every upstream fails, the last failure is returned
+        let mut count = 0;
+        let result = pool.query(|_| {
+            count += 1;
+
+            let mut packet = DnsPacket::new();
+            packet.header.res_code = ResultCode::SERVFAIL;
+            Ok(packet)
+        });
+
+        assert_eq!(3, count);
+        assert_eq!(ResultCode::SERVFAIL, result.unwrap().header.res_code);
+
+        let pool = UpstreamPool::new(Vec::new(), UpstreamStrategy::Failover);
+        assert!(pool.query(|_| Ok(DnsPacket::new())).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! borrowed views of DNS packets, for when a full parse isn't needed
+//!
+//! `DnsPacket::from_buffer` decodes everything up front, and allocates a string
+//! for every name in the packet. `DnsPacketRef` validates the structure of a
+//! packet held in a byte slice, and leaves the decoding of names and records
+//! to whoever needs them.
+
+use std::fmt;
+use std::io::Result;
+
+use dns::buffer::{PacketBuffer, SlicePacketBuffer, WireError, MAX_JUMPS, MAX_NAME_LENGTH};
+use dns::protocol::{DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType};
+
+fn get_u16(data: &[u8], pos: usize) -> u16 {
+    ((data[pos] as u16) << 8) | (data[pos + 1] as u16)
+}
+
+fn get_u32(data: &[u8], pos: usize) -> u32 {
+    ((get_u16(data, pos) as u32) << 16) | (get_u16(data, pos + 2) as u32)
+}
+
+/// Validate the name starting at `pos`, and return the position following it
+///
+/// The rules are the same as for `PacketBuffer::read_qname`, so the labels of
+/// a name that passed can be walked without any further checks.
+fn skip_name(data: &[u8], start: usize) -> Result<usize> {
+    let mut pos = start;
+    let mut end = None;
+    let mut pointer_limit = pos;
+    let mut jumps = 0;
+    let mut name_len = 0;
+
+    loop {
+        let len = *data.get(pos).ok_or(WireError::EndOfBuffer)?;
+
+        match len & 0xC0 {
+            0xC0 => {
+                let b2 = *data.get(pos + 1).ok_or(WireError::EndOfBuffer)? as usize;
+                let offset = (((len as usize) & 0x3F) << 8) | b2;
+
+                if offset >= pointer_limit {
+                    return Err(WireError::ForwardPointer(offset).into());
+                }
+
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(WireError::TooManyJumps.into());
+                }
+
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+
+                pos = offset;
+                pointer_limit = offset;
+                continue;
+            },
+            0x00 => {},
+            _ => return Err(WireError::BadLabelType(len).into())
+        }
+
+        name_len += len as usize + 1;
+        if name_len > MAX_NAME_LENGTH {
+            return Err(WireError::NameTooLong.into());
+        }
+
+        let next = pos + 1 + len as usize;
+        if next > data.len() {
+            return Err(WireError::EndOfBuffer.into());
+        }
+
+        if len == 0 {
+            return Ok(end.unwrap_or(next));
+        }
+
+        pos = next;
+    }
+}
+
+/// Validate the record starting at `pos`, and return the position following it
+fn skip_record(data: &[u8], pos: usize) -> Result<usize> {
+    let pos = skip_name(data, pos)?;
+    if pos + 10 > data.len() {
+        return Err(WireError::EndOfBuffer.into());
+    }
+
+    let end = pos + 10 + get_u16(data, pos + 8) as usize;
+    if end > data.len() {
+        return Err(WireError::EndOfBuffer.into());
+    }
+
+    Ok(end)
+}
+
+/// A name within a packet, which is decoded when asked for
+#[derive(Clone,Copy,Debug)]
+pub struct NameRef<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> NameRef<'a> {
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            data: self.data,
+            pos: self.pos
+        }
+    }
+
+    /// Compare with a name in presentation format, disregarding case
+    pub fn eq_ignore_case(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+
+        let mut labels = self.labels();
+        if !name.is_empty() {
+            for part in name.split('.') {
+                match labels.next() {
+                    Some(label) if label.eq_ignore_ascii_case(part.as_bytes()) => {},
+                    _ => return false
+                }
+            }
+        }
+
+        labels.next().is_none()
+    }
+}
+
+impl<'a> fmt::Display for NameRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", String::from_utf8_lossy(label))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Iterator over the labels of a name, following compression pointers
+pub struct Labels<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        loop {
+            let len = self.data[self.pos] as usize;
+            if len & 0xC0 == 0xC0 {
+                self.pos = ((len & 0x3F) << 8) | self.data[self.pos + 1] as usize;
+                continue;
+            }
+
+            if len == 0 {
+                return None;
+            }
+
+            let label = &self.data[self.pos + 1..self.pos + 1 + len];
+            self.pos += 1 + len;
+
+            return Some(label);
+        }
+    }
+}
+
+/// A question within a packet
+#[derive(Clone,Copy,Debug)]
+pub struct QuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: QueryType,
+    pub qclass: QueryClass
+}
+
+impl<'a> QuestionRef<'a> {
+    pub fn to_question(&self) -> DnsQuestion {
+        DnsQuestion::with_class(self.name.to_string(), self.qtype, self.qclass)
+    }
+}
+
+/// A resource record within a packet, with the RDATA left undecoded
+#[derive(Clone,Copy,Debug)]
+pub struct RecordRef<'a> {
+    data: &'a [u8],
+    pos: usize,
+    pub name: NameRef<'a>,
+    pub qtype: QueryType,
+    pub class: QueryClass,
+    pub ttl: u32,
+    pub rdata: &'a [u8]
+}
+
+impl<'a> RecordRef<'a> {
+    pub fn to_record(&self) -> Result<DnsRecord> {
+        let mut buffer = SlicePacketBuffer::new(self.data);
+        buffer.seek(self.pos)?;
+
+        DnsRecord::read(&mut buffer)
+    }
+}
+
+/// Iterator over the questions of a packet
+pub struct Questions<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: u16
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = QuestionRef<'a>;
+
+    fn next(&mut self) -> Option<QuestionRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let name = NameRef { data: self.data, pos: self.pos };
+        let end = skip_name(self.data, self.pos).ok()?;
+
+        self.pos = end + 4;
+        self.remaining -= 1;
+
+        Some(QuestionRef {
+            name,
+            qtype: QueryType::from_num(get_u16(self.data, end)),
+            qclass: QueryClass::from_num(get_u16(self.data, end + 2))
+        })
+    }
+}
+
+/// Iterator over the records of a section
+pub struct Records<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: u16
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = RecordRef<'a>;
+
+    fn next(&mut self) -> Option<RecordRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let start = self.pos;
+        let end = skip_name(self.data, start).ok()?;
+        let data_len = get_u16(self.data, end + 8) as usize;
+
+        self.pos = end + 10 + data_len;
+        self.remaining -= 1;
+
+        Some(RecordRef {
+            data: self.data,
+            pos: start,
+            name: NameRef { data: self.data, pos: start },
+            qtype: QueryType::from_num(get_u16(self.data, end)),
+            class: QueryClass::from_num(get_u16(self.data, end + 2)),
+            ttl: get_u32(self.data, end + 4),
+            rdata: &self.data[end + 10..self.pos]
+        })
+    }
+}
+
+/// A packet borrowed from a byte slice
+///
+/// Parsing only checks that the names and record boundaries are sound, and
+/// doesn't allocate. Everything else is decoded lazily, through the iterators
+/// over each section.
+pub struct DnsPacketRef<'a> {
+    data: &'a [u8],
+    pub header: DnsHeader,
+
+    /// Start of the question, answer, authority and additional sections
+    sections: [usize; 4]
+}
+
+impl<'a> DnsPacketRef<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<DnsPacketRef<'a>> {
+        let mut buffer = SlicePacketBuffer::new(data);
+        let mut header = DnsHeader::new();
+        header.read(&mut buffer)?;
+
+        let mut sections = [0; 4];
+        let mut pos = buffer.pos();
+
+        sections[0] = pos;
+        for _ in 0..header.questions {
+            pos = skip_name(data, pos)? + 4;
+            if pos > data.len() {
+                return Err(WireError::EndOfBuffer.into());
+            }
+        }
+
+        let counts = [header.answers, header.authoritative_entries, header.resource_entries];
+        for (i, count) in counts.iter().enumerate() {
+            sections[i + 1] = pos;
+            for _ in 0..*count {
+                pos = skip_record(data, pos)?;
+            }
+        }
+
+        Ok(DnsPacketRef {
+            data,
+            header,
+            sections
+        })
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            data: self.data,
+            pos: self.sections[0],
+            remaining: self.header.questions
+        }
+    }
+
+    fn records(&self, section: usize, count: u16) -> Records<'a> {
+        Records {
+            data: self.data,
+            pos: self.sections[section],
+            remaining: count
+        }
+    }
+
+    pub fn answers(&self) -> Records<'a> {
+        self.records(1, self.header.answers)
+    }
+
+    pub fn authorities(&self) -> Records<'a> {
+        self.records(2, self.header.authoritative_entries)
+    }
+
+    pub fn resources(&self) -> Records<'a> {
+        self.records(3, self.header.resource_entries)
+    }
+
+    /// The UDP payload size advertised through EDNS, if any
+    pub fn edns_payload_size(&self) -> Option<u16> {
+        self.resources()
+            .find(|rec| rec.qtype == QueryType::OPT)
+            .map(|rec| rec.class.to_num())
+    }
+
+    /// Decode the entire packet
+    pub fn to_packet(&self) -> Result<DnsPacket> {
+        DnsPacket::from_buffer(&mut SlicePacketBuffer::new(self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use dns::buffer::VectorPacketBuffer;
+    use dns::protocol::TransientTtl;
+
+    fn build_packet() -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1337;
+        packet.header.recursion_desired = true;
+        packet.questions.push(DnsQuestion::new("WwW.Google.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.google.com".to_string(),
+            class: QueryClass::IN,
+            host: "google.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(DnsRecord::A {
+            domain: "google.com".to_string(),
+            class: QueryClass::IN,
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(300)
+        });
+        packet.authorities.push(DnsRecord::NS {
+            domain: "google.com".to_string(),
+            class: QueryClass::IN,
+            host: "ns1.google.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            data: Vec::new()
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.buffer
+    }
+
+    #[test]
+    fn test_packet_ref() {
+        let data = build_packet();
+        let packet = DnsPacket::from_buffer(&mut SlicePacketBuffer::new(&data)).unwrap();
+        let packet_ref = DnsPacketRef::parse(&data).unwrap();
+
+        assert_eq!(1337, packet_ref.header.id);
+        assert!(packet_ref.header.recursion_desired);
+
+        let questions = packet_ref.questions().collect::<Vec<_>>();
+        assert_eq!(1, questions.len());
+        assert_eq!("WwW.Google.com", questions[0].name.to_string());
+        assert!(questions[0].name.eq_ignore_case("www.google.com."));
+        assert!(!questions[0].name.eq_ignore_case("google.com"));
+        assert!(!questions[0].name.eq_ignore_case("a.www.google.com"));
+        assert_eq!(QueryType::A, questions[0].qtype);
+        assert_eq!(packet.questions[0], questions[0].to_question());
+
+        // The names of records are compressed, and can still be compared
+        let answers = packet_ref.answers().collect::<Vec<_>>();
+        assert_eq!(2, answers.len());
+        assert_eq!("www.google.com", answers[0].name.to_string());
+        assert_eq!(QueryType::CNAME, answers[0].qtype);
+        assert_eq!(3600, answers[0].ttl);
+        assert_eq!("google.com", answers[1].name.to_string());
+        assert_eq!(vec![127, 0, 0, 1], answers[1].rdata.to_vec());
+
+        for (rec_ref, rec) in packet_ref.answers().zip(&packet.answers) {
+            assert_eq!(*rec, rec_ref.to_record().unwrap());
+        }
+        for (rec_ref, rec) in packet_ref.authorities().zip(&packet.authorities) {
+            assert_eq!(*rec, rec_ref.to_record().unwrap());
+        }
+
+        assert_eq!(Some(4096), packet_ref.edns_payload_size());
+        assert_eq!(packet.answers, packet_ref.to_packet().unwrap().answers);
+    }
+
+    #[test]
+    fn test_invalid_packet_ref() {
+        let data = build_packet();
+
+        // Every truncated version of the packet is refused
+        for len in 0..data.len() {
+            assert!(DnsPacketRef::parse(&data[..len]).is_err());
+        }
+
+        // As is a question pointing to itself
+        let mut data = data[..12].to_vec();
+        data.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+        data[7] = 0;
+        data[9] = 0;
+        data[11] = 0;
+
+        let err = DnsPacketRef::parse(&data).err().unwrap();
+        assert_eq!(Some(&WireError::ForwardPointer(12)), WireError::from_io(&err));
+    }
+}
@@ -0,0 +1,28 @@
+//! hermes documentation
+
+#![cfg_attr(feature="clippy", feature(plugin))]
+#![cfg_attr(feature="clippy", plugin(clippy))]
+
+pub mod dns;
+pub mod web;
+
+extern crate rand;
+extern crate chrono;
+extern crate tiny_http;
+//extern crate rustc_serialize;
+extern crate ascii;
+extern crate handlebars;
+extern crate regex;
+extern crate rustls;
+extern crate webpki_roots;
+extern crate webpki;
+extern crate ring;
+extern crate base64;
+extern crate bytes;
+extern crate h2;
+extern crate http;
+extern crate tokio;
+extern crate tokio_rustls;
+
+#[cfg(test)]
+extern crate proptest;
@@ -3,29 +3,25 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
-pub mod dns;
-//pub mod web;
-
-extern crate rand;
-extern crate chrono;
-//extern crate tiny_http;
-//extern crate rustc_serialize;
-extern crate ascii;
-extern crate handlebars;
-extern crate regex;
+extern crate hermes;
 extern crate getopts;
+extern crate ctrlc;
 
 use std::env;
 use std::sync::Arc;
-use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::net::IpAddr;
 
 use getopts::Options;
 
-use dns::server::{DnsServer,DnsUdpServer,DnsTcpServer};
-use dns::protocol::{DnsRecord,TransientTtl};
-use dns::context::{ServerContext, ResolveStrategy};
-//use web::server::WebServer;
-//use web::cache::CacheAction;
+use hermes::dns::server::{DnsServer,DnsUdpServer,DnsTcpServer,DnsTlsServer};
+use hermes::dns::tls::ServerCertificate;
+use hermes::dns::protocol::{DnsRecord,QueryClass,TransientTtl};
+use hermes::dns::context::{ServerContext, ResolveStrategy};
+use hermes::dns::prefetch::RefreshQueue;
+use hermes::dns::upstream::{Upstream, UpstreamPool, UpstreamStrategy};
+use hermes::web::server::WebServer;
+use hermes::web::cache::CacheAction;
 //use web::authority::{AuthorityAction,ZoneAction};
 //use web::index::IndexAction;
 
@@ -42,9 +38,27 @@ fn main() {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("a", "authority", "disable support for recursive lookups, and serve only local zones");
-    opts.optopt("f", "forward", "forward replies to specified dns server", "SERVER");
+    opts.optmulti("f", "forward", "forward replies to specified dns server, which can be given more than once, prefixed with tls:// for DNS over TLS, or an https:// URL for DNS over HTTPS", "SERVER[:PORT]");
+    opts.optopt("", "forward-strategy", "order in which the forward servers are tried: failover, round-robin, random or fastest", "failover");
+    opts.optmulti("", "route", "resolve names under SUFFIX using TARGET, which is recursive, authority or a comma separated list of forward servers", "SUFFIX=TARGET");
     opts.optopt("t", "threads", "count of precreated threads in pools", "32");
     opts.optopt("p", "port", "listening port", "53");
+    opts.optopt("", "version-bind", "answer CHAOS TXT queries for version.bind with VALUE", "VALUE");
+    opts.optopt("", "hostname-bind", "answer CHAOS TXT queries for hostname.bind with VALUE", "VALUE");
+    opts.optopt("", "id-server", "answer CHAOS TXT queries for id.server with VALUE", "VALUE");
+    opts.optopt("", "cache-size", "maximum number of domains kept in the cache, 0 for no limit", "100000");
+    opts.optopt("", "cache-snapshot", "save the cache to FILE periodically and on shutdown, and load it on startup", "FILE");
+    opts.optopt("", "min-ttl", "lowest TTL of records stored in the cache", "0");
+    opts.optopt("", "max-ttl", "highest TTL of records stored in the cache, 0 for no limit", "0");
+    opts.optopt("", "prefetch", "percentage at the end of the TTL in which popular names are refreshed, 0 to disable", "10");
+    opts.optopt("", "stale-window", "seconds past expiry that cached records are served for when resolution fails, 0 to disable", "86400");
+    opts.optopt("", "tls-cert", "serve DNS over TLS using the PEM certificate chain in FILE, which is reloaded when it changes", "FILE");
+    opts.optopt("", "tls-key", "PEM private key of the DNS over TLS certificate", "FILE");
+    opts.optopt("", "tls-port", "listening port for DNS over TLS", "853");
+    opts.optopt("", "tls-idle-timeout", "seconds a DNS over TLS connection is kept open without queries", "10");
+    opts.optopt("", "api-bind", "address the HTTP API listens on", "127.0.0.1");
+    opts.optflag("", "disable-api", "disable the HTTP API");
+    opts.optflag("", "api-allow-delete", "allow flushing and removing cache entries through the HTTP API");
 
     let opt_matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -60,19 +74,52 @@ fn main() {
 
     if let Some(ctx) = Arc::get_mut(&mut context) {
 
+        let strategy = match opt_matches.opt_str("forward-strategy") {
+            Some(name) => match UpstreamStrategy::parse(&name) {
+                Some(x) => x,
+                None => {
+                    println!("Forward strategy must be one of failover, round-robin, random or fastest");
+                    return;
+                }
+            },
+            None => UpstreamStrategy::Failover
+        };
+
         let mut index_rootservers = true;
         if opt_matches.opt_present("f") {
-            match opt_matches.opt_str("f").and_then(|x| x.parse::<Ipv4Addr>().ok()) {
-                Some(ip) => {
-                    ctx.resolve_strategy = ResolveStrategy::Forward {
-                        host: ip.to_string(),
-                        port: 53
-                    };
-                    index_rootservers = false;
-                    println!("Running as forwarder");
+            let mut upstreams = Vec::new();
+            for server in opt_matches.opt_strs("f") {
+                match Upstream::parse(&server) {
+                    Some(upstream) => upstreams.push(upstream),
+                    None => {
+                        println!("Forward parameter must be a valid Ipv4 address, optionally followed by a port, a tls:// server or an https:// URL with the address of its server");
+                        return;
+                    }
+                }
+            }
+
+            println!("Running as forwarder to {} server(s), using {:?} strategy", upstreams.len(), strategy);
+            ctx.resolve_strategy = ResolveStrategy::Forward {
+                upstreams: Arc::new(UpstreamPool::new(upstreams, strategy))
+            };
+            index_rootservers = false;
+        }
+
+        for route in opt_matches.opt_strs("route") {
+            let parsed = route.find('=')
+                .and_then(|idx| ResolveStrategy::parse(&route[idx+1..], strategy).map(|x| (&route[..idx], x)));
+
+            match parsed {
+                Some((suffix, route_strategy)) => {
+                    if let ResolveStrategy::Recursive = route_strategy {
+                        index_rootservers = true;
+                    }
+
+                    println!("Routing {} to {}", suffix, &route[suffix.len()+1..]);
+                    ctx.add_route(suffix, route_strategy);
                 },
                 None => {
-                    println!("Forward parameter must be a valid Ipv4 address");
+                    println!("Route parameter must be SUFFIX=TARGET, where TARGET is recursive, authority or a list of forward servers");
                     return;
                 }
             }
@@ -105,10 +152,128 @@ fn main() {
             }
         }
 
+        if opt_matches.opt_present("cache-size") {
+            match opt_matches.opt_str("cache-size").and_then(|x| x.parse::<usize>().ok()) {
+                Some(entries) => {
+                    ctx.cache_max_entries = entries;
+                    println!("Keeping at most {} domains in the cache", entries);
+                },
+                None => {
+                    println!("Cache size must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        ctx.cache_snapshot_path = opt_matches.opt_str("cache-snapshot").map(PathBuf::from);
+
+        if opt_matches.opt_present("min-ttl") {
+            match opt_matches.opt_str("min-ttl").and_then(|x| x.parse::<u32>().ok()) {
+                Some(ttl) => {
+                    ctx.cache_min_ttl = ttl;
+                    println!("Caching records for at least {} seconds", ttl);
+                },
+                None => {
+                    println!("Minimum TTL must be a number of seconds");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("max-ttl") {
+            match opt_matches.opt_str("max-ttl").and_then(|x| x.parse::<u32>().ok()) {
+                Some(ttl) => {
+                    ctx.cache_max_ttl = ttl;
+                    println!("Caching records for at most {} seconds", ttl);
+                },
+                None => {
+                    println!("Maximum TTL must be a number of seconds");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("prefetch") {
+            match opt_matches.opt_str("prefetch").and_then(|x| x.parse::<u32>().ok()).filter(|x| *x <= 100) {
+                Some(percent) => {
+                    ctx.prefetch_threshold = percent;
+                    println!("Prefetching popular names in the last {}% of their TTL", percent);
+                },
+                None => {
+                    println!("Prefetch parameter must be a percentage in 0..100 range");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("stale-window") {
+            match opt_matches.opt_str("stale-window").and_then(|x| x.parse::<u32>().ok()) {
+                Some(window) => {
+                    ctx.stale_window = window;
+                    println!("Serving stale data up to {} seconds past expiry", window);
+                },
+                None => {
+                    println!("Stale window must be a number of seconds");
+                    return;
+                }
+            }
+        }
+
+        ctx.tls_cert_path = opt_matches.opt_str("tls-cert").map(PathBuf::from);
+        ctx.tls_key_path = opt_matches.opt_str("tls-key").map(PathBuf::from);
+        if ctx.tls_cert_path.is_some() != ctx.tls_key_path.is_some() {
+            println!("Both a certificate and a key must be given for DNS over TLS");
+            return;
+        }
+
+        if opt_matches.opt_present("tls-port") {
+            match opt_matches.opt_str("tls-port").and_then(|x| x.parse::<u16>().ok()) {
+                Some(port) => {
+                    ctx.tls_port = port;
+                },
+                None => {
+                    println!("TLS port number must be positive number in 1..65535 range");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("tls-idle-timeout") {
+            match opt_matches.opt_str("tls-idle-timeout").and_then(|x| x.parse::<u64>().ok()).filter(|x| *x > 0) {
+                Some(timeout) => {
+                    ctx.tls_idle_timeout = timeout;
+                    println!("Closing DNS over TLS connections after {} idle seconds", timeout);
+                },
+                None => {
+                    println!("TLS idle timeout must be a positive number of seconds");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("api-bind") {
+            match opt_matches.opt_str("api-bind").filter(|x| x.parse::<IpAddr>().is_ok()) {
+                Some(addr) => {
+                    ctx.api_bind_ip = addr;
+                },
+                None => {
+                    println!("API bind address must be an IP address");
+                    return;
+                }
+            }
+        }
+
+        ctx.enable_api = !opt_matches.opt_present("disable-api");
+        ctx.api_allow_delete = opt_matches.opt_present("api-allow-delete");
+
         if opt_matches.opt_present("a") {
             ctx.allow_recursive = false;
         }
 
+        ctx.chaos_version = opt_matches.opt_str("version-bind");
+        ctx.chaos_hostname = opt_matches.opt_str("hostname-bind");
+        ctx.chaos_id = opt_matches.opt_str("id-server");
+
         match ctx.initialize() {
             Ok(_) => {},
             Err(e) => {
@@ -118,7 +283,9 @@ fn main() {
         }
 
         if index_rootservers {
-            let _ = ctx.cache.store(&get_rootservers());
+            if let Err(e) = ctx.cache.store_hints(&get_rootservers()) {
+                println!("Failed to store root hints: {:?}", e);
+            }
         }
     }
 
@@ -137,78 +304,119 @@ fn main() {
         }
     }
 
-    println!("Listening on port {}", context.dns_port);
+    if let (Some(cert_path), Some(key_path)) = (context.tls_cert_path.as_ref(), context.tls_key_path.as_ref()) {
+        match ServerCertificate::load(cert_path, key_path) {
+            Ok(certificate) => {
+                let tls_server = DnsTlsServer::new(context.clone(), Arc::new(certificate), context.threads_tcp);
+                if let Err(e) = tls_server.run_server() {
+                    println!("Failed to bind TLS listener: {:?}", e);
+                } else {
+                    println!("Listening on port {} for DNS over TLS", context.tls_port);
+                }
+            },
+            Err(e) => println!("Failed to load TLS certificate {}: {:?}", cert_path.display(), e)
+        }
+    }
 
-    loop {
-        use std::thread;
-        thread::sleep(std::time::Duration::from_millis(500));
+    if let Err(e) = RefreshQueue::run(context.clone(), context.threads_refresh) {
+        println!("Failed to start refresh threads: {:?}", e);
+    }
+
+    if let Err(e) = ServerContext::run_cache_sweeper(context.clone()) {
+        println!("Failed to start cache sweeper: {:?}", e);
+    }
+
+    if let Err(e) = ServerContext::run_cache_snapshots(context.clone()) {
+        println!("Failed to start cache snapshots: {:?}", e);
+    }
+
+    // Save the cache on the way out, so that it's warm after a restart
+    let shutdown_context = context.clone();
+    let shutdown = ctrlc::set_handler(move || {
+        shutdown_context.save_cache_snapshot();
+        std::process::exit(0);
+    });
+    if let Err(e) = shutdown {
+        println!("Failed to install shutdown handler: {:?}", e);
     }
+
     // Start web server
-    /*if context.enable_api {
+    if context.enable_api {
         let mut webserver = WebServer::new(context.clone());
 
         webserver.register_action(Box::new(CacheAction::new(context.clone())));
-        webserver.register_action(Box::new(AuthorityAction::new(context.clone())));
-        webserver.register_action(Box::new(ZoneAction::new(context.clone())));
-        webserver.register_action(Box::new(IndexAction::new(context.clone())));
+        //webserver.register_action(Box::new(AuthorityAction::new(context.clone())));
+        //webserver.register_action(Box::new(ZoneAction::new(context.clone())));
+        //webserver.register_action(Box::new(IndexAction::new(context.clone())));
+
+        if let Err(e) = webserver.run_webserver() {
+            println!("Failed to start API server: {:?}", e);
+        } else {
+            println!("API listening on {}:{}", context.api_bind_ip, context.api_port);
+        }
+    }
 
-        webserver.run_webserver();
-    }*/
+    println!("Listening on port {}", context.dns_port);
+
+    loop {
+        use std::thread;
+        thread::sleep(std::time::Duration::from_millis(500));
+    }
 }
 
 fn get_rootservers() -> Vec<DnsRecord>
 {
     let mut rootservers = Vec::new();
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "a.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "a.root-servers.net".to_string(), addr: "198.41.0.4".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "a.root-servers.net".to_string(), addr: "2001:503:ba3e::2:30".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "a.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "a.root-servers.net".to_string(), class: QueryClass::IN, addr: "198.41.0.4".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "a.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:503:ba3e::2:30".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "b.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "b.root-servers.net".to_string(), addr: "199.9.14.201".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "b.root-servers.net".to_string(), addr: "2001:500:84::b".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "b.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "b.root-servers.net".to_string(), class: QueryClass::IN, addr: "199.9.14.201".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "b.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:500:84::b".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "c.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "c.root-servers.net".to_string(), addr: "192.33.4.12".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "c.root-servers.net".to_string(), addr: "2001:500:2::c".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "c.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "c.root-servers.net".to_string(), class: QueryClass::IN, addr: "192.33.4.12".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "c.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:500:2::c".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "d.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "d.root-servers.net".to_string(), addr: "199.7.91.13".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "d.root-servers.net".to_string(), addr: "2001:500:2d::d".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "d.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "d.root-servers.net".to_string(), class: QueryClass::IN, addr: "199.7.91.13".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "d.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:500:2d::d".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "e.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "e.root-servers.net".to_string(), addr: "192.203.230.10".parse().unwrap(),ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "e.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "e.root-servers.net".to_string(), class: QueryClass::IN, addr: "192.203.230.10".parse().unwrap(),ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "f.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "f.root-servers.net".to_string(), addr: "192.5.5.241".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "f.root-servers.net".to_string(), addr: "2001:500:2f::f".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "f.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "f.root-servers.net".to_string(), class: QueryClass::IN, addr: "192.5.5.241".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "f.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:500:2f::f".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(),  host: "g.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "g.root-servers.net".to_string(), addr: "192.112.36.4".parse().unwrap(),ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN,  host: "g.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "g.root-servers.net".to_string(), class: QueryClass::IN, addr: "192.112.36.4".parse().unwrap(),ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "h.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "h.root-servers.net".to_string(), addr: "198.97.190.53".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "h.root-servers.net".to_string(), addr: "2001:500:1::53".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "h.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "h.root-servers.net".to_string(), class: QueryClass::IN, addr: "198.97.190.53".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "h.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:500:1::53".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "i.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "i.root-servers.net".to_string(), addr: "192.36.148.17".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "i.root-servers.net".to_string(), addr: "2001:7fe::53".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "i.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "i.root-servers.net".to_string(), class: QueryClass::IN, addr: "192.36.148.17".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "i.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:7fe::53".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "j.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "j.root-servers.net".to_string(), addr: "192.58.128.30".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "j.root-servers.net".to_string(), addr: "2001:503:c27::2:30".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "j.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "j.root-servers.net".to_string(), class: QueryClass::IN, addr: "192.58.128.30".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "j.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:503:c27::2:30".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "k.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "k.root-servers.net".to_string(), addr: "193.0.14.129".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "k.root-servers.net".to_string(), addr: "2001:7fd::1".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "k.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "k.root-servers.net".to_string(), class: QueryClass::IN, addr: "193.0.14.129".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "k.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:7fd::1".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "l.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "l.root-servers.net".to_string(), addr: "199.7.83.42".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "l.root-servers.net".to_string(), addr: "2001:500:3::42".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "l.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "l.root-servers.net".to_string(), class: QueryClass::IN, addr: "199.7.83.42".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "l.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:500:3::42".parse().unwrap(), ttl: TransientTtl(3600000) });
 
-    rootservers.push(DnsRecord::NS { domain: ".".to_string(), host: "m.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
-    rootservers.push(DnsRecord::A{ domain: "m.root-servers.net".to_string(), addr: "202.12.27.33".parse().unwrap(),ttl: TransientTtl(3600000) });
-    //rootservers.push(DnsRecord::AAAA { domain: "m.root-servers.net".to_string(), addr: "2001:dc3::35".parse().unwrap(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::NS { domain: ".".to_string(), class: QueryClass::IN, host: "m.root-servers.net".to_string(), ttl: TransientTtl(3600000) });
+    rootservers.push(DnsRecord::A{ domain: "m.root-servers.net".to_string(), class: QueryClass::IN, addr: "202.12.27.33".parse().unwrap(),ttl: TransientTtl(3600000) });
+    //rootservers.push(DnsRecord::AAAA { domain: "m.root-servers.net".to_string(), class: QueryClass::IN, addr: "2001:dc3::35".parse().unwrap(), ttl: TransientTtl(3600000) });
 
     rootservers
 }
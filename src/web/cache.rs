@@ -0,0 +1,130 @@
+//! the /cache endpoint, for inspecting and flushing the cache
+
+use std::io::Result;
+use std::sync::Arc;
+
+use regex::{Captures, Regex};
+use tiny_http::{Method, Request};
+
+use dns::cache::DomainDump;
+use dns::context::ServerContext;
+use dns::protocol::{parse_type_name, type_name};
+use web::server::{Action, json_string, respond_error, respond_json};
+
+/// Lists the cache on GET, and removes entries from it on DELETE
+///
+/// * `DELETE /cache` flushes the whole cache
+/// * `DELETE /cache/<name>` removes a name
+/// * `DELETE /cache/*.<name>` removes a name along with every name below it
+/// * `DELETE /cache/<name>/<type>` removes the records of one type
+pub struct CacheAction {
+    context: Arc<ServerContext>
+}
+
+impl CacheAction {
+    pub fn new(context: Arc<ServerContext>) -> CacheAction {
+        CacheAction {
+            context
+        }
+    }
+
+    fn list(&self, request: Request) -> Result<()> {
+        let statistics = self.context.cache.get_statistics()?;
+        let entries = self.context.cache.dump()?;
+
+        let entries = entries.iter().map(dump_to_json).collect::<Vec<String>>();
+
+        let body = format!("{{\"statistics\":{{\"entries\":{},\"evictions\":{},\"purged\":{}}},\"entries\":[{}]}}",
+                           statistics.entries,
+                           statistics.evictions,
+                           statistics.purged,
+                           entries.join(","));
+
+        respond_json(request, 200, body)
+    }
+
+    fn delete(&self, request: Request, name: Option<&str>, qtype: Option<&str>) -> Result<()> {
+        if !self.context.api_allow_delete {
+            return respond_error(request, 403, "Removing cache entries is disabled, see --api-allow-delete");
+        }
+
+        let cache = &self.context.cache;
+
+        let removed = match (name, qtype) {
+            (None, _) => cache.flush()?,
+            (Some(name), None) if name.starts_with("*.") => cache.remove_subtree(&name[2..])?,
+            (Some(name), None) => cache.remove(name, None)? as usize,
+            (Some(name), Some(qtype)) => match parse_type_name(qtype) {
+                Some(qtype) => cache.remove(name, Some(qtype))? as usize,
+                None => return respond_error(request, 400, "Unknown record type")
+            }
+        };
+
+        respond_json(request, 200, format!("{{\"removed\":{}}}", removed))
+    }
+}
+
+fn dump_to_json(dump: &DomainDump) -> String {
+    let records = dump.records.iter().map(|rec| {
+        format!("{{\"type\":{},\"ttl\":{},\"data\":{}}}",
+                json_string(&type_name(rec.get_querytype())),
+                rec.get_ttl(),
+                json_string(&rec.rdata_to_string()))
+    }).collect::<Vec<String>>();
+
+    let nodata = dump.nodata.iter()
+        .map(|qtype| json_string(&type_name(*qtype)))
+        .collect::<Vec<String>>();
+
+    format!("{{\"domain\":{},\"hits\":{},\"nxdomain\":{},\"nodata\":[{}],\"records\":[{}]}}",
+            json_string(&dump.domain),
+            dump.hits,
+            dump.nxdomain,
+            nodata.join(","),
+            records.join(","))
+}
+
+impl Action for CacheAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/cache(?:/([^/]+)(?:/([^/]+))?)?/?$").unwrap()
+    }
+
+    fn handle(&self, request: Request, caps: &Captures) -> Result<()> {
+        let name = caps.get(1).map(|x| x.as_str());
+        let qtype = caps.get(2).map(|x| x.as_str());
+
+        match *request.method() {
+            Method::Get if name.is_none() => self.list(request),
+            Method::Delete => self.delete(request, name, qtype),
+            _ => respond_error(request, 405, "Method not allowed")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use dns::protocol::{DnsRecord, QueryClass, QueryType, TransientTtl};
+
+    use super::*;
+
+    #[test]
+    fn test_dump_to_json() {
+        let dump = DomainDump {
+            domain: "google.com".to_string(),
+            hits: 2,
+            records: vec![DnsRecord::A {
+                domain: "google.com".to_string(),
+                class: QueryClass::IN,
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(30)
+            }],
+            nodata: vec![QueryType::AAAA],
+            nxdomain: false
+        };
+
+        assert_eq!("{\"domain\":\"google.com\",\"hits\":2,\"nxdomain\":false,\"nodata\":[\"AAAA\"],\
+                    \"records\":[{\"type\":\"A\",\"ttl\":30,\"data\":\"127.0.0.1\"}]}",
+                   dump_to_json(&dump));
+    }
+}
@@ -0,0 +1,4 @@
+//! the management api
+
+pub mod server;
+pub mod cache;
@@ -0,0 +1,116 @@
+//! a small http server dispatching requests to actions by url
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::thread::Builder;
+
+use regex::{Captures, Regex};
+use tiny_http::{Header, Request, Response, Server};
+
+use dns::context::ServerContext;
+
+/// A handler for the requests to urls matching a pattern
+pub trait Action {
+    fn get_regex(&self) -> Regex;
+    fn handle(&self, request: Request, caps: &Captures) -> Result<()>;
+}
+
+pub struct WebServer {
+    pub context: Arc<ServerContext>,
+    pub actions: Vec<Box<dyn Action + Sync + Send>>
+}
+
+impl WebServer {
+    pub fn new(context: Arc<ServerContext>) -> WebServer {
+        WebServer {
+            context,
+            actions: Vec::new()
+        }
+    }
+
+    pub fn register_action(&mut self, action: Box<dyn Action + Sync + Send>) {
+        self.actions.push(action);
+    }
+
+    /// Start serving the api on `api_bind_ip` and `api_port`, in a thread of
+    /// its own
+    pub fn run_webserver(self) -> Result<()> {
+        let webserver = match Server::http((self.context.api_bind_ip.as_str(), self.context.api_port)) {
+            Ok(x) => x,
+            Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string()))
+        };
+
+        let actions = self.actions.into_iter()
+            .map(|action| (action.get_regex(), action))
+            .collect::<Vec<_>>();
+
+        Builder::new().name("WebServer".into()).spawn(move || {
+            for request in webserver.incoming_requests() {
+                let url = request.url().split('?').next().unwrap_or("").to_string();
+
+                let action = actions.iter()
+                    .filter_map(|(regex, action)| regex.captures(&url).map(|caps| (caps, action)))
+                    .next();
+
+                let result = match action {
+                    Some((caps, action)) => action.handle(request, &caps),
+                    None => respond_error(request, 404, "Not found")
+                };
+
+                if let Err(e) = result {
+                    println!("Failed to handle request for {}: {:?}", url, e);
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Respond with a json document
+pub fn respond_json(request: Request, status: u16, body: String) -> Result<()> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .map_err(|_| Error::new(ErrorKind::Other, "Invalid header"))?;
+
+    request.respond(Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type))
+}
+
+/// Respond with an error message wrapped in a json document
+pub fn respond_error(request: Request, status: u16, message: &str) -> Result<()> {
+    respond_json(request, status, format!("{{\"error\":{}}}", json_string(message)))
+}
+
+/// Quote and escape a string for use in a json document
+pub fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c)
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!("\"google.com\"", json_string("google.com"));
+        assert_eq!("\"\\\"a\\\\b\\\"\\n\\u0001\"", json_string("\"a\\b\"\n\u{1}"));
+    }
+}